@@ -12,6 +12,8 @@ async def root():
     return {
         "available_handlers": list(Handler.available_handlers().keys()),
         "handler_template": "/ocr/{handler_name}",
+        # Handlers run on the event loop, so only one request is processed at a time
+        "max_concurrency": 1,
     }
 
 
@@ -0,0 +1,270 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use axum::{
+    extract::{Multipart, Path},
+    http::StatusCode,
+    response::IntoResponse,
+    Json,
+};
+use ocr_common::archive::{self, ArchiveKind, ExtractLimits};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::{io::AsyncWriteExt, sync::Semaphore, task::JoinSet};
+use tracing::{debug, trace};
+
+use crate::{helpers::temp_file::TempFile, ocr, OcrResponseBase, OcrResult};
+
+static LIMITS: Lazy<BatchLimits> = Lazy::new(BatchLimits::from_env);
+
+/// Configured with `OCR_BATCH_MAX_FILES` (defaults to 1000),
+/// `OCR_BATCH_MAX_CONCURRENT` (how many files of a batch to process at once, defaults to all workers),
+/// `OCR_BATCH_MAX_EXTRACTED_FILE_MB` (how large a single file of an archive may extract to, defaults to 100)
+/// and `OCR_BATCH_MAX_EXTRACTED_MB` (how large all files of an archive may extract to, defaults to 512).
+#[derive(Debug)]
+struct BatchLimits {
+    max_files: usize,
+    max_concurrent: usize,
+    extracted_file_mb: u64,
+    extracted_mb: u64,
+}
+
+impl BatchLimits {
+    fn from_env() -> Self {
+        let max_files = std::env::var("OCR_BATCH_MAX_FILES")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(1000);
+
+        let max_concurrent = std::env::var("OCR_BATCH_MAX_CONCURRENT")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .filter(|x| *x > 0)
+            .unwrap_or_else(|| ocr::OcrWorkers::global().status().max_running);
+
+        let extracted_file_mb = std::env::var("OCR_BATCH_MAX_EXTRACTED_FILE_MB")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(100);
+
+        let extracted_mb = std::env::var("OCR_BATCH_MAX_EXTRACTED_MB")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(512);
+
+        Self {
+            max_files,
+            max_concurrent,
+            extracted_file_mb,
+            extracted_mb,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct BatchFile {
+    name: String,
+    content_type: Option<String>,
+    temp_file: TempFile,
+}
+
+/// Collects the files of a batch, giving each a unique name.
+#[derive(Debug, Default)]
+struct BatchFiles(Vec<BatchFile>);
+
+impl BatchFiles {
+    fn contains(&self, name: &str) -> bool {
+        self.0.iter().any(|x| x.name == name)
+    }
+
+    async fn push(
+        &mut self,
+        mut name: String,
+        content_type: Option<String>,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        if self.0.len() >= LIMITS.max_files {
+            anyhow::bail!("Too many files, at most {} are allowed", LIMITS.max_files);
+        }
+
+        if self.contains(&name) {
+            // There are more candidates than files, so one of them is always free
+            name = (2..=self.0.len() + 2)
+                .map(|i| format!("{name} ({i})"))
+                .find(|x| !self.contains(x))
+                .expect("Some name is always free");
+        }
+
+        let ext = content_type
+            .as_ref()
+            .and_then(mime2ext::mime2ext)
+            .unwrap_or("bin");
+
+        let mut temp_file = TempFile::with_prefix_and_extension("ocr-", ext).await?;
+        temp_file.file_mut().write_all(data).await?;
+        temp_file.file_mut().flush().await?;
+
+        trace!(?name, ?temp_file, "Wrote batch file");
+        self.0.push(BatchFile {
+            name,
+            content_type,
+            temp_file,
+        });
+
+        Ok(())
+    }
+}
+
+/// Read the `file` fields of the request, expanding any ZIP or TAR archives into the files they contain.
+async fn read_files(multipart: &mut Multipart) -> anyhow::Result<Vec<BatchFile>> {
+    let mut files = BatchFiles::default();
+
+    while let Some(field) = multipart.next_field().await? {
+        if field.name() != Some("file") {
+            continue;
+        }
+
+        let name = field.file_name().map_or_else(
+            || format!("file-{}", files.0.len() + 1),
+            ToString::to_string,
+        );
+        let content_type = field.content_type().map(ToString::to_string);
+        let data = field.bytes().await?;
+
+        let kind = match ArchiveKind::detect(&data) {
+            Some(kind) => kind,
+            None => {
+                files.push(name, content_type, &data).await?;
+                continue;
+            }
+        };
+
+        debug!(?name, ?kind, "Extracting archive");
+        let limits = ExtractLimits {
+            files: LIMITS.max_files.saturating_sub(files.0.len()),
+            file_size: LIMITS.extracted_file_mb.saturating_mul(1024 * 1024),
+            total_size: LIMITS.extracted_mb.saturating_mul(1024 * 1024),
+        };
+        let entries =
+            tokio::task::spawn_blocking(move || archive::extract(kind, &data, limits)).await??;
+
+        for entry in entries {
+            let content_type = archive::guess_content_type(&entry.name).map(ToString::to_string);
+            files.push(entry.name, content_type, &entry.data).await?;
+        }
+    }
+
+    Ok(files.0)
+}
+
+#[derive(Debug, Serialize)]
+struct BatchResponse {
+    engine: String,
+    succeeded: usize,
+    failed: usize,
+    /// The result of each file, by file name.
+    files: BTreeMap<String, OcrResult>,
+}
+
+/// Run many files through the handler in one request.
+#[tracing::instrument(skip_all, fields(handler = handler_name))]
+pub async fn handler_ocr_batch(
+    Path(handler_name): Path<String>,
+    mut multipart: Multipart,
+) -> impl IntoResponse {
+    trace!("Handling batch OCR request");
+
+    let resp = OcrResponseBase::new(&handler_name);
+
+    let ocr_handler = match ocr::HANDLERS
+        .iter()
+        .find(|h| h.name() == handler_name)
+        .cloned()
+    {
+        Some(handler) => handler,
+        None => {
+            let err = format!(
+                "Unknown handler. Available handlers: {}",
+                ocr::HANDLERS
+                    .iter()
+                    .map(|h| h.name())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            );
+
+            return (StatusCode::NOT_FOUND, Json(resp.error(&err))).into_response();
+        }
+    };
+
+    let files = match read_files(&mut multipart).await {
+        Ok(files) if files.is_empty() => {
+            return resp.error(&"No files uploaded").into_response();
+        }
+        Ok(files) => files,
+        Err(e) => {
+            return resp.error(&e).into_response();
+        }
+    };
+
+    debug!(files = files.len(), "Start batch OCR tasks");
+
+    let permits = Arc::new(Semaphore::new(LIMITS.max_concurrent));
+    let mut tasks = JoinSet::new();
+    for file in files {
+        let ocr_handler = ocr_handler.clone();
+        let permits = permits.clone();
+
+        tasks.spawn(async move {
+            let _permit = permits.acquire().await;
+
+            let img_path = file.temp_file.path().to_path_buf();
+            let img_mime_type = file.content_type.clone();
+            let ocr_task_result = ocr::OcrWorkers::global()
+                .run(move || ocr_handler.ocr(&img_path, img_mime_type.as_deref()))
+                .await;
+
+            let result = match ocr_task_result {
+                Ok(Ok(ocr_result)) => {
+                    OcrResult::Data(ocr_result.matches.into_iter().map(Into::into).collect())
+                }
+                Ok(Err(e)) => OcrResult::Error(e.to_string()),
+                Err(e) => OcrResult::Error(e.to_string()),
+            };
+            trace!(name = ?file.name, ?result, "Batch file done");
+
+            (file.name, result)
+        });
+    }
+
+    let mut results = BTreeMap::new();
+    while let Some(task) = tasks.join_next().await {
+        match task {
+            Ok((name, result)) => {
+                results.insert(name, result);
+            }
+            Err(e) => {
+                return (StatusCode::INTERNAL_SERVER_ERROR, Json(resp.error(&e))).into_response();
+            }
+        }
+    }
+
+    let succeeded = results
+        .values()
+        .filter(|x| matches!(x, OcrResult::Data(_)))
+        .count();
+    debug!(succeeded, "Batch OCR tasks done");
+
+    let status = if succeeded > 0 {
+        StatusCode::OK
+    } else {
+        StatusCode::BAD_REQUEST
+    };
+
+    let body = BatchResponse {
+        engine: handler_name,
+        succeeded,
+        failed: results.len() - succeeded,
+        files: results,
+    };
+
+    (status, Json(body)).into_response()
+}
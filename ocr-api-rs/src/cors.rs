@@ -0,0 +1,127 @@
+use std::time::Duration;
+
+use axum::http::{header, HeaderName, HeaderValue, Method, Uri};
+use ocr_common::timeframe::Timeframe;
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+/// CORS policy for the OCR routes, so browsers can call them from other origins.
+///
+/// Configured like the gateway's public routes, with:
+/// - `CORS_ALLOWED_ORIGINS`: comma- or space-separated origins, or `*` for any. CORS stays off when unset.
+/// - `CORS_ALLOWED_METHODS`: comma- or space-separated methods, or `*` for any (defaults to `GET,POST`).
+/// - `CORS_ALLOWED_HEADERS`: comma- or space-separated headers, or `*` for any (the default).
+/// - `CORS_ALLOW_CREDENTIALS`: `true` to allow credentials, which needs listed origins.
+/// - `CORS_MAX_AGE`: how long browsers may cache preflight responses, eg. `10min` (defaults to `1h`).
+///
+/// Invalid values are an error, so a typo doesn't silently loosen or disable the policy.
+pub fn layer() -> Result<Option<CorsLayer>, String> {
+    let origins = env_list("CORS_ALLOWED_ORIGINS", "", parse_origin)?;
+    if origins.is_empty() {
+        return Ok(None);
+    }
+
+    let allow_credentials = match std::env::var("CORS_ALLOW_CREDENTIALS").as_deref() {
+        Err(_) | Ok("false") => false,
+        Ok("true") => true,
+        Ok(x) => {
+            return Err(format!(
+                "Invalid CORS_ALLOW_CREDENTIALS {x:?}, expected `true` or `false`"
+            ))
+        }
+    };
+
+    let any_origin = origins.iter().any(|x| x == "*");
+    // Browsers refuse credentials for wildcard origins
+    if any_origin && allow_credentials {
+        return Err(
+            "CORS credentials can't be allowed for any origin, list the origins in `CORS_ALLOWED_ORIGINS` instead"
+                .to_string(),
+        );
+    }
+
+    let allow_origin = if any_origin {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(origins.iter().filter_map(|x| HeaderValue::from_str(x).ok()))
+    };
+
+    let methods = env_list("CORS_ALLOWED_METHODS", "GET,POST", |x| {
+        Method::from_bytes(x.to_ascii_uppercase().as_bytes())
+            .map(|x| x.to_string())
+            .map_err(|e| format!("Invalid HTTP method {x:?}: {e}"))
+    })?;
+    let allow_methods = if methods.iter().any(|x| x == "*") {
+        AllowMethods::mirror_request()
+    } else {
+        AllowMethods::list(
+            methods
+                .iter()
+                .filter_map(|x| Method::from_bytes(x.as_bytes()).ok()),
+        )
+    };
+
+    let headers = env_list("CORS_ALLOWED_HEADERS", "*", |x| {
+        HeaderName::from_bytes(x.as_bytes())
+            .map(|x| x.to_string())
+            .map_err(|e| format!("Invalid header name {x:?}: {e}"))
+    })?;
+    let allow_headers = if headers.iter().any(|x| x == "*") {
+        AllowHeaders::mirror_request()
+    } else {
+        AllowHeaders::list(
+            headers
+                .iter()
+                .filter_map(|x| HeaderName::from_bytes(x.as_bytes()).ok()),
+        )
+    };
+
+    let max_age = std::env::var("CORS_MAX_AGE")
+        .ok()
+        .map(|x| Timeframe::parse_str(&x).map_err(|e| format!("Invalid CORS_MAX_AGE: {e}")))
+        .transpose()?
+        .map_or(Duration::from_secs(60 * 60), Duration::from);
+
+    Ok(Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods(allow_methods)
+            .allow_headers(allow_headers)
+            .allow_credentials(allow_credentials)
+            .max_age(max_age)
+            .expose_headers([HeaderName::from_static("x-request-id"), header::RETRY_AFTER]),
+    ))
+}
+
+/// Comma- or space-separated values of the variable, each either `*` or normalized by `parse`.
+fn env_list<F>(name: &str, default: &str, parse: F) -> Result<Vec<String>, String>
+where
+    F: Fn(&str) -> Result<String, String>,
+{
+    std::env::var(name)
+        .unwrap_or_else(|_| default.to_string())
+        .split([',', ' '])
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(|x| {
+            if x == "*" {
+                Ok(x.to_string())
+            } else {
+                parse(x)
+            }
+        })
+        .collect()
+}
+
+/// The origin as browsers send it, eg. `https://example.com:8443`.
+fn parse_origin(s: &str) -> Result<String, String> {
+    let uri = s
+        .parse::<Uri>()
+        .map_err(|e| format!("Invalid CORS origin {s:?}: {e}"))?;
+
+    match (uri.scheme_str(), uri.authority()) {
+        (Some(scheme), Some(authority)) if matches!(uri.path(), "" | "/") => {
+            Ok(format!("{scheme}://{authority}").to_ascii_lowercase())
+        }
+        _ => Err(format!("Invalid CORS origin {s:?}")),
+    }
+}
@@ -1,3 +1,4 @@
 pub mod id;
+pub mod in_flight;
 pub mod radix_fmt;
 pub mod temp_file;
@@ -1,6 +1,9 @@
+mod batch;
+mod cors;
 mod helpers;
 mod log;
 mod ocr;
+mod shutdown;
 
 use std::{string::ToString, time::Duration};
 
@@ -13,7 +16,7 @@ use axum::{
 };
 use helpers::temp_file::TempFile;
 use serde::{Deserialize, Serialize};
-use tokio::{io::AsyncWriteExt, net::TcpListener, signal};
+use tokio::{io::AsyncWriteExt, net::TcpListener};
 use tower::ServiceBuilder;
 use tower_http::{
     catch_panic::CatchPanicLayer,
@@ -21,7 +24,7 @@ use tower_http::{
     timeout::TimeoutLayer,
     trace::TraceLayer,
 };
-use tracing::{debug, field, info, trace, Span};
+use tracing::{debug, error, field, info, trace, warn, Span};
 
 #[derive(Clone)]
 struct AppMakeRequestId;
@@ -39,6 +42,8 @@ impl MakeRequestId for AppMakeRequestId {
 async fn main() {
     log::init();
 
+    warm_up_handlers();
+
     let app = create_router();
 
     let listener = {
@@ -60,16 +65,34 @@ async fn main() {
         listener.local_addr().expect("Failed to get local address!")
     );
 
-    axum::serve(listener, app.into_make_service())
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .expect("Failed to start server!");
+    let server =
+        axum::serve(listener, app.into_make_service()).with_graceful_shutdown(shutdown::graceful());
+
+    #[allow(clippy::redundant_pub_crate)]
+    {
+        tokio::select! {
+            res = server => res.expect("Failed to start server!"),
+            () = shutdown::forced() => warn!("Forcing shutdown with connections still open"),
+        }
+    }
 }
 
 fn create_router() -> Router {
-    Router::new()
+    let ocr_router = Router::new()
         .route("/", get(handler_root))
         .route("/ocr/:handler_name", post(handler_ocr_by_handler_name))
+        .layer(TimeoutLayer::new(Duration::from_secs(60)))
+        // Batches can take much longer than single requests
+        .route(
+            "/ocr/:handler_name/batch",
+            post(batch::handler_ocr_batch).layer(TimeoutLayer::new(batch_timeout())),
+        );
+
+    // Only the OCR routes, as health checks don't come from browsers
+    with_cors(ocr_router)
+        .layer(axum::middleware::from_fn(shutdown::track_in_flight))
+        .route("/healthz", get(handler_healthz))
+        .route("/readyz", get(handler_readyz))
         .layer(CatchPanicLayer::new())
         .layer(DefaultBodyLimit::disable())
         .layer(
@@ -127,18 +150,102 @@ fn create_router() -> Router {
                             );
                         }),
                 )
-                .layer(TimeoutLayer::new(Duration::from_secs(60)))
                 .layer(PropagateRequestIdLayer::x_request_id()),
         )
 }
 
+/// Adds the CORS policy to the routes, if browsers may call them from other origins at all.
+fn with_cors(router: Router) -> Router {
+    match cors::layer() {
+        Ok(Some(cors)) => router.layer(cors),
+        Ok(None) => router,
+        Err(e) => {
+            error!(error = %e, "Invalid CORS configuration");
+            std::process::exit(1);
+        }
+    }
+}
+
+/// How long a batch request may take, configured with `OCR_BATCH_TIMEOUT_SECS` (defaults to 30 minutes).
+fn batch_timeout() -> Duration {
+    std::env::var("OCR_BATCH_TIMEOUT_SECS")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .map_or(Duration::from_secs(30 * 60), Duration::from_secs)
+}
+
 async fn handler_root() -> impl IntoResponse {
     Json(serde_json::json!({
         "available_handlers": ocr::HANDLERS.iter().map(|h| h.name()).collect::<Vec<_>>(),
         "handler_template": "/ocr/{handler_name}",
+        // So gateways don't send more requests at once than there are workers for
+        "max_concurrency": ocr::OcrWorkers::global().status().max_running,
     }))
 }
 
+/// Load models and check external dependencies in the background,
+/// so the first requests don't have to wait for it.
+fn warm_up_handlers() {
+    for handler in ocr::HANDLERS.iter() {
+        let name = handler.name();
+        let task = tokio::task::spawn_blocking(move || {
+            debug!(name = ?handler.name(), "Warming up handler");
+            handler.warm_up()
+        });
+
+        tokio::spawn(async move {
+            match task.await {
+                Ok(Ok(())) => info!(?name, "Handler ready"),
+                Ok(Err(e)) => error!(?name, error = ?e, "Failed to warm up handler"),
+                Err(e) => error!(?name, error = ?e, "Handler panicked while warming up"),
+            }
+        });
+    }
+}
+
+async fn handler_healthz() -> impl IntoResponse {
+    "OK"
+}
+
+async fn handler_readyz() -> impl IntoResponse {
+    let handlers = ocr::HANDLERS
+        .iter()
+        .map(|h| (h.name(), h.check_ready()))
+        .collect::<Vec<_>>();
+    let workers = ocr::OcrWorkers::global();
+    let shutting_down = shutdown::Shutdown::global().is_started();
+
+    let ready = !shutting_down
+        && !workers.is_saturated()
+        && handlers.iter().all(|(_, ready)| ready.is_ok());
+
+    let status = if ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    let body = serde_json::json!({
+        "ready": ready,
+        "shutting_down": shutting_down,
+        "workers": workers.status(),
+        "handlers": handlers
+            .into_iter()
+            .map(|(name, ready)| {
+                (
+                    name,
+                    serde_json::json!({
+                        "ready": ready.is_ok(),
+                        "error": ready.err(),
+                    }),
+                )
+            })
+            .collect::<serde_json::Map<_, _>>(),
+    });
+
+    (status, Json(body))
+}
+
 #[derive(Debug)]
 #[allow(dead_code)]
 struct UploadTempFile {
@@ -238,15 +345,9 @@ async fn handler_ocr_by_handler_name(
     let img_mime_type = file.content_type.clone();
 
     debug!(?img_path, ?img_mime_type, "Start image OCR task");
-    let ocr_task_result = {
-        let cur_span = Span::current();
-
-        tokio::task::spawn_blocking(move || {
-            let _entered = cur_span.enter();
-            ocr_handler.ocr(&img_path, img_mime_type.as_deref())
-        })
-        .await
-    };
+    let ocr_task_result = ocr::OcrWorkers::global()
+        .run(move || ocr_handler.ocr(&img_path, img_mime_type.as_deref()))
+        .await;
     debug!(success = ocr_task_result.is_ok(), "Image OCR task done");
 
     let ocr_result = match ocr_task_result {
@@ -324,29 +425,3 @@ impl OcrResponseBase {
         }
     }
 }
-
-async fn shutdown_signal() {
-    // Listen for a SIGINT (Ctrl+C) or SIGTERM signal
-    let ctrl_c = signal::ctrl_c();
-
-    #[cfg(unix)]
-    let mut handler = signal::unix::signal(signal::unix::SignalKind::terminate())
-        .expect("Failed to install signal handler");
-    #[cfg(unix)]
-    let terminate = { handler.recv() };
-
-    #[cfg(not(unix))]
-    let terminate = std::future::pending();
-
-    #[allow(clippy::redundant_pub_crate)]
-    {
-        tokio::select! {
-            _ = ctrl_c => {
-                println!("Received Ctrl+C, shutting down");
-            }
-            _ = terminate => {
-                println!("Received SIGTERM, shutting down");
-            }
-        }
-    }
-}
@@ -16,6 +16,18 @@ pub trait OcrHandler: std::fmt::Debug + Send + Sync {
     }
 
     fn ocr(&self, path: &Path, mime_type: Option<&str>) -> anyhow::Result<OcrResult>;
+
+    /// Do any expensive setup (eg. loading models) so the first request doesn't have to.
+    ///
+    /// Blocks, so should be run on a blocking thread.
+    fn warm_up(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Whether the handler is ready to take requests, and if not, why.
+    fn check_ready(&self) -> Result<(), String> {
+        Ok(())
+    }
 }
 
 #[derive(Debug, Serialize)]
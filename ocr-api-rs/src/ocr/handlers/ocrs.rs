@@ -22,6 +22,18 @@ impl OcrHandler for Ocrs {
     fn ocr(&self, path: &Path, mime_type: Option<&str>) -> anyhow::Result<OcrResult> {
         ocr_image(path, mime_type)
     }
+
+    fn warm_up(&self) -> anyhow::Result<()> {
+        Lazy::force(&OCR_ENGINE);
+
+        Ok(())
+    }
+
+    fn check_ready(&self) -> Result<(), String> {
+        Lazy::get(&OCR_ENGINE)
+            .map(|_| ())
+            .ok_or_else(|| "OCR models are not loaded yet".to_string())
+    }
 }
 
 pub static OCR_ENGINE: Lazy<OcrEngine> = Lazy::new(|| {
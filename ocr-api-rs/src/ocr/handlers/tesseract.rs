@@ -1,16 +1,88 @@
-use std::{cmp::Ord, collections::HashMap, path::Path};
+use std::{
+    cmp::Ord,
+    collections::HashMap,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time::{Duration, Instant},
+};
 
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
-use tracing::trace;
+use tracing::{debug, trace};
 
 use super::{OcrHandler, OcrResult};
 use crate::ocr::handlers::{CoordBox, OcrTextItem, Point};
 
+/// How long the result of checking the tesseract installation is trusted before it's checked again,
+/// so an installation that's fixed (or broken) while the server runs is noticed.
+const CHECK_TTL: Duration = Duration::from_secs(60);
+
+/// When the tesseract binary and its language data were checked, and the result.
+type InstallationCheck = (Instant, Result<(), String>);
+
+/// The last check of the tesseract installation.
+static TESSERACT_CHECK: Mutex<Option<InstallationCheck>> = parking_lot::const_mutex(None);
+
+/// Whether a re-check is already running in the background.
+static TESSERACT_RECHECKING: AtomicBool = AtomicBool::new(false);
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Tesseract;
 
+impl Tesseract {
+    /// Checks the installation and remembers the result.
+    ///
+    /// Blocks, so should be run on a blocking thread.
+    fn check() -> Result<(), String> {
+        let result = std::panic::catch_unwind(Self::check_installation)
+            .unwrap_or_else(|_| Err("Checking the tesseract installation panicked".to_string()));
+
+        *TESSERACT_CHECK.lock() = Some((Instant::now(), result.clone()));
+        result
+    }
+
+    fn check_installation() -> Result<(), String> {
+        let version = rusty_tesseract::get_tesseract_version()
+            .map_err(|e| format!("Tesseract binary not available: {e}"))?;
+        trace!(?version, "Found tesseract");
+
+        let lang = rusty_tesseract::Args::default().lang;
+        let langs = rusty_tesseract::get_tesseract_langs()
+            .map_err(|e| format!("Couldn't list tesseract languages: {e}"))?;
+
+        if !langs.contains(&lang) {
+            return Err(format!(
+                "Tesseract data for language {lang:?} not found (have: {})",
+                langs.join(", ")
+            ));
+        }
+
+        Ok(())
+    }
+}
+
 #[typetag::serde]
 impl OcrHandler for Tesseract {
+    fn warm_up(&self) -> anyhow::Result<()> {
+        Self::check().map_err(|e| anyhow::anyhow!(e))
+    }
+
+    fn check_ready(&self) -> Result<(), String> {
+        let Some((checked_at, result)) = TESSERACT_CHECK.lock().clone() else {
+            return Err("Tesseract installation has not been checked yet".to_string());
+        };
+
+        if checked_at.elapsed() > CHECK_TTL && !TESSERACT_RECHECKING.swap(true, Ordering::AcqRel) {
+            debug!("Re-checking tesseract installation");
+            tokio::task::spawn_blocking(|| {
+                let _ = Self::check();
+                TESSERACT_RECHECKING.store(false, Ordering::Release);
+            });
+        }
+
+        result
+    }
+
     fn ocr(&self, path: &Path, _mime_type: Option<&str>) -> anyhow::Result<OcrResult> {
         trace!(?path, "OCR with Tesseract");
 
@@ -1,3 +1,5 @@
 pub mod handlers;
+pub mod workers;
 
 pub use handlers::HANDLERS;
+pub use workers::OcrWorkers;
@@ -0,0 +1,114 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use tokio::sync::Semaphore;
+use tracing::{debug, Span};
+
+static WORKERS: Lazy<OcrWorkers> = Lazy::new(OcrWorkers::from_env);
+
+/// Runs OCR jobs on the blocking thread pool, with a limit on how many run at the same time.
+///
+/// Configured with `OCR_MAX_CONCURRENT_JOBS` (defaults to the number of CPUs)
+/// and `OCR_MAX_QUEUED_JOBS` (how many waiting jobs count as saturated, defaults to 4 per worker).
+#[derive(Debug)]
+pub struct OcrWorkers {
+    permits: Semaphore,
+    max_running: usize,
+    max_queued: usize,
+    queued: AtomicUsize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct OcrWorkersStatus {
+    pub running: usize,
+    pub max_running: usize,
+    pub queued: usize,
+    pub max_queued: usize,
+}
+
+impl OcrWorkers {
+    fn from_env() -> Self {
+        let max_running = std::env::var("OCR_MAX_CONCURRENT_JOBS")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .filter(|x| *x > 0)
+            .unwrap_or_else(|| {
+                std::thread::available_parallelism().map_or(1, std::num::NonZeroUsize::get)
+            });
+
+        let max_queued = std::env::var("OCR_MAX_QUEUED_JOBS")
+            .ok()
+            .and_then(|x| x.parse().ok())
+            .unwrap_or(max_running * 4);
+
+        debug!(max_running, max_queued, "Created OCR workers");
+
+        Self {
+            permits: Semaphore::new(max_running),
+            max_running,
+            max_queued,
+            queued: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn global() -> &'static Self {
+        &WORKERS
+    }
+
+    /// Run the job once a worker is free.
+    pub async fn run<F, T>(&self, job: F) -> Result<T, tokio::task::JoinError>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let permit = {
+            let _queued = QueuedGuard::new(&self.queued);
+
+            self.permits
+                .acquire()
+                .await
+                .expect("OCR worker semaphore is never closed")
+        };
+
+        let cur_span = Span::current();
+        let result = tokio::task::spawn_blocking(move || {
+            let _entered = cur_span.enter();
+            job()
+        })
+        .await;
+
+        drop(permit);
+
+        result
+    }
+
+    pub fn is_saturated(&self) -> bool {
+        self.queued.load(Ordering::Acquire) >= self.max_queued
+    }
+
+    pub fn status(&self) -> OcrWorkersStatus {
+        OcrWorkersStatus {
+            running: self.max_running - self.permits.available_permits(),
+            max_running: self.max_running,
+            queued: self.queued.load(Ordering::Acquire),
+            max_queued: self.max_queued,
+        }
+    }
+}
+
+/// Counts a job as queued until dropped, so jobs given up on while waiting don't stay counted.
+struct QueuedGuard<'a>(&'a AtomicUsize);
+
+impl<'a> QueuedGuard<'a> {
+    fn new(queued: &'a AtomicUsize) -> Self {
+        queued.fetch_add(1, Ordering::AcqRel);
+        Self(queued)
+    }
+}
+
+impl Drop for QueuedGuard<'_> {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::AcqRel);
+    }
+}
@@ -0,0 +1,147 @@
+use std::{sync::Arc, time::Duration};
+
+use axum::{
+    extract::Request,
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use ocr_common::timeframe::Timeframe;
+use once_cell::sync::Lazy;
+use tokio::{signal, sync::watch};
+use tracing::{info, warn};
+
+use crate::helpers::in_flight::InFlight;
+
+static SHUTDOWN: Lazy<Shutdown> = Lazy::new(Shutdown::new);
+
+/// Tracks whether the server is shutting down and which requests still need to finish.
+#[derive(Debug)]
+pub struct Shutdown {
+    started: watch::Sender<bool>,
+    in_flight: Arc<InFlight>,
+    grace_period: Duration,
+}
+
+impl Shutdown {
+    fn new() -> Self {
+        // Named and formatted like the gateway's setting, eg. `30s` or `2min`
+        let grace_period = std::env::var("SHUTDOWN_GRACE_PERIOD")
+            .ok()
+            .and_then(|x| {
+                Timeframe::parse_str(&x)
+                    .inspect_err(|e| warn!(error = %e, "Invalid SHUTDOWN_GRACE_PERIOD, using 30s"))
+                    .ok()
+            })
+            .map_or(Duration::from_secs(30), Duration::from);
+
+        Self {
+            started: watch::channel(false).0,
+            in_flight: Arc::new(InFlight::default()),
+            grace_period,
+        }
+    }
+
+    pub fn global() -> &'static Self {
+        &SHUTDOWN
+    }
+
+    pub fn is_started(&self) -> bool {
+        *self.started.borrow()
+    }
+
+    /// Resolves once shutdown has started.
+    pub async fn started(&self) {
+        let mut rx = self.started.subscribe();
+        let _ = rx.wait_for(|started| *started).await;
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.count()
+    }
+
+    fn start(&self) {
+        self.started.send_replace(true);
+    }
+}
+
+/// Refuses new requests once shutdown has started and keeps track of the ones in flight.
+pub async fn track_in_flight(request: Request, next: Next) -> Result<Response, Response> {
+    let shutdown = Shutdown::global();
+
+    if shutdown.is_started() {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::CONNECTION, "close")],
+            "Server is shutting down",
+        )
+            .into_response());
+    }
+
+    let _in_flight = shutdown.in_flight.track();
+
+    Ok(next.run(request).await)
+}
+
+/// Resolves when the server should stop accepting connections.
+///
+/// Waits for a shutdown signal, then gives in-flight OCR requests
+/// up to `SHUTDOWN_GRACE_PERIOD` (30s by default) to finish.
+pub async fn graceful() {
+    wait_for_signal().await;
+
+    let shutdown = Shutdown::global();
+
+    info!(
+        in_flight = shutdown.in_flight(),
+        grace_period = ?shutdown.grace_period,
+        "Waiting for in-flight requests",
+    );
+    shutdown.start();
+
+    if tokio::time::timeout(shutdown.grace_period, shutdown.in_flight.wait_idle())
+        .await
+        .is_err()
+    {
+        warn!(
+            in_flight = shutdown.in_flight(),
+            "Grace period over with requests still in flight",
+        );
+    }
+}
+
+/// Resolves once the grace period has passed after shutdown started.
+///
+/// Used to cut off connections that are still open after the graceful shutdown.
+pub async fn forced() {
+    let shutdown = Shutdown::global();
+
+    shutdown.started().await;
+    tokio::time::sleep(shutdown.grace_period).await;
+}
+
+async fn wait_for_signal() {
+    // Listen for a SIGINT (Ctrl+C) or SIGTERM signal
+    let ctrl_c = signal::ctrl_c();
+
+    #[cfg(unix)]
+    let mut handler = signal::unix::signal(signal::unix::SignalKind::terminate())
+        .expect("Failed to install signal handler");
+    #[cfg(unix)]
+    let terminate = { handler.recv() };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending();
+
+    #[allow(clippy::redundant_pub_crate)]
+    {
+        tokio::select! {
+            _ = ctrl_c => {
+                println!("Received Ctrl+C, shutting down");
+            }
+            _ = terminate => {
+                println!("Received SIGTERM, shutting down");
+            }
+        }
+    }
+}
@@ -0,0 +1,230 @@
+pub mod sink;
+
+use std::{collections::HashMap, sync::Arc, time::Instant};
+
+use futures::future::join_all;
+use ocr_common::timeframe::Timeframe;
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use serde::Serialize;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{debug, info, trace, warn};
+
+pub use self::sink::AlertSink;
+use crate::{
+    config::Config,
+    endpoint_watcher::{
+        endpoint::{EndpointId, EndpointState, EndpointStatus},
+        event::{EndpointEvent, EndpointEventKind},
+        EndpointWatcher,
+    },
+};
+
+static ALERT_MANAGER: OnceCell<Arc<AlertManager>> = OnceCell::new();
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Alert {
+    #[serde(flatten)]
+    pub event: EndpointEvent,
+    message: String,
+}
+
+impl Alert {
+    fn new(event: EndpointEvent) -> Self {
+        let message = match &event.kind {
+            EndpointEventKind::StatusChanged { from, to, status } => {
+                let mut message = format!(
+                    "Endpoint {url} ({id}) is now {to:?} (was {from:?})",
+                    url = event.url,
+                    id = event.endpoint_id,
+                );
+
+                if let EndpointStatus::Down { error, .. } = status {
+                    message.push_str(": ");
+                    message.push_str(error);
+                }
+
+                message
+            }
+            kind => format!(
+                "Endpoint {url} ({id}): {event}",
+                url = event.url,
+                id = event.endpoint_id,
+                event = kind.name(),
+            ),
+        };
+
+        Self { event, message }
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+/// Sends alerts to the configured sinks when endpoints change state.
+///
+/// A state change only triggers an alert if the endpoint stays in the new state for the whole
+/// debounce period, and the same endpoint won't alert about the same state again until the cooldown has passed.
+#[derive(Debug)]
+pub struct AlertManager {
+    sinks: Vec<AlertSink>,
+    debounce: Timeframe,
+    cooldown: Timeframe,
+    timeout: Timeframe,
+    /// Bumped on every state change of an endpoint, so a pending alert can tell whether
+    /// the endpoint changed state again while it was waiting for the debounce period.
+    generations: Mutex<HashMap<EndpointId, u64>>,
+    last_sent: Mutex<HashMap<(EndpointId, EndpointState), Instant>>,
+}
+
+impl AlertManager {
+    pub fn global() -> &'static Arc<Self> {
+        ALERT_MANAGER.get_or_init(|| {
+            info!("Creating global AlertManager");
+
+            let config = &Config::global().alerts;
+
+            let sinks = config
+                .alert_webhook_urls
+                .iter()
+                .cloned()
+                .map(AlertSink::Webhook)
+                .chain(
+                    config
+                        .alert_slack_webhook_urls
+                        .iter()
+                        .cloned()
+                        .map(AlertSink::Slack),
+                )
+                .chain(config.alert_command.clone().map(AlertSink::Command))
+                .collect::<Vec<_>>();
+
+            let manager = Arc::new(Self {
+                sinks,
+                debounce: config.alert_debounce,
+                cooldown: config.alert_cooldown,
+                timeout: config.alert_timeout,
+                generations: Mutex::new(HashMap::new()),
+                last_sent: Mutex::new(HashMap::new()),
+            });
+
+            if manager.sinks.is_empty() {
+                debug!("No alert sinks configured, not watching for endpoint events");
+                return manager;
+            }
+
+            tokio::spawn({
+                debug!("Starting alert manager task");
+                let manager = manager.clone();
+                let mut events = EndpointWatcher::global().subscribe();
+                async move {
+                    loop {
+                        match events.recv().await {
+                            Ok(event) => {
+                                let generation = manager.next_generation(&event);
+                                if Self::should_alert(&event) {
+                                    tokio::spawn(manager.clone().handle(event, generation));
+                                }
+                            }
+                            Err(RecvError::Lagged(missed)) => {
+                                warn!(missed, "Alert manager missed endpoint events");
+                            }
+                            Err(RecvError::Closed) => break,
+                        }
+                    }
+                }
+            });
+
+            manager
+        })
+    }
+
+    const fn should_alert(event: &EndpointEvent) -> bool {
+        match &event.kind {
+            EndpointEventKind::StatusChanged { from, to, .. } => match (from, to) {
+                // Endpoints being discovered or forgotten aren't worth waking anyone up for
+                (EndpointState::Unknown, EndpointState::Up) | (_, EndpointState::Unknown) => false,
+                _ => true,
+            },
+            _ => false,
+        }
+    }
+
+    /// Records a state change (or removal) of the event's endpoint, returning its new generation.
+    fn next_generation(&self, event: &EndpointEvent) -> u64 {
+        let mut generations = self.generations.lock();
+
+        match &event.kind {
+            EndpointEventKind::StatusChanged { .. } => {
+                let generation = generations.entry(event.endpoint_id.clone()).or_default();
+                *generation += 1;
+                *generation
+            }
+            EndpointEventKind::Removed => {
+                generations.remove(&event.endpoint_id);
+                0
+            }
+            _ => generations
+                .get(&event.endpoint_id)
+                .copied()
+                .unwrap_or_default(),
+        }
+    }
+
+    #[tracing::instrument(skip_all, fields(id = %event.endpoint_id, event = event.name()))]
+    async fn handle(self: Arc<Self>, event: EndpointEvent, generation: u64) {
+        let to = match &event.kind {
+            EndpointEventKind::StatusChanged { to, .. } => *to,
+            _ => return,
+        };
+
+        trace!(debounce = %self.debounce, "Waiting for state change to settle");
+        tokio::time::sleep(self.debounce.into()).await;
+
+        let current_generation = self.generations.lock().get(&event.endpoint_id).copied();
+        if current_generation != Some(generation) {
+            debug!("Endpoint changed state again while debouncing, not alerting");
+            return;
+        }
+
+        let current = EndpointWatcher::global()
+            .endpoint(event.endpoint_id.clone())
+            .map(|endpoint| endpoint.status.read().state());
+
+        if current != Some(to) {
+            debug!(?current, ?to, "State change did not persist, not alerting");
+            return;
+        }
+
+        {
+            let mut last_sent = self.last_sent.lock();
+            let key = (event.endpoint_id.clone(), to);
+
+            if let Some(sent_at) = last_sent.get(&key) {
+                if sent_at.elapsed() < self.cooldown.into() {
+                    debug!(cooldown = %self.cooldown, "Alert is cooling down, not alerting");
+                    return;
+                }
+            }
+
+            last_sent.insert(key, Instant::now());
+        }
+
+        let alert = Alert::new(event);
+        info!(message = alert.message(), "Sending alert");
+
+        let results = join_all(
+            self.sinks
+                .iter()
+                .map(|sink| sink.send(&alert, self.timeout.into())),
+        )
+        .await;
+
+        for (sink, result) in self.sinks.iter().zip(results) {
+            if let Err(e) = result {
+                warn!(?sink, error = ?e, "Failed to send alert");
+            }
+        }
+    }
+}
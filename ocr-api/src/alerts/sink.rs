@@ -0,0 +1,256 @@
+use std::{process::Stdio, time::Duration};
+
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use tokio::{io::AsyncWriteExt, process::Command};
+use tracing::{debug, trace};
+use url::Url;
+
+use super::Alert;
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .connect_timeout(Duration::from_secs(5))
+        .build()
+        .expect("Failed to create alert HTTP client")
+});
+
+/// Somewhere to deliver alerts to.
+#[derive(Debug, Clone)]
+pub enum AlertSink {
+    /// POSTs the alert as JSON.
+    Webhook(Url),
+    /// POSTs a Slack-compatible `{"text": ...}` payload.
+    Slack(Url),
+    /// Runs a shell command with the alert JSON on stdin.
+    Command(String),
+}
+
+impl AlertSink {
+    /// Delivers the alert, giving up after `timeout`.
+    #[tracing::instrument(skip(alert))]
+    pub async fn send(&self, alert: &Alert, timeout: Duration) -> anyhow::Result<()> {
+        debug!("Sending alert");
+
+        match self {
+            Self::Webhook(url) => {
+                CLIENT
+                    .post(url.as_str())
+                    .timeout(timeout)
+                    .json(alert)
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            Self::Slack(url) => {
+                CLIENT
+                    .post(url.as_str())
+                    .timeout(timeout)
+                    .json(&serde_json::json!({ "text": alert.message() }))
+                    .send()
+                    .await?
+                    .error_for_status()?;
+            }
+            Self::Command(command) => {
+                // The child is killed when it's dropped, so timing out also stops the command
+                tokio::time::timeout(timeout, Self::run_command(command, alert))
+                    .await
+                    .with_context(|| format!("Alert command timed out after {timeout:?}"))??;
+            }
+        }
+
+        Ok(())
+    }
+
+    async fn run_command(command: &str, alert: &Alert) -> anyhow::Result<()> {
+        let payload = serde_json::to_vec(alert)?;
+
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .env("OCR_ALERT_ENDPOINT_ID", alert.event.endpoint_id.to_string())
+            .env("OCR_ALERT_ENDPOINT_URL", alert.event.url.as_str())
+            .env("OCR_ALERT_MESSAGE", alert.message())
+            .stdin(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+
+        if let Some(mut stdin) = child.stdin.take() {
+            stdin.write_all(&payload).await?;
+        }
+
+        let status = child.wait().await?;
+        trace!(?status, "Alert command finished");
+
+        if !status.success() {
+            anyhow::bail!("Alert command exited with {status}");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::{net::SocketAddr, sync::Arc};
+
+    use axum::{extract::State, http::StatusCode, routing::post, Json, Router};
+    use chrono::Utc;
+    use parking_lot::Mutex;
+    use serde_json::Value;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::endpoint_watcher::{
+        endpoint::{EndpointState, EndpointStatus},
+        event::{EndpointEvent, EndpointEventKind},
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    type Received = Arc<Mutex<Vec<Value>>>;
+
+    fn alert() -> Alert {
+        Alert::new(EndpointEvent {
+            at: Utc::now(),
+            endpoint_id: "test-endpoint".into(),
+            url: Url::parse("http://ocr.invalid:8000/").unwrap(),
+            tenant: None,
+            kind: EndpointEventKind::StatusChanged {
+                from: EndpointState::Up,
+                to: EndpointState::Down,
+                status: EndpointStatus::down("connection refused"),
+            },
+        })
+    }
+
+    /// Starts a local HTTP server standing in for a webhook receiver.
+    async fn stand_in(status: StatusCode, delay: Duration) -> (Url, Received) {
+        let received = Received::default();
+
+        let app = Router::new()
+            .route(
+                "/hook",
+                post(
+                    move |State(received): State<Received>, Json(body): Json<Value>| async move {
+                        tokio::time::sleep(delay).await;
+                        received.lock().push(body);
+                        status
+                    },
+                ),
+            )
+            .with_state(received.clone());
+
+        let listener = TcpListener::bind(SocketAddr::from(([127, 0, 0, 1], 0)))
+            .await
+            .unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+
+        (
+            Url::parse(&format!("http://{addr}/hook")).unwrap(),
+            received,
+        )
+    }
+
+    #[tokio::test]
+    async fn webhook_posts_alert_json() {
+        let (url, received) = stand_in(StatusCode::OK, Duration::ZERO).await;
+
+        AlertSink::Webhook(url)
+            .send(&alert(), TIMEOUT)
+            .await
+            .unwrap();
+
+        let received = received.lock().clone();
+        assert_eq!(received.len(), 1);
+        assert_eq!(received[0]["endpoint_id"], "test-endpoint");
+        assert_eq!(received[0]["event"], "status_changed");
+        assert_eq!(received[0]["to"], "down");
+        assert_eq!(received[0]["message"], alert().message());
+    }
+
+    #[tokio::test]
+    async fn slack_posts_text_payload() {
+        let (url, received) = stand_in(StatusCode::OK, Duration::ZERO).await;
+
+        AlertSink::Slack(url).send(&alert(), TIMEOUT).await.unwrap();
+
+        let received = received.lock().clone();
+        assert_eq!(received.len(), 1);
+        assert_eq!(
+            received[0],
+            serde_json::json!({ "text": alert().message() })
+        );
+    }
+
+    #[tokio::test]
+    async fn webhook_error_status_fails() {
+        let (url, _) = stand_in(StatusCode::INTERNAL_SERVER_ERROR, Duration::ZERO).await;
+
+        assert!(AlertSink::Webhook(url)
+            .send(&alert(), TIMEOUT)
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn webhook_times_out() {
+        let (url, _) = stand_in(StatusCode::OK, Duration::from_secs(30)).await;
+
+        let result = AlertSink::Webhook(url)
+            .send(&alert(), Duration::from_millis(200))
+            .await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn command_receives_alert() {
+        let dir = std::env::temp_dir().join(format!("ocr-api-alert-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let stdin = dir.join("stdin.json");
+        let env = dir.join("env.txt");
+
+        let command = format!(
+            "cat > {stdin} && echo \"$OCR_ALERT_ENDPOINT_ID\" > {env}",
+            stdin = stdin.display(),
+            env = env.display(),
+        );
+        AlertSink::Command(command)
+            .send(&alert(), TIMEOUT)
+            .await
+            .unwrap();
+
+        let payload: Value = serde_json::from_slice(&std::fs::read(&stdin).unwrap()).unwrap();
+        assert_eq!(payload["endpoint_id"], "test-endpoint");
+        assert_eq!(
+            std::fs::read_to_string(&env).unwrap().trim(),
+            "test-endpoint"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn command_failure_fails() {
+        let result = AlertSink::Command("exit 3".to_string())
+            .send(&alert(), TIMEOUT)
+            .await;
+
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn command_times_out() {
+        let started = std::time::Instant::now();
+
+        let result = AlertSink::Command("sleep 30".to_string())
+            .send(&alert(), Duration::from_millis(200))
+            .await;
+
+        assert!(result.is_err());
+        assert!(started.elapsed() < Duration::from_secs(5));
+    }
+}
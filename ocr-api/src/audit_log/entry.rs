@@ -0,0 +1,102 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use url::Url;
+
+use crate::{
+    endpoint_watcher::{endpoint::EndpointId, Endpoint},
+    helpers::id::time_rand_id,
+};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct AuditEntry {
+    pub id: String,
+    pub at: DateTime<Utc>,
+    #[serde(flatten)]
+    pub actor: AuditActor,
+    pub action: AuditAction,
+    pub target: AuditTarget,
+    pub before: Option<serde_json::Value>,
+    pub after: Option<serde_json::Value>,
+}
+
+impl AuditEntry {
+    pub fn new(actor: AuditActor, action: AuditAction, target: AuditTarget) -> Self {
+        Self {
+            id: time_rand_id(),
+            at: Utc::now(),
+            actor,
+            action,
+            target,
+            before: None,
+            after: None,
+        }
+    }
+
+    pub fn with_before<T>(mut self, before: Option<&T>) -> Self
+    where
+        T: Serialize,
+    {
+        self.before = before.and_then(|x| serde_json::to_value(x).ok());
+        self
+    }
+
+    pub fn with_after<T>(mut self, after: Option<&T>) -> Self
+    where
+        T: Serialize,
+    {
+        self.after = after.and_then(|x| serde_json::to_value(x).ok());
+        self
+    }
+}
+
+/// Who performed an audited action.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuditActor {
+    pub key_name: Option<String>,
+    /// The tenant of the API key, unset for the gateway's own keys.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    pub source_ip: Option<String>,
+    pub request_id: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+pub enum AuditAction {
+    EndpointAdd,
+    EndpointRemove,
+    EndpointDisable,
+    EndpointEnable,
+    EndpointDrain,
+    EndpointUndrain,
+    ShadowReportReset,
+    CachePurge,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AuditTarget {
+    pub endpoint_id: Option<EndpointId>,
+    pub endpoint_url: Option<Url>,
+}
+
+impl AuditTarget {
+    pub fn endpoint_id<T>(id: T) -> Self
+    where
+        T: Into<EndpointId>,
+    {
+        Self {
+            endpoint_id: Some(id.into()),
+            endpoint_url: None,
+        }
+    }
+}
+
+impl From<&Endpoint> for AuditTarget {
+    fn from(endpoint: &Endpoint) -> Self {
+        Self {
+            endpoint_id: Some(endpoint.id.clone()),
+            endpoint_url: Some(endpoint.url.clone()),
+        }
+    }
+}
@@ -0,0 +1,172 @@
+use std::{
+    collections::VecDeque,
+    fs::OpenOptions,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use once_cell::sync::OnceCell;
+use parking_lot::RwLock;
+use tokio::{fs::File, io::AsyncWriteExt, sync::Mutex};
+use tracing::{debug, error, info, warn};
+
+use super::entry::{AuditAction, AuditEntry};
+use crate::config::Config;
+
+static AUDIT_LOG: OnceCell<AuditLog> = OnceCell::new();
+
+/// Append-only log of admin actions.
+///
+/// Entries are appended to the configured file (if any) as JSON lines
+/// and the most recent ones are kept in memory for querying.
+#[derive(Debug)]
+pub struct AuditLog {
+    file: Option<Mutex<File>>,
+    entries: RwLock<VecDeque<AuditEntry>>,
+    max_entries: usize,
+}
+
+impl AuditLog {
+    pub fn global() -> &'static Self {
+        AUDIT_LOG.get_or_init(|| {
+            info!("Creating global AuditLog");
+
+            let config = &Config::global().audit;
+
+            Self::new(
+                config.audit_log_file.as_deref(),
+                config.audit_log_max_entries,
+            )
+        })
+    }
+
+    fn new(path: Option<&Path>, max_entries: usize) -> Self {
+        let mut entries = VecDeque::with_capacity(max_entries);
+
+        let file = path.map(|path| {
+            debug!(?path, "Opening audit log file");
+
+            if let Ok(existing) = std::fs::File::open(path) {
+                for line in BufReader::new(existing).lines().map_while(Result::ok) {
+                    match serde_json::from_str::<AuditEntry>(&line) {
+                        Ok(entry) => {
+                            if entries.len() >= max_entries {
+                                entries.pop_front();
+                            }
+                            entries.push_back(entry);
+                        }
+                        Err(e) => {
+                            warn!(error = ?e, ?line, "Failed to parse audit log line");
+                        }
+                    }
+                }
+            }
+
+            let file = OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .expect("Failed to open audit log file!");
+
+            Mutex::new(File::from_std(file))
+        });
+
+        Self {
+            file,
+            entries: RwLock::new(entries),
+            max_entries,
+        }
+    }
+}
+
+impl AuditLog {
+    #[tracing::instrument(skip_all, fields(action = ?entry.action))]
+    pub async fn record(&self, entry: AuditEntry) {
+        info!(
+            key = ?entry.actor.key_name,
+            ip = ?entry.actor.source_ip,
+            target = ?entry.target,
+            "Audit",
+        );
+
+        if let Some(file) = &self.file {
+            let mut line = match serde_json::to_vec(&entry) {
+                Ok(line) => line,
+                Err(e) => {
+                    error!(error = ?e, "Failed to serialize audit entry");
+                    return;
+                }
+            };
+            line.push(b'\n');
+
+            let mut file = file.lock().await;
+            if let Err(e) = file.write_all(&line).await {
+                error!(error = ?e, "Failed to write audit entry");
+            }
+            if let Err(e) = file.flush().await {
+                error!(error = ?e, "Failed to flush audit log");
+            }
+        }
+
+        let mut entries = self.entries.write();
+        if entries.len() >= self.max_entries {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Returns matching entries, newest first.
+    pub fn query(&self, filter: &AuditFilter) -> Vec<AuditEntry> {
+        self.entries
+            .read()
+            .iter()
+            .rev()
+            .filter(|entry| filter.matches(entry))
+            .take(filter.limit.unwrap_or(100))
+            .cloned()
+            .collect()
+    }
+}
+
+#[derive(Debug, Default, serde::Deserialize)]
+pub struct AuditFilter {
+    pub action: Option<AuditAction>,
+    pub key_name: Option<String>,
+    pub tenant: Option<String>,
+    pub endpoint_id: Option<String>,
+    pub since: Option<chrono::DateTime<chrono::Utc>>,
+    pub limit: Option<usize>,
+}
+
+impl AuditFilter {
+    fn matches(&self, entry: &AuditEntry) -> bool {
+        if self.action.is_some_and(|action| action != entry.action) {
+            return false;
+        }
+
+        if let Some(key_name) = &self.key_name {
+            if entry.actor.key_name.as_ref() != Some(key_name) {
+                return false;
+            }
+        }
+
+        if let Some(tenant) = &self.tenant {
+            if entry.actor.tenant.as_ref() != Some(tenant) {
+                return false;
+            }
+        }
+
+        if let Some(endpoint_id) = &self.endpoint_id {
+            let entry_endpoint_id = entry.target.endpoint_id.as_ref().map(ToString::to_string);
+            if entry_endpoint_id.as_ref() != Some(endpoint_id) {
+                return false;
+            }
+        }
+
+        if self.since.is_some_and(|since| entry.at < since) {
+            return false;
+        }
+
+        true
+    }
+}
@@ -0,0 +1,5 @@
+pub mod entry;
+pub mod log;
+
+pub use entry::{AuditAction, AuditActor, AuditEntry, AuditTarget};
+pub use log::AuditLog;
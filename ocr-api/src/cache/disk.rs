@@ -0,0 +1,174 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+    time::{Duration, SystemTime},
+};
+
+use axum::body::Bytes;
+use tracing::{debug, warn};
+
+use super::CachedResponse;
+
+const ENTRY_EXTENSION: &str = "entry";
+
+/// Whether the file is an entry of this cache, or one being written.
+///
+/// The cache directory may be shared, so anything else in it is left alone.
+fn is_cache_file(path: &Path) -> bool {
+    path.file_name()
+        .and_then(|x| x.to_str())
+        .is_some_and(|name| {
+            name.ends_with(&format!(".{ENTRY_EXTENSION}"))
+                || name.ends_with(&format!(".{ENTRY_EXTENSION}.tmp"))
+        })
+}
+
+/// Cached responses on disk, one file per entry.
+///
+/// Each file is the JSON metadata of the response on the first line, followed by the body.
+#[derive(Debug)]
+pub struct DiskCache {
+    dir: PathBuf,
+}
+
+impl DiskCache {
+    pub fn new(dir: PathBuf) -> Self {
+        debug!(?dir, "Creating cache directory");
+        std::fs::create_dir_all(&dir).expect("Failed to create cache directory!");
+
+        Self { dir }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.dir.join(format!("{key}.{ENTRY_EXTENSION}"))
+    }
+
+    pub async fn get(&self, key: &str) -> Option<CachedResponse> {
+        let data = match tokio::fs::read(self.path(key)).await {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return None,
+            Err(e) => {
+                warn!(?key, error = ?e, "Failed to read cache entry");
+                return None;
+            }
+        };
+
+        let split = data.iter().position(|x| *x == b'\n')?;
+        let mut entry = match serde_json::from_slice::<CachedResponse>(&data[..split]) {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!(?key, error = ?e, "Invalid cache entry, removing it");
+                self.remove(key).await;
+                return None;
+            }
+        };
+        entry.body = Bytes::copy_from_slice(&data[split + 1..]);
+
+        Some(entry)
+    }
+
+    pub async fn insert(&self, entry: &CachedResponse) {
+        let mut data = match serde_json::to_vec(entry) {
+            Ok(data) => data,
+            Err(e) => {
+                warn!(key = ?entry.key, error = ?e, "Failed to serialize cache entry");
+                return;
+            }
+        };
+        data.push(b'\n');
+        data.extend_from_slice(&entry.body);
+
+        // Written to a temporary file first so readers never see a half-written entry
+        let path = self.path(&entry.key);
+        let tmp_path = path.with_extension(format!("{ENTRY_EXTENSION}.tmp"));
+        let written = match tokio::fs::write(&tmp_path, data).await {
+            Ok(()) => tokio::fs::rename(&tmp_path, &path).await,
+            Err(e) => Err(e),
+        };
+
+        if let Err(e) = written {
+            warn!(key = ?entry.key, error = ?e, "Failed to write cache entry");
+        }
+    }
+
+    pub async fn remove(&self, key: &str) -> bool {
+        match tokio::fs::remove_file(self.path(key)).await {
+            Ok(()) => true,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => false,
+            Err(e) => {
+                warn!(?key, error = ?e, "Failed to remove cache entry");
+                false
+            }
+        }
+    }
+
+    /// Remove the entries matching the filter, returning how many were removed.
+    async fn remove_where<F>(&self, filter: F) -> usize
+    where
+        F: Fn(&std::fs::Metadata) -> bool,
+    {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!(dir = ?self.dir, error = ?e, "Failed to read cache directory");
+                return 0;
+            }
+        };
+
+        let mut removed = 0;
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if !is_cache_file(&entry.path()) {
+                continue;
+            }
+
+            let matches = entry
+                .metadata()
+                .await
+                .is_ok_and(|metadata| metadata.is_file() && filter(&metadata));
+
+            if matches && tokio::fs::remove_file(entry.path()).await.is_ok() {
+                removed += 1;
+            }
+        }
+
+        removed
+    }
+
+    pub async fn clear(&self) -> usize {
+        self.remove_where(|_| true).await
+    }
+
+    /// Remove entries written longer than `ttl` ago.
+    pub async fn remove_expired(&self, ttl: Duration) -> usize {
+        let now = SystemTime::now();
+
+        self.remove_where(|metadata| {
+            metadata
+                .modified()
+                .ok()
+                .and_then(|modified| now.duration_since(modified).ok())
+                .is_some_and(|age| age > ttl)
+        })
+        .await
+    }
+
+    pub async fn len(&self) -> usize {
+        let mut entries = match tokio::fs::read_dir(&self.dir).await {
+            Ok(entries) => entries,
+            Err(_) => return 0,
+        };
+
+        let mut count = 0;
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            if entry.path().extension().and_then(|x| x.to_str()) == Some(ENTRY_EXTENSION) {
+                count += 1;
+            }
+        }
+
+        count
+    }
+}
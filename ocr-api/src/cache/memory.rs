@@ -0,0 +1,110 @@
+use std::collections::{BTreeMap, HashMap};
+
+use super::CachedResponse;
+
+/// Roughly how much memory an entry takes besides its body.
+const ENTRY_OVERHEAD: usize = 256;
+
+/// In-memory cache that drops the least recently used entries once it is over its size.
+#[derive(Debug)]
+pub struct MemoryCache {
+    entries: HashMap<String, (CachedResponse, u64)>,
+    /// Keys by when they were last used.
+    recency: BTreeMap<u64, String>,
+    tick: u64,
+    size: usize,
+    max_size: usize,
+}
+
+impl MemoryCache {
+    pub fn new(max_size: usize) -> Self {
+        Self {
+            entries: HashMap::new(),
+            recency: BTreeMap::new(),
+            tick: 0,
+            size: 0,
+            max_size,
+        }
+    }
+
+    fn entry_size(entry: &CachedResponse) -> usize {
+        entry.body.len() + entry.key.len() + ENTRY_OVERHEAD
+    }
+
+    pub fn get(&mut self, key: &str) -> Option<CachedResponse> {
+        self.tick += 1;
+
+        let (entry, used) = self.entries.get_mut(key)?;
+        self.recency.remove(used);
+        *used = self.tick;
+        self.recency.insert(self.tick, key.to_string());
+
+        Some(entry.clone())
+    }
+
+    pub fn insert(&mut self, entry: CachedResponse) {
+        let size = Self::entry_size(&entry);
+        if size > self.max_size {
+            return;
+        }
+
+        self.remove(&entry.key);
+
+        while self.size + size > self.max_size {
+            match self.recency.pop_first() {
+                Some((_, key)) => {
+                    if let Some((evicted, _)) = self.entries.remove(&key) {
+                        self.size -= Self::entry_size(&evicted);
+                    }
+                }
+                None => break,
+            }
+        }
+
+        self.tick += 1;
+        self.size += size;
+        self.recency.insert(self.tick, entry.key.clone());
+        self.entries.insert(entry.key.clone(), (entry, self.tick));
+    }
+
+    pub fn remove(&mut self, key: &str) -> bool {
+        match self.entries.remove(key) {
+            Some((entry, used)) => {
+                self.recency.remove(&used);
+                self.size -= Self::entry_size(&entry);
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn clear(&mut self) -> usize {
+        let count = self.entries.len();
+
+        self.entries.clear();
+        self.recency.clear();
+        self.size = 0;
+
+        count
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub const fn size(&self) -> usize {
+        self.size
+    }
+
+    pub const fn max_size(&self) -> usize {
+        self.max_size
+    }
+
+    /// The entries, most recently used first.
+    pub fn recent(&self) -> impl Iterator<Item = &CachedResponse> {
+        self.recency
+            .values()
+            .rev()
+            .filter_map(|key| self.entries.get(key).map(|(entry, _)| entry))
+    }
+}
@@ -0,0 +1,378 @@
+mod disk;
+mod memory;
+
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes},
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use reqwest::StatusCode;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, info, warn};
+
+use self::{disk::DiskCache, memory::MemoryCache};
+use crate::{
+    config::Config,
+    ocr::{fallback, HANDLER_HEADER},
+    router::MAX_BODY_SIZE,
+};
+
+static RESULT_CACHE: OnceCell<Arc<ResultCache>> = OnceCell::new();
+
+/// Response header saying whether the result came from the cache.
+pub const CACHE_HEADER: &str = "x-ocr-cache";
+
+/// How often to remove expired entries from the disk cache.
+const DISK_CLEANUP_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+/// A successful OCR response, kept for repeat requests.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CachedResponse {
+    pub key: String,
+    /// The handler that was requested.
+    pub handler: String,
+    /// The handler that actually served the request.
+    pub served_by: Option<String>,
+    pub status: u16,
+    pub content_type: Option<String>,
+    pub created_at: DateTime<Utc>,
+    #[serde(skip)]
+    pub body: Bytes,
+}
+
+impl CachedResponse {
+    fn is_expired(&self, ttl: Duration) -> bool {
+        (Utc::now() - self.created_at)
+            .to_std()
+            .is_ok_and(|age| age > ttl)
+    }
+}
+
+impl IntoResponse for CachedResponse {
+    fn into_response(self) -> Response {
+        let mut response = Response::builder()
+            .status(StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK))
+            .header(CACHE_HEADER, "hit");
+
+        if let Some(content_type) = self.content_type {
+            response = response.header(header::CONTENT_TYPE, content_type);
+        }
+        if let Some(served_by) = self.served_by {
+            response = response.header(HANDLER_HEADER, served_by);
+        }
+
+        response
+            .body(Body::from(self.body))
+            .expect("Failed to build response")
+    }
+}
+
+/// OCR results by a hash of the upload, the handler and the options.
+///
+/// Kept in memory, and optionally on disk as a second tier.
+#[derive(Debug)]
+pub struct ResultCache {
+    memory: Mutex<MemoryCache>,
+    disk: Option<DiskCache>,
+    ttl: Duration,
+    hits: AtomicUsize,
+    misses: AtomicUsize,
+    bypasses: AtomicUsize,
+}
+
+impl ResultCache {
+    pub fn global() -> &'static Arc<Self> {
+        RESULT_CACHE.get_or_init(|| {
+            info!("Creating global ResultCache");
+
+            let config = &Config::global().cache;
+
+            let cache = Arc::new(Self {
+                memory: Mutex::new(MemoryCache::new(config.cache_max_memory_mb * 1024 * 1024)),
+                disk: config.cache_dir.clone().map(DiskCache::new),
+                ttl: config.cache_ttl.into(),
+                hits: AtomicUsize::new(0),
+                misses: AtomicUsize::new(0),
+                bypasses: AtomicUsize::new(0),
+            });
+
+            if cache.disk.is_some() {
+                tokio::spawn({
+                    debug!("Starting cache cleanup task");
+                    let cache = cache.clone();
+                    async move {
+                        let mut interval = tokio::time::interval(DISK_CLEANUP_INTERVAL);
+                        loop {
+                            interval.tick().await;
+                            if let Some(disk) = &cache.disk {
+                                let removed = disk.remove_expired(cache.ttl).await;
+                                debug!(removed, "Removed expired cache entries from disk");
+                            }
+                        }
+                    }
+                });
+            }
+
+            cache
+        })
+    }
+
+    pub fn is_enabled() -> bool {
+        Config::global().cache.is_enabled()
+    }
+
+    async fn get(&self, key: &str) -> Option<CachedResponse> {
+        let entry = self.memory.lock().get(key);
+        if let Some(entry) = entry {
+            if !entry.is_expired(self.ttl) {
+                return Some(entry);
+            }
+            self.memory.lock().remove(key);
+        }
+
+        let disk = self.disk.as_ref()?;
+        let entry = disk.get(key).await?;
+        if entry.is_expired(self.ttl) {
+            disk.remove(key).await;
+            return None;
+        }
+
+        self.memory.lock().insert(entry.clone());
+
+        Some(entry)
+    }
+
+    async fn insert(&self, entry: CachedResponse) {
+        if let Some(disk) = &self.disk {
+            disk.insert(&entry).await;
+        }
+
+        self.memory.lock().insert(entry);
+    }
+
+    pub async fn remove(&self, key: &str) -> bool {
+        let in_memory = self.memory.lock().remove(key);
+        let on_disk = match &self.disk {
+            Some(disk) => disk.remove(key).await,
+            None => false,
+        };
+
+        in_memory || on_disk
+    }
+
+    /// Remove all entries, returning how many were removed from memory and from disk.
+    pub async fn purge(&self) -> (usize, usize) {
+        let in_memory = self.memory.lock().clear();
+        let on_disk = match &self.disk {
+            Some(disk) => disk.clear().await,
+            None => 0,
+        };
+
+        (in_memory, on_disk)
+    }
+
+    pub async fn stats(&self, limit: usize) -> CacheStats {
+        let (memory, recent) = {
+            let memory = self.memory.lock();
+
+            (
+                MemoryCacheStats {
+                    entries: memory.len(),
+                    size: memory.size(),
+                    max_size: memory.max_size(),
+                },
+                memory.recent().take(limit).cloned().collect(),
+            )
+        };
+
+        let disk = match &self.disk {
+            Some(disk) => Some(DiskCacheStats {
+                dir: disk.dir().display().to_string(),
+                entries: disk.len().await,
+            }),
+            None => None,
+        };
+
+        CacheStats {
+            enabled: Self::is_enabled(),
+            ttl_secs: self.ttl.as_secs(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            bypasses: self.bypasses.load(Ordering::Relaxed),
+            memory,
+            disk,
+            recent,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub enabled: bool,
+    pub ttl_secs: u64,
+    pub hits: usize,
+    pub misses: usize,
+    pub bypasses: usize,
+    pub memory: MemoryCacheStats,
+    pub disk: Option<DiskCacheStats>,
+    /// The most recently used entries in memory.
+    pub recent: Vec<CachedResponse>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct MemoryCacheStats {
+    pub entries: usize,
+    pub size: usize,
+    pub max_size: usize,
+}
+
+#[derive(Debug, Serialize)]
+pub struct DiskCacheStats {
+    pub dir: String,
+    pub entries: usize,
+}
+
+/// The `Cache-Control` directives of a request that affect the cache.
+#[derive(Debug, Clone, Copy, Default)]
+struct CacheDirectives {
+    /// Don't use a cached result.
+    no_cache: bool,
+    /// Don't cache the result either.
+    no_store: bool,
+}
+
+impl CacheDirectives {
+    fn from_headers(headers: &HeaderMap) -> Self {
+        let mut directives = Self::default();
+
+        for directive in headers
+            .get_all(header::CACHE_CONTROL)
+            .iter()
+            .filter_map(|x| x.to_str().ok())
+            .flat_map(|x| x.split(','))
+        {
+            match directive.trim().to_ascii_lowercase().as_str() {
+                "no-cache" => directives.no_cache = true,
+                "no-store" => {
+                    directives.no_cache = true;
+                    directives.no_store = true;
+                }
+                _ => {}
+            }
+        }
+
+        directives
+    }
+}
+
+/// A request going through the cache.
+#[derive(Debug)]
+pub struct CacheLookup {
+    handler: String,
+    key: Option<String>,
+    directives: CacheDirectives,
+}
+
+impl CacheLookup {
+    /// The key is from [`request_key`](crate::ocr::key::request_key).
+    pub fn new(handler: &str, key: Option<&str>, headers: &HeaderMap) -> Self {
+        Self {
+            handler: handler.to_string(),
+            key: key
+                .filter(|_| ResultCache::is_enabled())
+                .map(ToString::to_string),
+            directives: CacheDirectives::from_headers(headers),
+        }
+    }
+
+    /// The cached response, unless the client asked not to use the cache.
+    pub async fn hit(&self) -> Option<Response> {
+        let key = self.key.as_deref()?;
+        if self.directives.no_cache {
+            return None;
+        }
+
+        let cache = ResultCache::global();
+        let entry = cache.get(key).await?;
+
+        debug!(?key, "Serving result from cache");
+        cache.hits.fetch_add(1, Ordering::Relaxed);
+
+        Some(entry.into_response())
+    }
+
+    /// Cache the response if it is a successful result, and mark it as not coming from the cache.
+    pub async fn store(self, response: Response) -> Response {
+        let key = match self.key {
+            Some(key) => key,
+            None => return response,
+        };
+
+        let cache = ResultCache::global();
+        let cache_status = if self.directives.no_cache {
+            cache.bypasses.fetch_add(1, Ordering::Relaxed);
+            "bypass"
+        } else {
+            cache.misses.fetch_add(1, Ordering::Relaxed);
+            "miss"
+        };
+
+        let (mut parts, body) = response.into_parts();
+        parts
+            .headers
+            .insert(CACHE_HEADER, HeaderValue::from_static(cache_status));
+
+        if self.directives.no_store || !parts.status.is_success() {
+            return Response::from_parts(parts, body);
+        }
+
+        let body = match axum::body::to_bytes(body, MAX_BODY_SIZE).await {
+            Ok(body) => body,
+            Err(e) => {
+                warn!(error = ?e, "Failed to read response to cache");
+                return (
+                    StatusCode::BAD_GATEWAY,
+                    format!("Failed to read response: {:?}", e),
+                )
+                    .into_response();
+            }
+        };
+
+        if !fallback::is_failed_response(parts.status, &body) {
+            debug!(?key, "Caching result");
+
+            cache
+                .insert(CachedResponse {
+                    key,
+                    handler: self.handler,
+                    served_by: parts
+                        .headers
+                        .get(HANDLER_HEADER)
+                        .and_then(|x| x.to_str().ok())
+                        .map(ToString::to_string),
+                    status: parts.status.as_u16(),
+                    content_type: parts
+                        .headers
+                        .get(header::CONTENT_TYPE)
+                        .and_then(|x| x.to_str().ok())
+                        .map(ToString::to_string),
+                    created_at: Utc::now(),
+                    body: body.clone(),
+                })
+                .await;
+        }
+
+        Response::from_parts(parts, Body::from(body))
+    }
+}
@@ -1,10 +1,11 @@
-use clap::{Args, Parser};
+use std::{collections::BTreeMap, time::Duration};
+
+use clap::{error::ErrorKind, ArgAction, Args, CommandFactory, Parser};
+use ocr_common::timeframe::Timeframe;
 use once_cell::sync::Lazy;
 use rand::{distributions::Alphanumeric, prelude::*};
 use url::Url;
 
-use crate::helpers::timeframe::Timeframe;
-
 static CONFIG: Lazy<Config> = Lazy::new(Config::new);
 
 #[derive(Debug, Clone, Parser)]
@@ -24,6 +25,14 @@ pub struct Config {
     #[clap(short = 'P', long, default_value = "8000", env = "PORT")]
     pub port: u16,
 
+    /// Addresses of reverse proxies in front of the gateway, whose `X-Forwarded-For` header is trusted.
+    ///
+    /// Clients can send the header themselves, so it is ignored unless the request came from one of these.
+    /// Comma- or space-separated list of IP addresses.
+    /// eg. `127.0.0.1` when running behind a proxy on the same machine.
+    #[clap(long = "trusted-proxy", env = "TRUSTED_PROXIES", default_value = "", value_parser = value_parser_parse_ips())]
+    pub trusted_proxies: std::vec::Vec<std::net::IpAddr>,
+
     /// The URLs of the OCR APIs to use.
     ///
     /// Can be any combination of repeating this flag with comma- or space-separated lists of URLs.
@@ -38,8 +47,229 @@ pub struct Config {
     #[clap(long, value_parser = Timeframe::parse_str, default_value = "5s", env = "API_CHECK_INTERVAL")]
     pub api_check_interval: Timeframe,
 
+    /// How much to randomly vary check intervals by, as a fraction of the interval.
+    ///
+    /// Spreads out checks so endpoints aren't all hit at the same time.
+    /// eg. `0.1` to vary intervals by up to 10% in either direction.
+    /// Must be at least `0` and less than `1`, so an interval never shrinks to nothing.
+    #[clap(long, default_value = "0.1", env = "API_CHECK_JITTER", value_parser = value_parser_parse_jitter())]
+    pub api_check_jitter: f64,
+
+    /// How soon to re-check an endpoint right after it failed a check.
+    #[clap(long, value_parser = Timeframe::parse_str, default_value = "1s", env = "API_CHECK_RETRY_INTERVAL")]
+    pub api_check_retry_interval: Timeframe,
+
+    /// How many times to quickly re-check a failing endpoint before backing off.
+    #[clap(long, default_value = "3", env = "API_CHECK_FAST_RETRIES")]
+    pub api_check_fast_retries: u32,
+
+    /// The longest to wait between checks of an endpoint that is down.
+    ///
+    /// After the fast retries, the check interval doubles with every failure up to this cap.
+    #[clap(long, value_parser = Timeframe::parse_str, default_value = "5min", env = "API_CHECK_MAX_BACKOFF")]
+    pub api_check_max_backoff: Timeframe,
+
+    /// How long to wait for in-flight requests when draining an endpoint, unless specified per request.
+    #[clap(long, value_parser = Timeframe::parse_str, default_value = "1min", env = "ENDPOINT_DRAIN_TIMEOUT")]
+    pub endpoint_drain_timeout: Timeframe,
+
+    /// How many requests to send to an endpoint at the same time, unless it declares or advertises its own limit.
+    ///
+    /// `0` means no limit.
+    #[clap(long, default_value = "0", env = "ENDPOINT_MAX_CONCURRENCY")]
+    pub endpoint_max_concurrency: usize,
+
+    /// How many requests can wait for an endpoint with free capacity before new ones are turned away.
+    #[clap(long, default_value = "100", env = "ENDPOINT_QUEUE_SIZE")]
+    pub endpoint_queue_size: usize,
+
+    /// How long a request waits for an endpoint with free capacity.
+    ///
+    /// Covers all the handlers the request falls back to, not each of them.
+    #[clap(long, value_parser = Timeframe::parse_str, default_value = "30s", env = "ENDPOINT_QUEUE_TIMEOUT")]
+    pub endpoint_queue_timeout: Timeframe,
+
+    /// How many past checks to remember per endpoint.
+    ///
+    /// Used to calculate uptime and latency statistics.
+    #[clap(long, default_value = "1000", env = "ENDPOINT_HISTORY_SIZE")]
+    pub endpoint_history_size: usize,
+
+    /// The default windows over which to calculate endpoint statistics.
+    ///
+    /// Comma- or space-separated list of human readable durations.
+    /// eg. `5min,1h,1d` to get statistics for the last 5 minutes, hour and day.
+    #[clap(long, value_parser = value_parser_parse_timeframes(), default_value = "5min,1h,1d", env = "ENDPOINT_HISTORY_WINDOWS")]
+    pub endpoint_history_windows: std::vec::Vec<Timeframe>,
+
+    /// How long to wait for in-flight requests to finish when shutting down.
+    ///
+    /// During this time new requests are refused and `/readyz` reports not ready.
+    /// Connections still open after it has passed are cut off.
+    #[clap(long, value_parser = Timeframe::parse_str, default_value = "30s", env = "SHUTDOWN_GRACE_PERIOD")]
+    pub shutdown_grace_period: Timeframe,
+
+    /// Handlers to try instead when the requested one has no live endpoints or fails.
+    ///
+    /// A handler fails when its endpoint can't be reached or responds with a server error,
+    /// or with a 404 because it doesn't have the handler. Other errors are returned to the client.
+    /// Comma-separated list of chains, with the handlers of a chain separated by `->`.
+    /// Clients can override the chain per request with the `fallback` query parameter.
+    /// eg. `doctr -> ocrs -> tesseract` to try `ocrs` and then `tesseract` if `doctr` isn't available,
+    /// or `tesseract` if `ocrs` isn't.
+    #[clap(long = "handler-fallback", env = "HANDLER_FALLBACKS", default_value = "", value_parser = value_parser_parse_fallback_chains())]
+    pub handler_fallbacks: std::vec::Vec<FallbackChain>,
+
+    /// Gateway-defined handler names that map to one or more real handlers.
+    ///
+    /// Comma-separated list of `name=[strategy:]handler|handler` entries.
+    /// The strategy is one of:
+    ///   - `first` (default) to use the first live handler, trying the next one if it fails;
+    ///   - `random` to use the live handlers in random order;
+    ///   - `ensemble` to run all of them and merge the results.
+    ///
+    /// Virtual handlers take precedence over real handlers with the same name.
+    /// eg. `fast=ocrs|tesseract,accurate=ensemble:doctr|easy-ocr|surya`
+    #[clap(long = "virtual-handler", env = "VIRTUAL_HANDLERS", default_value = "", value_parser = value_parser_parse_virtual_handlers())]
+    pub virtual_handlers: std::vec::Vec<VirtualHandler>,
+
+    /// The ID of this gateway, used to detect request loops when gateways are chained.
+    ///
+    /// Should be unique among the chained gateways.
+    /// May only contain letters, digits, `.`, `_` and `-`.
+    /// If not set, a random ID will be generated on startup.
+    #[clap(long, env = "GATEWAY_ID", default_value = "", value_parser = value_parser_parse_gateway_id())]
+    pub gateway_id: String,
+
+    /// How many gateways a request may pass through before it is refused.
+    #[clap(long, default_value = "8", env = "GATEWAY_MAX_HOPS")]
+    pub gateway_max_hops: usize,
+
     #[clap(flatten)]
     pub auth: AuthConfig,
+
+    #[clap(flatten)]
+    pub audit: AuditConfig,
+
+    #[clap(flatten)]
+    pub alerts: AlertsConfig,
+
+    #[clap(flatten)]
+    pub hedging: HedgingConfig,
+
+    #[clap(flatten)]
+    pub shadow: ShadowConfig,
+
+    #[clap(flatten)]
+    pub jobs: JobsConfig,
+
+    #[clap(flatten)]
+    pub batch: BatchConfig,
+
+    #[clap(flatten)]
+    pub cache: CacheConfig,
+
+    #[clap(flatten)]
+    pub priority: PriorityConfig,
+
+    #[clap(flatten)]
+    pub tenants: TenantsConfig,
+
+    #[clap(flatten)]
+    pub cors: CorsConfig,
+}
+
+#[derive(Debug, Clone)]
+pub struct FallbackChain {
+    pub handlers: Vec<String>,
+}
+
+#[derive(Debug, Clone)]
+pub struct VirtualHandler {
+    pub name: String,
+    pub strategy: VirtualHandlerStrategy,
+    pub handlers: Vec<String>,
+}
+
+impl VirtualHandler {
+    /// Whether an endpoint with the given handlers can serve this virtual handler.
+    #[must_use]
+    pub fn is_served_by(&self, available_handlers: &[String]) -> bool {
+        self.handlers.iter().any(|x| available_handlers.contains(x))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VirtualHandlerStrategy {
+    First,
+    Random,
+    Ensemble,
+}
+
+impl std::str::FromStr for VirtualHandlerStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "first" => Ok(Self::First),
+            "random" => Ok(Self::Random),
+            "ensemble" => Ok(Self::Ensemble),
+            _ => Err(format!(
+                "Unknown strategy {s:?}, expected one of `first`, `random` or `ensemble`"
+            )),
+        }
+    }
+}
+
+/// How urgently a request should be served when endpoints are busy.
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "snake_case")]
+pub enum Priority {
+    /// Bulk work that can wait, like nightly reprocessing.
+    Low,
+    #[default]
+    Normal,
+    /// Interactive users, who get reserved capacity.
+    High,
+}
+
+impl Priority {
+    #[must_use]
+    pub const fn as_str(self) -> &'static str {
+        match self {
+            Self::Low => "low",
+            Self::Normal => "normal",
+            Self::High => "high",
+        }
+    }
+}
+
+impl std::str::FromStr for Priority {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "low" => Ok(Self::Low),
+            "normal" => Ok(Self::Normal),
+            "high" => Ok(Self::High),
+            _ => Err(format!(
+                "Unknown priority {s:?}, expected one of `low`, `normal` or `high`"
+            )),
+        }
+    }
 }
 
 #[derive(Debug, Clone, Args)]
@@ -50,6 +280,476 @@ pub struct AuthConfig {
     /// If not set, a random key will be generated on startup and printed to stdout.
     #[clap(long, env = "API_AUTH_KEY", default_value = "", value_parser = value_parser_parse_auth_key())]
     pub api_auth_key: String,
+
+    /// Additional named API keys.
+    ///
+    /// Comma- or space-separated list of `name=key` pairs.
+    /// The key set by `--api-auth-key` is always available under the name `default`.
+    /// eg. `ci=some-long-random-key,dashboard=another-long-random-key`
+    #[clap(long = "api-key", env = "API_AUTH_KEYS", default_value = "", value_parser = value_parser_parse_named_auth_keys())]
+    pub api_auth_keys: std::vec::Vec<NamedAuthKey>,
+}
+
+impl AuthConfig {
+    pub const DEFAULT_KEY_NAME: &'static str = "default";
+
+    /// Iterate over all configured keys as `(name, key)` pairs.
+    pub fn keys(&self) -> impl Iterator<Item = (&str, &str)> {
+        std::iter::once((Self::DEFAULT_KEY_NAME, self.api_auth_key.as_str())).chain(
+            self.api_auth_keys
+                .iter()
+                .map(|x| (x.name.as_str(), x.key.as_str())),
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NamedAuthKey {
+    pub name: String,
+    pub key: String,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct AuditConfig {
+    /// Where to append the audit log of admin actions.
+    ///
+    /// Each entry is written as a single JSON line.
+    /// If not set, entries are only kept in memory.
+    #[clap(long, env = "AUDIT_LOG_FILE")]
+    pub audit_log_file: Option<std::path::PathBuf>,
+
+    /// How many of the most recent audit log entries to keep in memory for querying.
+    #[clap(long, default_value = "1000", env = "AUDIT_LOG_MAX_ENTRIES")]
+    pub audit_log_max_entries: usize,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct AlertsConfig {
+    /// URLs to POST a JSON payload to when an endpoint changes state.
+    ///
+    /// Comma- or space-separated list of URLs.
+    #[clap(long = "alert-webhook-url", env = "ALERT_WEBHOOK_URLS", default_value = "", value_parser = value_parser_parse_urls())]
+    pub alert_webhook_urls: std::vec::Vec<Url>,
+
+    /// Slack-compatible incoming webhook URLs to notify when an endpoint changes state.
+    ///
+    /// Comma- or space-separated list of URLs.
+    #[clap(long = "alert-slack-webhook-url", env = "ALERT_SLACK_WEBHOOK_URLS", default_value = "", value_parser = value_parser_parse_urls())]
+    pub alert_slack_webhook_urls: std::vec::Vec<Url>,
+
+    /// A shell command to run when an endpoint changes state.
+    ///
+    /// The alert is passed as JSON on stdin.
+    /// The `OCR_ALERT_ENDPOINT_ID`, `OCR_ALERT_ENDPOINT_URL` and `OCR_ALERT_MESSAGE`
+    /// environment variables are also set.
+    #[clap(long, env = "ALERT_COMMAND")]
+    pub alert_command: Option<String>,
+
+    /// How long a state change must persist before an alert is sent.
+    #[clap(long, value_parser = Timeframe::parse_str, default_value = "30s", env = "ALERT_DEBOUNCE")]
+    pub alert_debounce: Timeframe,
+
+    /// The minimum time between alerts for the same endpoint and state.
+    #[clap(long, value_parser = Timeframe::parse_str, default_value = "5min", env = "ALERT_COOLDOWN")]
+    pub alert_cooldown: Timeframe,
+
+    /// How long a single webhook request or command may take before it's abandoned.
+    #[clap(long, value_parser = Timeframe::parse_str, default_value = "10s", env = "ALERT_TIMEOUT")]
+    pub alert_timeout: Timeframe,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct HedgingConfig {
+    /// Handlers to send hedged requests for.
+    ///
+    /// If an endpoint is slow to respond, the same request is also sent to a second endpoint
+    /// and whichever responds first is used.
+    /// To be able to send it twice, the whole request body is buffered in memory first
+    /// (up to the maximum upload size) rather than streamed to the endpoint.
+    /// Comma- or space-separated list of handler names, or `*` for all handlers.
+    #[clap(long = "hedge-handler", env = "HEDGE_HANDLERS", default_value = "", value_parser = value_parser_parse_names())]
+    pub hedge_handlers: std::vec::Vec<String>,
+
+    /// Which percentile of recent response times of a handler to wait for before hedging.
+    #[clap(long, default_value = "95", env = "HEDGE_PERCENTILE")]
+    pub hedge_percentile: f64,
+
+    /// How long to wait before hedging while there are too few response times to go by.
+    #[clap(long, value_parser = Timeframe::parse_str, default_value = "1s", env = "HEDGE_DEFAULT_DELAY")]
+    pub hedge_default_delay: Timeframe,
+
+    /// The shortest time to wait before hedging.
+    #[clap(long, value_parser = Timeframe::parse_str, default_value = "50ms", env = "HEDGE_MIN_DELAY")]
+    pub hedge_min_delay: Timeframe,
+
+    /// The share of requests that may be hedged, to avoid piling on load when all endpoints are slow.
+    ///
+    /// eg. `0.1` to hedge at most 10% of requests.
+    #[clap(long, default_value = "0.1", env = "HEDGE_BUDGET")]
+    pub hedge_budget: f64,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct ShadowConfig {
+    /// The URLs of OCR APIs to mirror live traffic to, eg. candidate builds.
+    ///
+    /// Their responses are never returned to clients, only compared to the live ones.
+    /// Can be any combination of repeating this flag with comma- or space-separated lists of URLs.
+    #[clap(long = "shadow-api-url", env = "SHADOW_API_URLS", default_value = "", value_parser = value_parser_parse_absolute_urls())]
+    pub shadow_api_urls: std::vec::Vec<Url>,
+
+    /// The percentage of OCR requests to mirror to shadow endpoints.
+    #[clap(long, default_value = "10", env = "SHADOW_TRAFFIC_PERCENT")]
+    pub shadow_traffic_percent: f64,
+
+    /// How many of the most recent comparisons to keep for the report.
+    #[clap(long, default_value = "100", env = "SHADOW_REPORT_SIZE")]
+    pub shadow_report_size: usize,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct CacheConfig {
+    /// How many megabytes of OCR results to keep in memory for repeat requests.
+    ///
+    /// The least recently used results are dropped first. `0` (the default) disables the in-memory cache.
+    ///
+    /// While any cache is enabled, uploads and responses are read fully into memory
+    /// rather than streamed, so they can be keyed and stored.
+    #[clap(long, default_value = "0", env = "CACHE_MAX_MEMORY_MB")]
+    pub cache_max_memory_mb: usize,
+
+    /// Where to keep OCR results on disk, as a second tier behind the in-memory cache.
+    ///
+    /// If not set, results are only cached in memory.
+    #[clap(long, env = "CACHE_DIR")]
+    pub cache_dir: Option<std::path::PathBuf>,
+
+    /// How long cached OCR results stay valid.
+    #[clap(long, value_parser = Timeframe::parse_str, default_value = "1d", env = "CACHE_TTL")]
+    pub cache_ttl: Timeframe,
+
+    /// Share one call to the endpoints between identical OCR requests that are in flight at the same time.
+    ///
+    /// Off by default, as uploads then have to be read fully into memory to be compared
+    /// rather than streamed.
+    #[clap(long, default_value_t = false, action = ArgAction::Set, env = "COALESCE_REQUESTS")]
+    pub coalesce_requests: bool,
+}
+
+impl CacheConfig {
+    #[must_use]
+    pub const fn is_enabled(&self) -> bool {
+        self.cache_max_memory_mb > 0 || self.cache_dir.is_some()
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct PriorityConfig {
+    /// The priority of requests that don't set the `X-Ocr-Priority` header and whose API key has none configured.
+    #[clap(long, default_value = "normal", env = "DEFAULT_PRIORITY")]
+    pub default_priority: Priority,
+
+    /// The priority of requests made with an API key.
+    ///
+    /// Requests can lower their priority with the `X-Ocr-Priority` header, but never raise it above this,
+    /// or above `default_priority` for keys without one. Keys of gateways chained in front of this one
+    /// need to be `high` for the priorities they pass on to be kept.
+    ///
+    /// Comma- or space-separated list of `key_name=priority` pairs, where priority is `low`, `normal` or `high`.
+    /// eg. `dashboard=high,nightly=low`
+    #[clap(long = "api-key-priority", env = "API_KEY_PRIORITIES", default_value = "", value_parser = value_parser_parse_key_priorities())]
+    pub api_key_priorities: std::vec::Vec<KeyPriority>,
+
+    /// Percentage of each endpoint's capacity, and of the queue for it, only high priority requests can use.
+    ///
+    /// Only applies to endpoints with a concurrency limit. Rounded up, but at least one request
+    /// can always be served at other priorities, so endpoints that take one request at a time reserve nothing.
+    #[clap(long, default_value = "20", env = "PRIORITY_RESERVED_PERCENT", value_parser = clap::value_parser!(u8).range(0..=100))]
+    pub priority_reserved_percent: u8,
+}
+
+impl PriorityConfig {
+    #[must_use]
+    pub fn key_priority(&self, key_name: &str) -> Option<Priority> {
+        self.api_key_priorities
+            .iter()
+            .find(|x| x.key_name == key_name)
+            .map(|x| x.priority)
+    }
+
+    /// How much of `capacity` is reserved for high priority requests.
+    ///
+    /// Rounded up, so small capacities still reserve some, but at least one is always left for other requests.
+    #[must_use]
+    pub fn reserved(&self, capacity: usize) -> usize {
+        let reserved = (capacity * usize::from(self.priority_reserved_percent)).div_ceil(100);
+
+        reserved.min(capacity.saturating_sub(1))
+    }
+
+    /// How much of `capacity` requests with the priority can use.
+    #[must_use]
+    pub fn available(&self, capacity: usize, priority: Priority) -> usize {
+        match priority {
+            Priority::High => capacity,
+            Priority::Normal | Priority::Low => capacity - self.reserved(capacity),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct KeyPriority {
+    pub key_name: String,
+    pub priority: Priority,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct TenantsConfig {
+    /// JSON file listing the tenants sharing the gateway, each with their own API keys, endpoints and quota.
+    ///
+    /// Requests made with a tenant's API key are only routed to its own endpoints and the shared ones,
+    /// and admin requests made with it only see and manage its own endpoints.
+    /// eg. `[{"name": "finance", "api_keys": {"finance-app": "some-long-random-key"},
+    /// "endpoints": ["http://10.0.0.5:8000"], "quota": {"max_requests": 10000, "period": "1d"}}]`
+    #[clap(long, env = "TENANTS_FILE", default_value = "", value_parser = value_parser_parse_tenants_file())]
+    pub tenants_file: std::vec::Vec<Tenant>,
+}
+
+impl TenantsConfig {
+    #[must_use]
+    pub fn tenant(&self, name: &str) -> Option<&Tenant> {
+        self.tenants_file.iter().find(|x| x.name == name)
+    }
+
+    /// Iterate over the API keys of all tenants as `(tenant, name, key)`.
+    pub fn keys(&self) -> impl Iterator<Item = (&Tenant, &str, &str)> {
+        self.tenants_file.iter().flat_map(|tenant| {
+            tenant
+                .api_keys
+                .iter()
+                .map(move |(name, key)| (tenant, name.as_str(), key.as_str()))
+        })
+    }
+}
+
+#[derive(Debug, Clone, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Tenant {
+    pub name: String,
+    /// The API keys of the tenant, by name.
+    pub api_keys: BTreeMap<String, String>,
+    /// Base URLs of the endpoints only the tenant's requests are routed to.
+    #[serde(default, deserialize_with = "deserialize_absolute_urls")]
+    pub endpoints: Vec<Url>,
+    #[serde(default)]
+    pub quota: TenantQuota,
+}
+
+/// How much a tenant may use the gateway in each period. Unlimited if not set.
+#[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct TenantQuota {
+    /// OCR requests, counting a batch as one request.
+    pub max_requests: Option<u64>,
+    /// Megabytes of uploads.
+    pub max_upload_mb: Option<u64>,
+    /// Periods start at the UNIX epoch, so daily ones start at midnight UTC.
+    #[serde(
+        serialize_with = "serialize_timeframe",
+        deserialize_with = "deserialize_timeframe"
+    )]
+    pub period: Timeframe,
+}
+
+impl Default for TenantQuota {
+    fn default() -> Self {
+        Self {
+            max_requests: None,
+            max_upload_mb: None,
+            period: Timeframe::Days(1),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct CorsConfig {
+    /// Origins browsers may call the public routes (OCR, jobs and endpoint listings) from.
+    ///
+    /// Comma- or space-separated list, or `*` for any origin.
+    /// Browsers can't call them from other origins if empty.
+    /// eg. `https://upload.example.com,https://intranet.example.com`
+    #[clap(long = "cors-allowed-origin", env = "CORS_ALLOWED_ORIGINS", default_value = "", value_parser = value_parser_parse_cors_origins())]
+    pub cors_allowed_origins: std::vec::Vec<String>,
+
+    /// Methods browsers may use on the public routes, or `*` for any.
+    #[clap(long = "cors-allowed-method", env = "CORS_ALLOWED_METHODS", default_value = "GET,POST,DELETE", value_parser = value_parser_parse_cors_methods())]
+    pub cors_allowed_methods: std::vec::Vec<String>,
+
+    /// Request headers browsers may send to the public routes, or `*` for any.
+    #[clap(long = "cors-allowed-header", env = "CORS_ALLOWED_HEADERS", default_value = "*", value_parser = value_parser_parse_cors_headers())]
+    pub cors_allowed_headers: std::vec::Vec<String>,
+
+    /// Whether browsers may send cookies along to the public routes.
+    ///
+    /// Requires the allowed origins to be listed, rather than `*`.
+    #[clap(long, default_value_t = false, action = ArgAction::Set, env = "CORS_ALLOW_CREDENTIALS")]
+    pub cors_allow_credentials: bool,
+
+    /// How long browsers may cache the answer to a preflight request to the public routes.
+    #[clap(long, value_parser = Timeframe::parse_str, default_value = "1h", env = "CORS_MAX_AGE")]
+    pub cors_max_age: Timeframe,
+
+    /// Origins browsers may call the admin routes from, in the same format as `--cors-allowed-origin`.
+    ///
+    /// Empty by default, so browsers can't call them from other origins.
+    #[clap(long = "admin-cors-allowed-origin", env = "ADMIN_CORS_ALLOWED_ORIGINS", default_value = "", value_parser = value_parser_parse_cors_origins())]
+    pub admin_cors_allowed_origins: std::vec::Vec<String>,
+
+    /// Methods browsers may use on the admin routes, or `*` for any.
+    #[clap(long = "admin-cors-allowed-method", env = "ADMIN_CORS_ALLOWED_METHODS", default_value = "GET,POST,PUT,DELETE", value_parser = value_parser_parse_cors_methods())]
+    pub admin_cors_allowed_methods: std::vec::Vec<String>,
+
+    /// Request headers browsers may send to the admin routes, or `*` for any.
+    #[clap(long = "admin-cors-allowed-header", env = "ADMIN_CORS_ALLOWED_HEADERS", default_value = "*", value_parser = value_parser_parse_cors_headers())]
+    pub admin_cors_allowed_headers: std::vec::Vec<String>,
+
+    /// Whether browsers may send cookies along to the admin routes.
+    #[clap(long, default_value_t = false, action = ArgAction::Set, env = "ADMIN_CORS_ALLOW_CREDENTIALS")]
+    pub admin_cors_allow_credentials: bool,
+
+    /// How long browsers may cache the answer to a preflight request to the admin routes.
+    #[clap(long, value_parser = Timeframe::parse_str, default_value = "1h", env = "ADMIN_CORS_MAX_AGE")]
+    pub admin_cors_max_age: Timeframe,
+}
+
+/// Which browsers may call a group of routes from other origins.
+#[derive(Debug, Clone, Copy)]
+pub struct CorsPolicy<'a> {
+    pub allowed_origins: &'a [String],
+    pub allowed_methods: &'a [String],
+    pub allowed_headers: &'a [String],
+    pub allow_credentials: bool,
+    pub max_age: Timeframe,
+}
+
+impl CorsConfig {
+    #[must_use]
+    pub fn public(&self) -> CorsPolicy<'_> {
+        CorsPolicy {
+            allowed_origins: &self.cors_allowed_origins,
+            allowed_methods: &self.cors_allowed_methods,
+            allowed_headers: &self.cors_allowed_headers,
+            allow_credentials: self.cors_allow_credentials,
+            max_age: self.cors_max_age,
+        }
+    }
+
+    #[must_use]
+    pub fn admin(&self) -> CorsPolicy<'_> {
+        CorsPolicy {
+            allowed_origins: &self.admin_cors_allowed_origins,
+            allowed_methods: &self.admin_cors_allowed_methods,
+            allowed_headers: &self.admin_cors_allowed_headers,
+            allow_credentials: self.admin_cors_allow_credentials,
+            max_age: self.admin_cors_max_age,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct BatchConfig {
+    /// How many files of a batch to send to the endpoints at the same time.
+    #[clap(long, default_value = "4", env = "BATCH_MAX_CONCURRENT")]
+    pub batch_max_concurrent: usize,
+
+    /// The most files a batch may contain, including the ones in uploaded archives.
+    #[clap(long, default_value = "1000", env = "BATCH_MAX_FILES")]
+    pub batch_max_files: usize,
+
+    /// The most megabytes a single file in an uploaded archive may extract to.
+    #[clap(long, default_value = "100", env = "BATCH_MAX_EXTRACTED_FILE_MB")]
+    pub batch_max_extracted_file_mb: u64,
+
+    /// The most megabytes all files of an uploaded archive together may extract to.
+    #[clap(long, default_value = "512", env = "BATCH_MAX_EXTRACTED_MB")]
+    pub batch_max_extracted_mb: u64,
+
+    /// How long a batch request may take, instead of the usual request timeout.
+    #[clap(long, value_parser = Timeframe::parse_str, default_value = "30min", env = "BATCH_TIMEOUT")]
+    pub batch_timeout: Timeframe,
+}
+
+#[derive(Debug, Clone, Args)]
+pub struct JobsConfig {
+    /// Where to store asynchronous OCR jobs: their uploads until they have been processed,
+    /// and their state and results so they survive restarts.
+    ///
+    /// Defaults to an `ocr-api-jobs` directory in the system temporary directory,
+    /// which may not be kept across deploys. For jobs to survive deploys,
+    /// point it to a persistent volume. Files the gateway didn't write there are left alone.
+    #[clap(long, env = "JOBS_SPOOL_DIR")]
+    pub jobs_spool_dir: Option<std::path::PathBuf>,
+
+    /// How many asynchronous OCR jobs to send to the endpoints at the same time.
+    #[clap(long, default_value = "4", env = "JOBS_MAX_CONCURRENT")]
+    pub jobs_max_concurrent: usize,
+
+    /// How many asynchronous OCR jobs may be waiting to run before new ones are refused.
+    #[clap(long, default_value = "1000", env = "JOBS_MAX_QUEUED")]
+    pub jobs_max_queued: usize,
+
+    /// How many times to start a job before giving up on it.
+    ///
+    /// Jobs that were running when the gateway stopped are started again on startup,
+    /// so this keeps a job that brings the gateway down from doing so forever.
+    #[clap(long, default_value = "3", env = "JOBS_MAX_ATTEMPTS")]
+    pub jobs_max_attempts: u32,
+
+    /// How long to keep the results of finished jobs before they are removed.
+    #[clap(long, value_parser = Timeframe::parse_str, default_value = "1d", env = "JOBS_RESULT_TTL")]
+    pub jobs_result_ttl: Timeframe,
+
+    /// How many times to try delivering the result of a job to its callback URL.
+    #[clap(long, default_value = "5", env = "JOBS_CALLBACK_MAX_ATTEMPTS")]
+    pub jobs_callback_max_attempts: usize,
+
+    /// How long to wait before retrying a failed callback delivery.
+    ///
+    /// Doubles with every failed attempt, up to `jobs_callback_max_backoff`.
+    #[clap(long, value_parser = Timeframe::parse_str, default_value = "5s", env = "JOBS_CALLBACK_BACKOFF")]
+    pub jobs_callback_backoff: Timeframe,
+
+    /// The longest to wait between callback delivery attempts.
+    #[clap(long, value_parser = Timeframe::parse_str, default_value = "5min", env = "JOBS_CALLBACK_MAX_BACKOFF")]
+    pub jobs_callback_max_backoff: Timeframe,
+
+    /// How long a callback delivery may take, including connecting.
+    #[clap(long, value_parser = Timeframe::parse_str, default_value = "30s", env = "JOBS_CALLBACK_TIMEOUT")]
+    pub jobs_callback_timeout: Timeframe,
+
+    /// Hosts callbacks may be delivered to even though they are on an internal network.
+    ///
+    /// Callback URLs that point to loopback, link-local or private addresses are refused otherwise,
+    /// so clients can't make the gateway send requests into its own network.
+    /// Comma- or space-separated list of host names or IP addresses.
+    #[clap(long = "jobs-callback-allowed-host", env = "JOBS_CALLBACK_ALLOWED_HOSTS", default_value = "", value_parser = value_parser_parse_names())]
+    pub jobs_callback_allowed_hosts: std::vec::Vec<String>,
+}
+
+impl JobsConfig {
+    #[must_use]
+    pub fn spool_dir(&self) -> std::path::PathBuf {
+        self.jobs_spool_dir
+            .clone()
+            .unwrap_or_else(|| std::env::temp_dir().join("ocr-api-jobs"))
+    }
+}
+
+impl HedgingConfig {
+    #[must_use]
+    pub fn is_enabled_for(&self, handler: &str) -> bool {
+        self.hedge_handlers.iter().any(|x| x == "*" || x == handler)
+    }
 }
 
 impl Config {
@@ -57,11 +757,42 @@ impl Config {
     pub fn global() -> &'static Self {
         &CONFIG
     }
+
+    /// All API keys, both the gateway's own and the tenants', as `(tenant, name, key)`.
+    pub fn api_keys(&self) -> impl Iterator<Item = (Option<&Tenant>, &str, &str)> {
+        self.auth.keys().map(|(name, key)| (None, name, key)).chain(
+            self.tenants
+                .keys()
+                .map(|(tenant, name, key)| (Some(tenant), name, key)),
+        )
+    }
+
+    /// The configured handlers to fall back to, in order, if the given one can't be used.
+    #[must_use]
+    pub fn handler_fallbacks(&self, handler: &str) -> Vec<String> {
+        self.handler_fallbacks
+            .iter()
+            .find_map(|chain| {
+                let pos = chain.handlers.iter().position(|x| x == handler)?;
+
+                Some(chain.handlers[pos + 1..].to_vec())
+            })
+            .unwrap_or_default()
+    }
+
+    #[must_use]
+    pub fn virtual_handler(&self, name: &str) -> Option<&VirtualHandler> {
+        self.virtual_handlers.iter().find(|x| x.name == name)
+    }
 }
 
 impl Config {
     fn new() -> Self {
+        #[cfg(not(test))]
         let mut c = Self::parse();
+        // Tests get the defaults instead of parsing the test harness's arguments
+        #[cfg(test)]
+        let mut c = Self::parse_from(["ocr-api", "--base-api-url", "http://127.0.0.1:8000"]);
 
         if c.auth.api_auth_key.is_empty() {
             c.auth.api_auth_key = rand::thread_rng()
@@ -71,6 +802,42 @@ impl Config {
                 .collect::<String>();
         }
 
+        if let Some((_, name, _)) = c
+            .tenants
+            .keys()
+            .find(|(_, name, _)| c.auth.keys().any(|(x, _)| x == *name))
+        {
+            Self::command()
+                .error(
+                    ErrorKind::ArgumentConflict,
+                    format!("Tenant API key name {name:?} is already used by a gateway API key"),
+                )
+                .exit();
+        }
+
+        for (policy, arg) in [
+            (c.cors.public(), "--cors-allowed-origin"),
+            (c.cors.admin(), "--admin-cors-allowed-origin"),
+        ] {
+            // Browsers refuse credentials for wildcard origins
+            if policy.allow_credentials && policy.allowed_origins.iter().any(|x| x == "*") {
+                Self::command()
+                    .error(
+                        ErrorKind::ArgumentConflict,
+                        format!("CORS credentials can't be allowed for any origin, list the origins in `{arg}` instead"),
+                    )
+                    .exit();
+            }
+        }
+
+        if c.gateway_id.is_empty() {
+            c.gateway_id = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(16)
+                .map(|x| x as char)
+                .collect::<String>();
+        }
+
         c
     }
 }
@@ -107,16 +874,418 @@ fn value_parser_parse_absolute_urls() -> impl clap::builder::TypedValueParser {
     }
 }
 
+fn value_parser_parse_urls() -> impl clap::builder::TypedValueParser {
+    move |s: &str| {
+        s.split([',', ' '])
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(|x| Url::parse(x).map_err(|e| format!("Invalid URL {x:?}: {e}")))
+            .collect::<Result<Vec<_>, _>>()
+    }
+}
+
+pub fn parse_timeframes(s: &str) -> Result<Vec<Timeframe>, String> {
+    s.split([',', ' '])
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(|x| Timeframe::parse_str(x).map_err(|e| e.to_string()))
+        .collect()
+}
+
+fn value_parser_parse_timeframes() -> impl clap::builder::TypedValueParser {
+    move |s: &str| parse_timeframes(s)
+}
+
+fn value_parser_parse_jitter() -> impl clap::builder::TypedValueParser {
+    move |s: &str| {
+        let jitter = s
+            .trim()
+            .parse::<f64>()
+            .map_err(|e| format!("Invalid jitter {s:?}: {e}"))?;
+
+        if !(0.0..1.0).contains(&jitter) {
+            return Err(format!(
+                "Jitter must be at least 0 and less than 1: {jitter}"
+            ));
+        }
+
+        Ok(jitter)
+    }
+}
+
+fn value_parser_parse_gateway_id() -> impl clap::builder::TypedValueParser {
+    move |s: &str| {
+        let id = s.trim();
+
+        // Sent in a comma separated header, so commas and spaces would break loop detection
+        if let Some(x) = id
+            .chars()
+            .find(|x| !x.is_ascii_alphanumeric() && !matches!(x, '.' | '_' | '-'))
+        {
+            return Err(format!(
+                "Invalid gateway ID {s:?}, {x:?} is not allowed, only letters, digits, `.`, `_` and `-` are"
+            ));
+        }
+
+        Ok(id.to_string())
+    }
+}
+
+fn value_parser_parse_ips() -> impl clap::builder::TypedValueParser {
+    move |s: &str| {
+        s.split([',', ' '])
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(|x| {
+                x.parse::<std::net::IpAddr>()
+                    .map_err(|e| format!("Invalid IP address {x:?}: {e}"))
+            })
+            .collect::<Result<Vec<_>, _>>()
+    }
+}
+
+fn value_parser_parse_names() -> impl clap::builder::TypedValueParser {
+    move |s: &str| -> Result<Vec<String>, String> {
+        Ok(s.split([',', ' '])
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(ToString::to_string)
+            .collect())
+    }
+}
+
+fn value_parser_parse_fallback_chains() -> impl clap::builder::TypedValueParser {
+    move |s: &str| {
+        s.split(',')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(|x| {
+                let handlers = x.split("->").map(str::trim).collect::<Vec<_>>();
+
+                if handlers.len() < 2 || handlers.iter().any(|x| x.is_empty()) {
+                    return Err(format!(
+                        "Fallback chain must be in the form `handler -> handler`: {x:?}"
+                    ));
+                }
+
+                Ok(FallbackChain {
+                    handlers: handlers.into_iter().map(ToString::to_string).collect(),
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()
+    }
+}
+
+fn value_parser_parse_virtual_handlers() -> impl clap::builder::TypedValueParser {
+    move |s: &str| {
+        // Only commas separate entries, so spaces around `=`, `:` and `|` don't split one up
+        s.split(',')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(|x| {
+                let (name, handlers) = x.split_once('=').ok_or_else(|| {
+                    format!("Virtual handler must be in the form `name=[strategy:]handler|handler`: {x:?}")
+                })?;
+
+                let name = name.trim();
+
+                let (strategy, handlers) = match handlers.split_once(':') {
+                    Some((strategy, handlers)) => (strategy.trim().parse()?, handlers),
+                    None => (VirtualHandlerStrategy::First, handlers),
+                };
+
+                let handlers = handlers
+                    .split('|')
+                    .map(str::trim)
+                    .filter(|x| !x.is_empty())
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>();
+
+                if name.is_empty() || handlers.is_empty() {
+                    return Err(format!(
+                        "Virtual handler must have a name and at least one handler: {x:?}"
+                    ));
+                }
+
+                Ok(VirtualHandler {
+                    name: name.to_string(),
+                    strategy,
+                    handlers,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()
+    }
+}
+
+fn value_parser_parse_key_priorities() -> impl clap::builder::TypedValueParser {
+    move |s: &str| {
+        s.split([',', ' '])
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(|x| {
+                let (key_name, priority) = x.split_once('=').ok_or_else(|| {
+                    format!("API key priority must be in the form `key_name=priority`: {x:?}")
+                })?;
+
+                let key_name = key_name.trim();
+                if key_name.is_empty() {
+                    return Err("API key name must not be empty".to_string());
+                }
+
+                Ok(KeyPriority {
+                    key_name: key_name.to_string(),
+                    priority: priority.parse()?,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()
+    }
+}
+
+fn parse_auth_key(s: &str) -> Result<String, String> {
+    if s.is_empty() {
+        return Ok(s.to_string());
+    }
+
+    if s.len() < 16 {
+        return Err("API auth key must be at least 16 characters long".to_string());
+    }
+
+    Ok(s.to_string())
+}
+
 fn value_parser_parse_auth_key() -> impl clap::builder::TypedValueParser {
+    move |s: &str| parse_auth_key(s)
+}
+
+fn value_parser_parse_named_auth_keys() -> impl clap::builder::TypedValueParser {
+    move |s: &str| {
+        s.split([',', ' '])
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(|x| {
+                let (name, key) = x
+                    .split_once('=')
+                    .ok_or_else(|| format!("API key must be in the form `name=key`: {x:?}"))?;
+
+                let name = name.trim();
+                if name.is_empty() {
+                    return Err("API key name must not be empty".to_string());
+                }
+
+                let key = parse_auth_key(key.trim())?;
+                if key.is_empty() {
+                    return Err(format!("API key {name:?} must not be empty"));
+                }
+
+                Ok(NamedAuthKey {
+                    name: name.to_string(),
+                    key,
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()
+    }
+}
+
+fn deserialize_absolute_urls<'de, D>(deserializer: D) -> Result<Vec<Url>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    <Vec<String> as serde::Deserialize>::deserialize(deserializer)?
+        .iter()
+        .map(|x| parse_absolute_url(x).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+fn deserialize_timeframe<'de, D>(deserializer: D) -> Result<Timeframe, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    Timeframe::parse_str(&<String as serde::Deserialize>::deserialize(deserializer)?)
+        .map_err(serde::de::Error::custom)
+}
+
+pub(crate) fn serialize_timeframe<S>(
+    timeframe: &Timeframe,
+    serializer: S,
+) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.collect_str(timeframe)
+}
+
+fn parse_tenants(s: &str) -> Result<Vec<Tenant>, String> {
+    let tenants = serde_json::from_str::<Vec<Tenant>>(s).map_err(|e| e.to_string())?;
+
+    let mut key_names = Vec::<&str>::new();
+    for (i, tenant) in tenants.iter().enumerate() {
+        // Tenant names are sent along in a header
+        if tenant.name.is_empty()
+            || !tenant
+                .name
+                .bytes()
+                .all(|x| x.is_ascii_alphanumeric() || x == b'-' || x == b'_')
+        {
+            return Err(format!(
+                "Tenant name must be made of letters, digits, `-` and `_`: {:?}",
+                tenant.name
+            ));
+        }
+
+        if tenants[..i].iter().any(|x| x.name == tenant.name) {
+            return Err(format!("Tenant {:?} is listed twice", tenant.name));
+        }
+
+        for (name, key) in &tenant.api_keys {
+            if name.is_empty() {
+                return Err("API key name must not be empty".to_string());
+            }
+
+            if parse_auth_key(key)?.is_empty() {
+                return Err(format!("API key {name:?} must not be empty"));
+            }
+
+            if key_names.contains(&name.as_str()) {
+                return Err(format!(
+                    "API key name {name:?} is used by more than one tenant"
+                ));
+            }
+            key_names.push(name);
+        }
+
+        if Duration::from(tenant.quota.period).is_zero() {
+            return Err(format!(
+                "Quota period of tenant {:?} must not be zero",
+                tenant.name
+            ));
+        }
+    }
+
+    Ok(tenants)
+}
+
+fn value_parser_parse_tenants_file() -> impl clap::builder::TypedValueParser {
     move |s: &str| {
         if s.is_empty() {
-            return Ok(s.to_string());
+            return Ok(Vec::new());
+        }
+
+        let data = std::fs::read_to_string(s).map_err(|e| format!("Failed to read {s:?}: {e}"))?;
+
+        parse_tenants(&data).map_err(|e| format!("Invalid tenants file {s:?}: {e}"))
+    }
+}
+
+/// Comma- or space-separated values, each either `*` or normalized by `parse`.
+fn parse_cors_values<F>(s: &str, parse: F) -> Result<Vec<String>, String>
+where
+    F: Fn(&str) -> Result<String, String>,
+{
+    s.split([',', ' '])
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(|x| {
+            if x == "*" {
+                Ok(x.to_string())
+            } else {
+                parse(x)
+            }
+        })
+        .collect()
+}
+
+fn value_parser_parse_cors_origins() -> impl clap::builder::TypedValueParser {
+    move |s: &str| {
+        parse_cors_values(s, |x| {
+            let origin = Url::parse(x)
+                .map_err(|e| format!("Invalid CORS origin {x:?}: {e}"))?
+                .origin();
+
+            if !origin.is_tuple() {
+                return Err(format!("Invalid CORS origin {x:?}"));
+            }
+
+            Ok(origin.ascii_serialization())
+        })
+    }
+}
+
+fn value_parser_parse_cors_methods() -> impl clap::builder::TypedValueParser {
+    move |s: &str| {
+        parse_cors_values(s, |x| {
+            axum::http::Method::from_bytes(x.to_ascii_uppercase().as_bytes())
+                .map(|x| x.to_string())
+                .map_err(|e| format!("Invalid HTTP method {x:?}: {e}"))
+        })
+    }
+}
+
+fn value_parser_parse_cors_headers() -> impl clap::builder::TypedValueParser {
+    move |s: &str| {
+        parse_cors_values(s, |x| {
+            axum::http::HeaderName::from_bytes(x.as_bytes())
+                .map(|x| x.to_string())
+                .map_err(|e| format!("Invalid header name {x:?}: {e}"))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn priority_config(priority_reserved_percent: u8) -> PriorityConfig {
+        PriorityConfig {
+            default_priority: Priority::Normal,
+            api_key_priorities: Vec::new(),
+            priority_reserved_percent,
         }
+    }
 
-        if s.len() < 16 {
-            return Err("API auth key must be at least 16 characters long".to_string());
+    #[test]
+    fn reserves_capacity_for_high_priority() {
+        let config = priority_config(20);
+
+        assert_eq!(config.reserved(10), 2);
+        // Rounded up
+        assert_eq!(config.reserved(3), 1);
+
+        assert_eq!(config.available(10, Priority::High), 10);
+        assert_eq!(config.available(10, Priority::Normal), 8);
+        assert_eq!(config.available(10, Priority::Low), 8);
+    }
+
+    #[test]
+    fn reserves_nothing_when_disabled() {
+        let config = priority_config(0);
+
+        assert_eq!(config.reserved(10), 0);
+        assert_eq!(config.available(10, Priority::Normal), 10);
+    }
+
+    #[test]
+    fn leaves_one_for_other_priorities() {
+        let config = priority_config(100);
+
+        assert_eq!(config.reserved(10), 9);
+        assert_eq!(config.available(10, Priority::Normal), 1);
+        assert_eq!(config.available(10, Priority::High), 10);
+
+        // Endpoints taking one request at a time reserve nothing
+        for percent in [0, 20, 100] {
+            let config = priority_config(percent);
+
+            assert_eq!(config.reserved(1), 0, "{percent}%");
+            assert_eq!(config.available(1, Priority::Normal), 1, "{percent}%");
         }
+    }
+
+    #[test]
+    fn reserves_nothing_without_capacity() {
+        let config = priority_config(20);
 
-        Ok(s.to_string())
+        assert_eq!(config.reserved(0), 0);
+        assert_eq!(config.available(0, Priority::Normal), 0);
+        assert_eq!(config.available(0, Priority::High), 0);
     }
 }
@@ -1,38 +1,60 @@
 use std::{
+    pin::Pin,
     string::ToString,
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
+    time::{Duration, Instant},
 };
 
+use axum::http::HeaderMap;
 use chrono::{prelude::*, DateTime};
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 use serde::{Deserialize, Serialize};
-use tokio::net::TcpStream;
+use tokio::{net::TcpStream, sync::futures::Notified};
 use tracing::{debug, trace};
 use url::Url;
 
-use crate::helpers::id::time_rand_id;
+use super::{
+    event::EndpointEventKind,
+    history::{EndpointCheck, EndpointHistory},
+    schedule::CheckSchedule,
+};
+use crate::{
+    config::{Config, Priority},
+    helpers::{
+        id::time_rand_id,
+        in_flight::{InFlight, InFlightGuard},
+    },
+    hops,
+};
 
 #[derive(Debug, Clone, Serialize)]
 pub struct Endpoint {
     pub id: EndpointId,
     pub url: Url,
+    /// Shadow endpoints only get copies of live traffic and their responses are only used for comparison.
+    pub shadow: bool,
+    /// How many requests the endpoint can take at the same time, overriding what it advertises.
+    pub max_concurrency: Option<usize>,
+    /// The tenant whose requests are the only ones routed to the endpoint, shared by all if unset.
+    pub tenant: Option<String>,
     #[serde(serialize_with = "serialize_arc_rwlock_endpoint_status")]
     pub status: Arc<RwLock<EndpointStatus>>,
     #[serde(serialize_with = "serialize_arc_atomic_bool")]
     disabled: Arc<AtomicBool>,
+    #[serde(serialize_with = "serialize_arc_atomic_bool")]
+    draining: Arc<AtomicBool>,
+    #[serde(serialize_with = "serialize_arc_in_flight")]
+    in_flight: Arc<InFlight>,
+    #[serde(skip)]
+    pub history: Arc<Mutex<EndpointHistory>>,
+    #[serde(skip)]
+    pub schedule: Arc<Mutex<CheckSchedule>>,
 }
 
 impl Endpoint {
-    pub fn supports_handler(&self, handler: &str) -> bool {
-        self.status
-            .read()
-            .info()
-            .map_or(false, |info| info.supports_handler(handler))
-    }
-
     pub fn handler_url(&self, handler: &str) -> Option<Url> {
         let mut handler_path = self.status.read().info()?.handler_path(handler);
         if handler_path.starts_with('/') {
@@ -42,36 +64,120 @@ impl Endpoint {
         self.url.join(&handler_path).ok()
     }
 
+    /// The ID of the gateway behind this endpoint, if it is one.
+    pub fn gateway_id(&self) -> Option<String> {
+        self.status.read().info()?.gateway_id.clone()
+    }
+
     pub fn disabled(&self) -> bool {
         self.disabled.load(Ordering::Relaxed)
     }
 
-    pub fn set_disabled(&self, disabled: bool) {
-        self.disabled.store(disabled, Ordering::Relaxed);
+    /// Returns whether the value changed.
+    pub fn set_disabled(&self, disabled: bool) -> bool {
+        self.disabled.swap(disabled, Ordering::Relaxed) != disabled
+    }
+
+    /// A draining endpoint doesn't get new requests, but the ones in flight are allowed to finish.
+    pub fn draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Returns whether the value changed.
+    pub fn set_draining(&self, draining: bool) -> bool {
+        self.draining.swap(draining, Ordering::Relaxed) != draining
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.count()
+    }
+
+    /// How many requests the endpoint can take at the same time, if there is a limit.
+    ///
+    /// A declared limit wins over one the endpoint advertises, which wins over the configured default.
+    pub fn concurrency_limit(&self) -> Option<usize> {
+        self.max_concurrency
+            .or_else(|| self.status.read().info()?.max_concurrency)
+            .or_else(|| Some(Config::global().endpoint_max_concurrency))
+            .filter(|x| *x > 0)
+    }
+
+    /// Track a request to this endpoint if it has free capacity for the priority.
+    ///
+    /// Part of the capacity is reserved for high priority requests.
+    pub fn try_track_request(&self, priority: Priority) -> Option<InFlightGuard> {
+        self.concurrency_limit().map_or_else(
+            || Some(self.in_flight.track()),
+            |limit| {
+                let limit = Config::global().priority.available(limit, priority);
+                self.in_flight.try_track(limit)
+            },
+        )
+    }
+
+    /// Resolves the next time a request to this endpoint finishes.
+    pub fn request_finished(&self) -> Pin<Box<Notified<'_>>> {
+        self.in_flight.released()
+    }
+
+    /// Wait until there are no requests in flight to this endpoint.
+    ///
+    /// Returns whether the endpoint became idle before the timeout.
+    pub async fn wait_until_idle(&self, timeout: Duration) -> bool {
+        tokio::time::timeout(timeout, self.in_flight.wait_idle())
+            .await
+            .is_ok()
     }
 }
 
 impl Endpoint {
+    /// Checks the endpoint and updates its status.
+    ///
+    /// Returns the status change if the endpoint transitioned into a different state.
     #[tracing::instrument(skip_all, fields(url = %self.url.as_str()))]
-    pub async fn check_and_update(&self) {
+    pub async fn check_and_update(&self) -> Option<EndpointEventKind> {
         if self.disabled() {
-            return;
+            return None;
         }
 
         trace!("Checking and updating endpoint");
-        match self.check_connectivity().await {
+        let started_at = Instant::now();
+        let new_status = match self.check_connectivity().await {
             Ok(()) => {
                 trace!("Endpoint is up, updating metadata");
-                self.update_metadata().await;
+                self.get_metadata().await
             }
             Err(e) => {
                 trace!(error = ?e, "Endpoint is down, updating metadata");
-                {
-                    let mut status = self.status.write();
-                    *status = EndpointStatus::down(format!("Couldn't connect to endpoint: {}", e));
-                }
+                EndpointStatus::down(format!("Couldn't connect to endpoint: {}", e))
             }
+        };
+
+        self.history
+            .lock()
+            .push(EndpointCheck::new(&new_status, started_at.elapsed()));
+
+        self.set_status(new_status)
+    }
+
+    fn set_status(&self, new_status: EndpointStatus) -> Option<EndpointEventKind> {
+        let from = {
+            let mut status = self.status.write();
+            std::mem::replace(&mut *status, new_status.clone()).state()
+        };
+        let to = new_status.state();
+
+        if from == to {
+            return None;
         }
+
+        debug!(?from, ?to, "Endpoint state changed");
+
+        Some(EndpointEventKind::StatusChanged {
+            from,
+            to,
+            status: new_status,
+        })
     }
 
     #[tracing::instrument(skip_all)]
@@ -104,23 +210,17 @@ impl Endpoint {
         }
     }
 
-    async fn update_metadata(&self) -> &Self {
-        let new_status = self.get_metadata().await;
-
-        {
-            let mut status = self.status.write();
-            *status = new_status;
-        }
-
-        self
-    }
-
     #[tracing::instrument(skip_all)]
     async fn get_metadata(&self) -> EndpointStatus {
         debug!("Getting endpoint metadata");
 
         let client = reqwest::Client::new();
-        let response = client.get(self.url.as_str()).send().await;
+        // So chained gateways leave out the handlers they can only serve through us
+        let response = client
+            .get(self.url.as_str())
+            .header(hops::HOPS_HEADER, hops::forwarded(&HeaderMap::new()))
+            .send()
+            .await;
         trace!(response = ?response, "Got response from endpoint");
         let response = match response {
             Ok(resp) => resp,
@@ -147,10 +247,43 @@ impl Endpoint {
         Self {
             id: EndpointId::default(),
             url,
+            shadow: false,
+            max_concurrency: None,
+            tenant: None,
             status: Arc::new(RwLock::new(EndpointStatus::unknown())),
             disabled: Arc::new(AtomicBool::new(false)),
+            draining: Arc::new(AtomicBool::new(false)),
+            in_flight: Arc::new(InFlight::default()),
+            history: Arc::new(Mutex::new(EndpointHistory::with_capacity(
+                Config::global().endpoint_history_size,
+            ))),
+            schedule: Arc::new(Mutex::new(CheckSchedule::new())),
         }
     }
+
+    pub fn shadow(url: Url) -> Self {
+        Self {
+            shadow: true,
+            ..Self::new(url)
+        }
+    }
+
+    #[must_use]
+    pub const fn with_max_concurrency(mut self, max_concurrency: Option<usize>) -> Self {
+        self.max_concurrency = max_concurrency;
+        self
+    }
+
+    #[must_use]
+    pub fn with_tenant(mut self, tenant: Option<String>) -> Self {
+        self.tenant = tenant;
+        self
+    }
+
+    /// Whether requests of the tenant, or of no tenant if unset, may be routed to the endpoint.
+    pub fn serves_tenant(&self, tenant: Option<&str>) -> bool {
+        self.tenant.is_none() || self.tenant.as_deref() == tenant
+    }
 }
 
 impl From<Url> for Endpoint {
@@ -159,17 +292,19 @@ impl From<Url> for Endpoint {
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct EndpointInfo {
     #[serde(alias = "handlers")]
     pub available_handlers: Vec<String>,
     pub handler_template: String,
+    /// Set when the endpoint is itself a gateway.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gateway_id: Option<String>,
+    /// How many requests the endpoint can take at the same time.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_concurrency: Option<usize>,
 }
 impl EndpointInfo {
-    pub fn supports_handler(&self, handler: &str) -> bool {
-        self.available_handlers.contains(&handler.to_string())
-    }
-
     pub fn handler_path(&self, handler: &str) -> String {
         self.handler_template.replace("{handler_name}", handler)
     }
@@ -196,6 +331,22 @@ impl EndpointStatus {
             _ => None,
         }
     }
+
+    pub const fn state(&self) -> EndpointState {
+        match self {
+            Self::Up { .. } => EndpointState::Up,
+            Self::Down { .. } => EndpointState::Down,
+            Self::Unknown => EndpointState::Unknown,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EndpointState {
+    Up,
+    Down,
+    Unknown,
 }
 #[allow(dead_code)]
 impl EndpointStatus {
@@ -283,6 +434,15 @@ impl Serialize for EndpointId {
     }
 }
 
+impl<'de> Deserialize<'de> for EndpointId {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer).map(Self)
+    }
+}
+
 fn serialize_arc_rwlock_endpoint_status<S>(
     status: &Arc<RwLock<EndpointStatus>>,
     serializer: S,
@@ -301,3 +461,10 @@ where
     let status = status.load(Ordering::Relaxed);
     status.serialize(serializer)
 }
+
+fn serialize_arc_in_flight<S>(in_flight: &Arc<InFlight>, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    in_flight.count().serialize(serializer)
+}
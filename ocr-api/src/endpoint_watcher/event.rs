@@ -0,0 +1,66 @@
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use url::Url;
+
+use super::{
+    endpoint::{EndpointId, EndpointState, EndpointStatus},
+    Endpoint,
+};
+
+/// Something that happened to one of the watched endpoints.
+#[derive(Debug, Clone, Serialize)]
+pub struct EndpointEvent {
+    pub at: DateTime<Utc>,
+    pub endpoint_id: EndpointId,
+    pub url: Url,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    #[serde(flatten)]
+    pub kind: EndpointEventKind,
+}
+
+impl EndpointEvent {
+    pub fn new(endpoint: &Endpoint, kind: EndpointEventKind) -> Self {
+        Self {
+            at: Utc::now(),
+            endpoint_id: endpoint.id.clone(),
+            url: endpoint.url.clone(),
+            tenant: endpoint.tenant.clone(),
+            kind,
+        }
+    }
+
+    pub const fn name(&self) -> &'static str {
+        self.kind.name()
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "snake_case", tag = "event")]
+pub enum EndpointEventKind {
+    StatusChanged {
+        from: EndpointState,
+        to: EndpointState,
+        status: EndpointStatus,
+    },
+    Added,
+    Removed,
+    Disabled,
+    Enabled,
+    DrainStarted,
+    DrainStopped,
+}
+
+impl EndpointEventKind {
+    pub const fn name(&self) -> &'static str {
+        match self {
+            Self::StatusChanged { .. } => "status_changed",
+            Self::Added => "added",
+            Self::Removed => "removed",
+            Self::Disabled => "disabled",
+            Self::Enabled => "enabled",
+            Self::DrainStarted => "drain_started",
+            Self::DrainStopped => "drain_stopped",
+        }
+    }
+}
@@ -0,0 +1,115 @@
+use std::{collections::VecDeque, time::Duration};
+
+use chrono::{DateTime, Utc};
+use ocr_common::timeframe::Timeframe;
+use serde::Serialize;
+
+use super::endpoint::{EndpointState, EndpointStatus};
+
+/// A single past check of an endpoint.
+#[derive(Debug, Clone, Serialize)]
+pub struct EndpointCheck {
+    pub at: DateTime<Utc>,
+    pub state: EndpointState,
+    pub latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl EndpointCheck {
+    pub fn new(status: &EndpointStatus, latency: Duration) -> Self {
+        Self {
+            at: Utc::now(),
+            state: status.state(),
+            latency_ms: latency.as_secs_f64() * 1000.0,
+            error: match status {
+                EndpointStatus::Down { error, .. } => Some(error.clone()),
+                _ => None,
+            },
+        }
+    }
+}
+
+/// Bounded ring of the most recent checks of an endpoint.
+#[derive(Debug)]
+pub struct EndpointHistory {
+    checks: VecDeque<EndpointCheck>,
+    capacity: usize,
+}
+
+impl EndpointHistory {
+    pub fn with_capacity(capacity: usize) -> Self {
+        Self {
+            checks: VecDeque::with_capacity(capacity),
+            capacity,
+        }
+    }
+
+    pub fn push(&mut self, check: EndpointCheck) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.checks.len() >= self.capacity {
+            self.checks.pop_front();
+        }
+
+        self.checks.push_back(check);
+    }
+
+    /// The most recent checks, newest first.
+    pub fn latest(&self, limit: usize) -> Vec<EndpointCheck> {
+        self.checks.iter().rev().take(limit).cloned().collect()
+    }
+
+    pub fn stats(&self, window: Timeframe) -> EndpointHistoryStats {
+        let since = chrono::Duration::from_std(window.into())
+            .ok()
+            .and_then(|window| Utc::now().checked_sub_signed(window))
+            .unwrap_or(DateTime::<Utc>::MIN_UTC);
+
+        let checks = self
+            .checks
+            .iter()
+            .filter(|check| check.at >= since)
+            .collect::<Vec<_>>();
+
+        let total = checks.len();
+        let up = checks
+            .iter()
+            .filter(|check| check.state == EndpointState::Up)
+            .count();
+        let flaps = checks
+            .windows(2)
+            .filter(|pair| pair[0].state != pair[1].state)
+            .count();
+
+        #[allow(clippy::cast_precision_loss)]
+        let (uptime_percent, mean_latency_ms) = if total == 0 {
+            (None, None)
+        } else {
+            (
+                Some(up as f64 / total as f64 * 100.0),
+                Some(checks.iter().map(|check| check.latency_ms).sum::<f64>() / total as f64),
+            )
+        };
+
+        EndpointHistoryStats {
+            window,
+            checks: total,
+            uptime_percent,
+            mean_latency_ms,
+            flaps,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct EndpointHistoryStats {
+    #[serde(serialize_with = "crate::config::serialize_timeframe")]
+    pub window: Timeframe,
+    pub checks: usize,
+    pub uptime_percent: Option<f64>,
+    pub mean_latency_ms: Option<f64>,
+    pub flaps: usize,
+}
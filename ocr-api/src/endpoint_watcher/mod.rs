@@ -1,4 +1,8 @@
 pub mod endpoint;
+pub mod event;
+pub mod history;
+pub mod routing;
+pub mod schedule;
 pub mod watcher;
 
 pub use endpoint::Endpoint;
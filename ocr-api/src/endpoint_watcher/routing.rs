@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+
+use url::Url;
+
+use super::Endpoint;
+
+/// An immutable snapshot of which endpoints can currently serve which handler.
+///
+/// Only contains endpoints that are up, not disabled and not draining.
+/// Shadow endpoints are kept separately, as they never serve clients directly.
+/// Tenants get their own view with their endpoints on top of the shared ones.
+/// Rebuilt by the [`EndpointWatcher`](super::EndpointWatcher) whenever that changes.
+#[derive(Debug, Default)]
+pub struct RoutingTable {
+    handlers: HashMap<String, Vec<Route>>,
+    tenant_handlers: HashMap<String, HashMap<String, Vec<Route>>>,
+    shadow_handlers: HashMap<String, Vec<Route>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Route {
+    pub endpoint: Endpoint,
+    pub handler_url: Url,
+}
+
+impl RoutingTable {
+    pub fn build(endpoints: &[Endpoint]) -> Self {
+        let mut handlers = HashMap::<String, Vec<Route>>::new();
+        let mut tenant_handlers = HashMap::<String, HashMap<String, Vec<Route>>>::new();
+        let mut shadow_handlers = HashMap::<String, Vec<Route>>::new();
+
+        for endpoint in endpoints.iter().filter(|e| !e.disabled() && !e.draining()) {
+            let available_handlers = match endpoint.status.read().info() {
+                Some(info) => info.available_handlers.clone(),
+                None => continue,
+            };
+
+            for handler in available_handlers {
+                let handler_url = match endpoint.handler_url(&handler) {
+                    Some(url) => url,
+                    None => continue,
+                };
+
+                let handlers = if endpoint.shadow {
+                    &mut shadow_handlers
+                } else if let Some(tenant) = &endpoint.tenant {
+                    tenant_handlers.entry(tenant.clone()).or_default()
+                } else {
+                    &mut handlers
+                };
+
+                handlers.entry(handler).or_default().push(Route {
+                    endpoint: endpoint.clone(),
+                    handler_url,
+                });
+            }
+        }
+
+        // Tenants can use the shared endpoints as well
+        for tenant_handlers in tenant_handlers.values_mut() {
+            for (handler, routes) in &handlers {
+                tenant_handlers
+                    .entry(handler.clone())
+                    .or_default()
+                    .extend(routes.iter().cloned());
+            }
+        }
+
+        Self {
+            handlers,
+            tenant_handlers,
+            shadow_handlers,
+        }
+    }
+
+    fn handler_routes(&self, tenant: Option<&str>) -> &HashMap<String, Vec<Route>> {
+        tenant
+            .and_then(|x| self.tenant_handlers.get(x))
+            .unwrap_or(&self.handlers)
+    }
+
+    /// Handlers that have at least one live endpoint for the tenant.
+    pub fn handlers(&self, tenant: Option<&str>) -> impl Iterator<Item = &str> {
+        self.handler_routes(tenant).keys().map(String::as_str)
+    }
+
+    /// The routes for requests of the tenant, or for requests without one.
+    pub fn routes(&self, handler: &str, tenant: Option<&str>) -> &[Route] {
+        self.handler_routes(tenant)
+            .get(handler)
+            .map_or(&[], Vec::as_slice)
+    }
+
+    pub fn shadow_routes(&self, handler: &str) -> &[Route] {
+        self.shadow_handlers.get(handler).map_or(&[], Vec::as_slice)
+    }
+}
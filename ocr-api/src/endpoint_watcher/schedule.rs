@@ -0,0 +1,100 @@
+use std::time::{Duration, Instant};
+
+use rand::Rng;
+
+use super::endpoint::EndpointState;
+use crate::config::Config;
+
+/// When an endpoint should be checked next.
+///
+/// Healthy endpoints are checked every `api_check_interval` (with some jitter).
+/// Right after a failure the endpoint is re-checked quickly a few times,
+/// after which the interval grows exponentially up to `api_check_max_backoff`.
+#[derive(Debug)]
+pub struct CheckSchedule {
+    next_check_at: Instant,
+    consecutive_failures: u32,
+    checking: bool,
+}
+
+impl CheckSchedule {
+    pub fn new() -> Self {
+        Self {
+            next_check_at: Instant::now(),
+            consecutive_failures: 0,
+            checking: false,
+        }
+    }
+
+    pub fn is_due(&self, now: Instant) -> bool {
+        !self.checking && self.next_check_at <= now
+    }
+
+    /// How long until the check is due, if it isn't already running.
+    pub fn due_in(&self, now: Instant) -> Option<Duration> {
+        if self.checking {
+            return None;
+        }
+
+        Some(self.next_check_at.saturating_duration_since(now))
+    }
+
+    /// Marks the check as running, returning whether it wasn't already.
+    pub const fn start(&mut self) -> bool {
+        let started = !self.checking;
+        self.checking = true;
+        started
+    }
+
+    /// Schedule the next check based on the outcome of the current one.
+    pub fn finish(&mut self, state: EndpointState) {
+        self.checking = false;
+
+        if state == EndpointState::Down {
+            self.consecutive_failures = self.consecutive_failures.saturating_add(1);
+        } else {
+            self.consecutive_failures = 0;
+        }
+
+        self.next_check_at = Instant::now() + self.next_interval();
+    }
+
+    fn next_interval(&self) -> Duration {
+        let config = Config::global();
+        let base: Duration = config.api_check_interval.into();
+
+        let interval = match self
+            .consecutive_failures
+            .checked_sub(config.api_check_fast_retries)
+        {
+            None if self.consecutive_failures == 0 => base,
+            None => config.api_check_retry_interval.into(),
+            Some(backoff_exponent) => {
+                let max_backoff = config.api_check_max_backoff.into();
+
+                2_u32
+                    .checked_pow(backoff_exponent)
+                    .and_then(|factor| base.checked_mul(factor))
+                    .map_or(max_backoff, |x| x.min(max_backoff))
+            }
+        };
+
+        with_jitter(interval, config.api_check_jitter)
+    }
+}
+
+impl Default for CheckSchedule {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn with_jitter(interval: Duration, jitter: f64) -> Duration {
+    // Config only allows jitter below 1, so intervals never shrink to nothing
+    let jitter = jitter.clamp(0.0, 1.0);
+    if jitter == 0.0 {
+        return interval;
+    }
+
+    interval.mul_f64(rand::thread_rng().gen_range((1.0 - jitter)..=(1.0 + jitter)))
+}
@@ -1,94 +1,278 @@
-use std::sync::Arc;
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use futures::{stream::FuturesUnordered, StreamExt};
+use arc_swap::ArcSwap;
 use once_cell::sync::OnceCell;
-use tokio::sync::RwLock;
-use tracing::{debug, info};
+use tokio::sync::{broadcast, Mutex, Notify};
+use tracing::{debug, info, trace};
 
-use super::{endpoint::EndpointId, Endpoint};
+use super::{
+    endpoint::EndpointId,
+    event::{EndpointEvent, EndpointEventKind},
+    routing::RoutingTable,
+    Endpoint,
+};
 use crate::config::Config;
 
 static ENDPOINT_WATCHER: OnceCell<Arc<EndpointWatcher>> = OnceCell::new();
 
+/// How many events can be buffered for slow subscribers before they start missing some.
+const EVENTS_CAPACITY: usize = 256;
+
 #[derive(Debug)]
 pub struct EndpointWatcher {
-    endpoints: Arc<RwLock<Vec<Endpoint>>>,
+    endpoints: ArcSwap<Vec<Endpoint>>,
+    /// Serializes changes to the endpoint list so concurrent admin requests don't clobber each other.
+    endpoints_write: Mutex<()>,
+    routing_table: ArcSwap<RoutingTable>,
+    /// Serializes routing table rebuilds, so an older build can't overwrite a newer one.
+    routing_table_write: parking_lot::Mutex<()>,
+    events: broadcast::Sender<EndpointEvent>,
+    wake: Notify,
 }
 
 impl EndpointWatcher {
-    #[allow(clippy::significant_drop_tightening)]
-    pub async fn check_and_update_endpoints(&self) {
-        debug!("Checking TCP connections to endpoints");
+    /// Starts checks for all endpoints that are due.
+    ///
+    /// Returns how long to wait until the next endpoint is due.
+    fn check_due_endpoints(self: &Arc<Self>) -> Duration {
+        let now = Instant::now();
+        let endpoints = self.endpoints();
 
-        let endpoints = self.endpoints.read().await;
-        let futs = endpoints.iter().map(|endpoint| async move {
-            endpoint.check_and_update().await;
-        });
+        for endpoint in endpoints.iter() {
+            // Checked and started in one go, so a manual check can't start in between
+            let mut schedule = endpoint.schedule.lock();
+            let due = schedule.is_due(now) && schedule.start();
+            drop(schedule);
+
+            if !due {
+                continue;
+            }
+            trace!(url = %endpoint.url, "Endpoint check is due");
+
+            tokio::spawn({
+                let watcher = self.clone();
+                let endpoint = endpoint.clone();
+                async move {
+                    watcher.run_check(&endpoint).await;
+                    watcher.wake.notify_one();
+                }
+            });
+        }
+
+        endpoints
+            .iter()
+            .filter_map(|e| e.schedule.lock().due_in(now))
+            .min()
+            .unwrap_or_else(|| Config::global().api_check_interval.into())
+    }
+
+    /// Checks the endpoint right away and schedules its next check.
+    ///
+    /// Returns `false` without checking if a check of the endpoint is already running.
+    pub async fn check_endpoint(&self, endpoint: &Endpoint) -> bool {
+        if !endpoint.schedule.lock().start() {
+            return false;
+        }
+
+        self.run_check(endpoint).await;
+        // The next check is now due later than the watcher may be waiting for
+        self.wake.notify_one();
+
+        true
+    }
+
+    /// Checks an endpoint whose check has been started on its schedule.
+    async fn run_check(&self, endpoint: &Endpoint) {
+        let info_before = endpoint.status.read().info().cloned();
+
+        match endpoint.check_and_update().await {
+            Some(change) => self.emit(EndpointEvent::new(endpoint, change)),
+            None => {
+                // The endpoint may have started or stopped advertising some handlers
+                if endpoint.status.read().info() != info_before.as_ref() {
+                    self.rebuild_routing_table();
+                }
+            }
+        }
+
+        let state = endpoint.status.read().state();
+        endpoint.schedule.lock().finish(state);
+    }
+}
+
+impl EndpointWatcher {
+    /// Subscribe to events about the watched endpoints.
+    pub fn subscribe(&self) -> broadcast::Receiver<EndpointEvent> {
+        self.events.subscribe()
+    }
+
+    /// The current routing table.
+    ///
+    /// Cheap to call, the table is only rebuilt when endpoints change.
+    pub fn routing_table(&self) -> Arc<RoutingTable> {
+        self.routing_table.load_full()
+    }
+
+    fn rebuild_routing_table(&self) {
+        trace!("Rebuilding routing table");
+
+        // Built under the lock, so the last table stored is built from the latest statuses
+        let write = self.routing_table_write.lock();
+        self.routing_table
+            .store(Arc::new(RoutingTable::build(&self.endpoints.load())));
+        drop(write);
+    }
+
+    /// Every event changes which endpoints are routable, so the routing table is rebuilt before it is sent.
+    fn emit(&self, event: EndpointEvent) {
+        self.rebuild_routing_table();
+
+        trace!(?event, "Emitting endpoint event");
 
-        futs.collect::<FuturesUnordered<_>>()
-            .collect::<Vec<_>>()
-            .await;
+        // Sending only fails if there are no subscribers, which is fine
+        let _ = self.events.send(event);
     }
 }
 
 #[allow(dead_code)]
 impl EndpointWatcher {
-    pub async fn endpoints(&self) -> Vec<Endpoint> {
-        self.endpoints.read().await.clone()
+    pub fn endpoints(&self) -> Arc<Vec<Endpoint>> {
+        self.endpoints.load_full()
     }
 
-    pub async fn endpoint<T>(&self, id: T) -> Option<Endpoint>
+    pub fn endpoint<T>(&self, id: T) -> Option<Endpoint>
     where
-        T: Into<EndpointId> + Send + Sync,
+        T: Into<EndpointId>,
     {
         let id = id.into();
 
-        self.endpoints
-            .read()
-            .await
-            .iter()
-            .find(|e| e.id == id)
-            .cloned()
+        self.endpoints.load().iter().find(|e| e.id == id).cloned()
     }
 
-    pub async fn endpoints_supporting_handler(&self, handler: &str) -> Vec<Endpoint> {
-        self.endpoints()
-            .await
-            .into_iter()
-            .filter(|endpoint| !endpoint.disabled() && endpoint.supports_handler(handler))
-            .collect()
+    /// Live endpoints that can serve the handler for the tenant, which may be a virtual handler.
+    pub fn endpoints_supporting_handler(
+        &self,
+        handler: &str,
+        tenant: Option<&str>,
+    ) -> Vec<Endpoint> {
+        let routing_table = self.routing_table.load();
+
+        let handlers = Config::global()
+            .virtual_handler(handler)
+            .map_or_else(|| vec![handler.to_string()], |x| x.handlers.clone());
+
+        let mut endpoints = Vec::<Endpoint>::new();
+        for route in handlers
+            .iter()
+            .flat_map(|x| routing_table.routes(x, tenant))
+        {
+            if !endpoints.iter().any(|x| x.id == route.endpoint.id) {
+                endpoints.push(route.endpoint.clone());
+            }
+        }
+
+        endpoints
     }
 
-    pub async fn add_endpoint<T>(&self, endpoint: T) -> bool
+    /// Adds a new endpoint, returning it if no endpoint with the same URL existed.
+    pub async fn add_endpoint<T>(&self, endpoint: T) -> Option<Endpoint>
     where
         T: Into<Endpoint> + Send + Sync,
     {
         let endpoint = endpoint.into();
 
-        let exists = self
-            .endpoints
-            .read()
-            .await
-            .iter()
-            .any(|e| e.url == endpoint.url);
+        if self.endpoints.load().iter().any(|e| e.url == endpoint.url) {
+            return None;
+        }
 
-        if exists {
-            return false;
+        // Checked before taking the lock, so other changes don't wait on the endpoint
+        endpoint.schedule.lock().start();
+        let change = endpoint.check_and_update().await;
+        endpoint
+            .schedule
+            .lock()
+            .finish(endpoint.status.read().state());
+
+        let _write = self.endpoints_write.lock().await;
+
+        // It may have been added while it was being checked
+        if self.endpoints.load().iter().any(|e| e.url == endpoint.url) {
+            return None;
         }
 
-        endpoint.check_and_update().await;
-        self.endpoints.write().await.push(endpoint);
+        self.endpoints.rcu(|endpoints| {
+            let mut endpoints = Vec::clone(endpoints);
+            endpoints.push(endpoint.clone());
+            endpoints
+        });
 
-        true
+        self.emit(EndpointEvent::new(&endpoint, EndpointEventKind::Added));
+        if let Some(change) = change {
+            self.emit(EndpointEvent::new(&endpoint, change));
+        }
+        self.wake.notify_one();
+
+        Some(endpoint)
     }
 
-    pub async fn remove_endpoint<T>(&self, endpoint_id: T)
+    /// Removes an endpoint, returning it if it existed.
+    pub async fn remove_endpoint<T>(&self, endpoint_id: T) -> Option<Endpoint>
     where
         T: Into<EndpointId> + Send + Sync,
     {
         let id_to_delete = endpoint_id.into();
-        let mut endpoints = self.endpoints.write().await;
-        endpoints.retain(|endpoint| endpoint.id != id_to_delete);
+
+        let _write = self.endpoints_write.lock().await;
+
+        let removed = self
+            .endpoints
+            .load()
+            .iter()
+            .find(|endpoint| endpoint.id == id_to_delete)
+            .cloned()?;
+
+        self.endpoints.rcu(|endpoints| {
+            endpoints
+                .iter()
+                .filter(|endpoint| endpoint.id != id_to_delete)
+                .cloned()
+                .collect::<Vec<_>>()
+        });
+
+        self.emit(EndpointEvent::new(&removed, EndpointEventKind::Removed));
+
+        Some(removed)
+    }
+
+    pub fn set_endpoint_disabled(&self, endpoint: &Endpoint, disabled: bool) {
+        if !endpoint.set_disabled(disabled) {
+            return;
+        }
+
+        let kind = if disabled {
+            EndpointEventKind::Disabled
+        } else {
+            EndpointEventKind::Enabled
+        };
+
+        self.emit(EndpointEvent::new(endpoint, kind));
+    }
+
+    pub fn set_endpoint_draining(&self, endpoint: &Endpoint, draining: bool) {
+        if !endpoint.set_draining(draining) {
+            return;
+        }
+
+        let kind = if draining {
+            EndpointEventKind::DrainStarted
+        } else {
+            EndpointEventKind::DrainStopped
+        };
+
+        self.emit(EndpointEvent::new(endpoint, kind));
     }
 }
 
@@ -99,9 +283,14 @@ impl EndpointWatcher {
         I: Into<Endpoint>,
     {
         Self {
-            endpoints: Arc::new(RwLock::new(
+            endpoints: ArcSwap::from_pointee(
                 urls.into_iter().map(std::convert::Into::into).collect(),
-            )),
+            ),
+            endpoints_write: Mutex::new(()),
+            routing_table: ArcSwap::default(),
+            routing_table_write: parking_lot::Mutex::new(()),
+            events: broadcast::channel(EVENTS_CAPACITY).0,
+            wake: Notify::new(),
         }
     }
 
@@ -109,7 +298,28 @@ impl EndpointWatcher {
         ENDPOINT_WATCHER.get_or_init(|| {
             info!("Creating global EndpointWatcher");
 
-            let endpoints = Config::global().base_api_urls.clone();
+            let config = Config::global();
+            let endpoints = config
+                .base_api_urls
+                .iter()
+                .cloned()
+                .map(Endpoint::new)
+                .chain(
+                    config
+                        .shadow
+                        .shadow_api_urls
+                        .iter()
+                        .cloned()
+                        .map(Endpoint::shadow),
+                )
+                .chain(config.tenants.tenants_file.iter().flat_map(|tenant| {
+                    tenant
+                        .endpoints
+                        .iter()
+                        .cloned()
+                        .map(|url| Endpoint::new(url).with_tenant(Some(tenant.name.clone())))
+                }))
+                .collect::<Vec<_>>();
 
             let watcher = Arc::new(Self::from_urls(endpoints));
 
@@ -118,8 +328,16 @@ impl EndpointWatcher {
                 let watcher = watcher.clone();
                 async move {
                     loop {
-                        watcher.check_and_update_endpoints().await;
-                        tokio::time::sleep(Config::global().api_check_interval.into()).await;
+                        let next_due_in = watcher.check_due_endpoints();
+                        trace!(?next_due_in, "Waiting for next endpoint check");
+
+                        #[allow(clippy::redundant_pub_crate)]
+                        {
+                            tokio::select! {
+                                () = tokio::time::sleep(next_due_in) => {},
+                                () = watcher.wake.notified() => {},
+                            }
+                        }
                     }
                 }
             });
@@ -0,0 +1,78 @@
+use std::{
+    pin::Pin,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use tokio::sync::{futures::Notified, Notify};
+
+/// Counts requests currently in flight.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    count: AtomicUsize,
+    idle: Notify,
+    released: Notify,
+}
+
+impl InFlight {
+    pub fn count(&self) -> usize {
+        self.count.load(Ordering::Acquire)
+    }
+
+    /// Track a request until the returned guard is dropped.
+    pub fn track(self: &Arc<Self>) -> InFlightGuard {
+        self.count.fetch_add(1, Ordering::AcqRel);
+
+        InFlightGuard(self.clone())
+    }
+
+    /// Track a request only if fewer than `limit` are in flight.
+    pub fn try_track(self: &Arc<Self>, limit: usize) -> Option<InFlightGuard> {
+        self.count
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |count| {
+                (count < limit).then_some(count + 1)
+            })
+            .ok()?;
+
+        Some(InFlightGuard(self.clone()))
+    }
+
+    /// Resolves the next time a request finishes.
+    ///
+    /// Only requests finishing after this is called count, so check for capacity after calling it.
+    pub fn released(&self) -> Pin<Box<Notified<'_>>> {
+        let mut notified = Box::pin(self.released.notified());
+        notified.as_mut().enable();
+
+        notified
+    }
+
+    /// Resolves once there are no requests in flight.
+    pub async fn wait_idle(&self) {
+        loop {
+            let notified = self.idle.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            if self.count() == 0 {
+                return;
+            }
+
+            notified.await;
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct InFlightGuard(Arc<InFlight>);
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.idle.notify_waiters();
+        }
+        self.0.released.notify_waiters();
+    }
+}
@@ -1,3 +1,3 @@
 pub mod id;
+pub mod in_flight;
 pub mod radix_fmt;
-pub mod timeframe;
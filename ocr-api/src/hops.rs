@@ -0,0 +1,30 @@
+use axum::http::{HeaderMap, HeaderValue};
+
+use crate::config::Config;
+
+/// Request header listing the IDs of the gateways a request passed through, in order.
+///
+/// Lets gateways be chained (one registered as an endpoint of another) without requests looping forever.
+pub const HOPS_HEADER: &str = "x-ocr-gateway-hops";
+
+/// IDs of the gateways the request already passed through.
+pub fn from_headers(headers: &HeaderMap) -> Vec<String> {
+    headers
+        .get_all(HOPS_HEADER)
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .map(ToString::to_string)
+        .collect()
+}
+
+/// The header value to send onwards, with this gateway added to the incoming hops.
+pub fn forwarded(headers: &HeaderMap) -> HeaderValue {
+    let mut hops = from_headers(headers);
+    hops.push(Config::global().gateway_id.clone());
+
+    // Incoming hops are visible ASCII and the gateway ID is checked on startup, so this can't fail
+    HeaderValue::from_str(&hops.join(", ")).expect("Gateway hops are valid header characters")
+}
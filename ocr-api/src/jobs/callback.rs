@@ -0,0 +1,307 @@
+use std::{
+    net::{IpAddr, SocketAddr, ToSocketAddrs},
+    sync::Arc,
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use once_cell::sync::Lazy;
+use reqwest::dns::{Addrs, Name, Resolve, Resolving};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use tracing::{debug, warn};
+use url::{Host, Url};
+
+use super::job::Job;
+use crate::config::Config;
+
+/// Header with the signature of the callback payload, as `sha256=<hex>`.
+pub const SIGNATURE_HEADER: &str = "x-ocr-signature";
+/// Header with the Unix timestamp that was signed along with the payload.
+pub const TIMESTAMP_HEADER: &str = "x-ocr-signature-timestamp";
+pub const JOB_ID_HEADER: &str = "x-ocr-job-id";
+
+/// What the signing secret of an API key is derived from, so it differs from the key itself.
+const SECRET_CONTEXT: &[u8] = b"ocr-api callback";
+
+/// How long connecting to a callback URL may take, as part of the whole delivery timeout.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+type HmacSha256 = Hmac<Sha256>;
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+    reqwest::Client::builder()
+        .connect_timeout(CONNECT_TIMEOUT)
+        .timeout(Config::global().jobs.jobs_callback_timeout.into())
+        // A redirect could point anywhere, including the internal network
+        .redirect(reqwest::redirect::Policy::none())
+        .dns_resolver(Arc::new(PublicResolver))
+        .build()
+        .expect("Failed to create callback HTTP client!")
+});
+
+/// Where to send the result of a job once it has finished.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobCallback {
+    pub url: Url,
+    /// The API key the job was submitted with, whose secret the payload is signed with.
+    pub key_name: String,
+    pub delivered: bool,
+    pub deliveries: Vec<CallbackDelivery>,
+}
+
+/// One attempt at delivering the result of a job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CallbackDelivery {
+    pub at: DateTime<Utc>,
+    /// HTTP status the callback URL responded with.
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl JobCallback {
+    pub const fn new(url: Url, key_name: String) -> Self {
+        Self {
+            url,
+            key_name,
+            delivered: false,
+            deliveries: Vec::new(),
+        }
+    }
+
+    /// Whether results may be delivered to the URL.
+    ///
+    /// It has to be HTTP(S) and point to a public address, unless its host is explicitly allowed.
+    pub async fn check_url(url: &Url) -> Result<(), String> {
+        if !matches!(url.scheme(), "http" | "https") {
+            return Err("Callback URL must be HTTP(S)".to_string());
+        }
+
+        let host = url
+            .host()
+            .ok_or_else(|| "Callback URL must have a host".to_string())?;
+
+        let ip = match host {
+            Host::Ipv4(ip) => IpAddr::V4(ip),
+            Host::Ipv6(ip) => IpAddr::V6(ip),
+            Host::Domain(domain) => return resolve_public(domain.to_string()).await.map(|_| ()),
+        };
+
+        if !is_allowed_host(&host.to_string()) && !is_public(ip) {
+            return Err("Callback URL must not point to an internal address".to_string());
+        }
+
+        Ok(())
+    }
+
+    pub fn should_retry(&self) -> bool {
+        !self.delivered && self.deliveries.len() < Config::global().jobs.jobs_callback_max_attempts
+    }
+
+    /// How long to wait before the next delivery attempt.
+    pub fn next_attempt_in(&self) -> Duration {
+        let last = match self.deliveries.last() {
+            Some(last) => last,
+            None => return Duration::ZERO,
+        };
+
+        let config = &Config::global().jobs;
+        let base: Duration = config.jobs_callback_backoff.into();
+        let max_backoff: Duration = config.jobs_callback_max_backoff.into();
+
+        let backoff = u32::try_from(self.deliveries.len() - 1)
+            .ok()
+            .and_then(|exponent| 2_u32.checked_pow(exponent))
+            .and_then(|factor| base.checked_mul(factor))
+            .map_or(max_backoff, |x| x.min(max_backoff));
+
+        let since_last = (Utc::now() - last.at).to_std().unwrap_or_default();
+
+        backoff.saturating_sub(since_last)
+    }
+}
+
+impl CallbackDelivery {
+    pub const fn is_success(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Whether callbacks may go to the host, even if it is on an internal network.
+fn is_allowed_host(host: &str) -> bool {
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+
+    Config::global()
+        .jobs
+        .jobs_callback_allowed_hosts
+        .iter()
+        .any(|x| {
+            x.trim_start_matches('[')
+                .trim_end_matches(']')
+                .eq_ignore_ascii_case(host)
+        })
+}
+
+/// Whether the address is on the public internet, rather than loopback, link-local, private or otherwise special.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            let [first, second, ..] = ip.octets();
+
+            !(ip.is_private()
+                || ip.is_loopback()
+                || ip.is_link_local()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // "This network", 0.0.0.0/8
+                || first == 0
+                // Shared address space of carrier-grade NAT, 100.64.0.0/10
+                || (first == 100 && second & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => {
+            if let Some(ip) = ip.to_ipv4_mapped() {
+                return is_public(IpAddr::V4(ip));
+            }
+
+            let first = ip.segments()[0];
+
+            !(ip.is_loopback()
+                || ip.is_unspecified()
+                || ip.is_multicast()
+                // Unique local, fc00::/7
+                || first & 0xfe00 == 0xfc00
+                // Link-local, fe80::/10
+                || first & 0xffc0 == 0xfe80)
+        }
+    }
+}
+
+/// The addresses of the host, leaving out internal ones unless the host is explicitly allowed.
+async fn resolve_public(host: String) -> Result<Vec<SocketAddr>, String> {
+    let allowed = is_allowed_host(&host);
+
+    let addrs = tokio::task::spawn_blocking(move || {
+        (host.as_str(), 0)
+            .to_socket_addrs()
+            .map(Iterator::collect::<Vec<_>>)
+            .map_err(|e| format!("Failed to resolve {host:?}: {e}"))
+    })
+    .await
+    .map_err(|e| format!("Failed to resolve host: {e}"))??;
+
+    if allowed {
+        return Ok(addrs);
+    }
+
+    let addrs = addrs
+        .into_iter()
+        .filter(|x| is_public(x.ip()))
+        .collect::<Vec<_>>();
+    if addrs.is_empty() {
+        return Err("Callback URL must not point to an internal address".to_string());
+    }
+
+    Ok(addrs)
+}
+
+/// Resolves host names for callbacks, so they can't be pointed to the internal network
+/// by a host name that resolves to a different address by the time the callback is sent.
+struct PublicResolver;
+
+impl Resolve for PublicResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let host = name.as_str().to_string();
+
+        Box::pin(async move {
+            let addrs = resolve_public(host).await?;
+
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// The secret callback payloads of jobs submitted with the API key are signed with.
+///
+/// It is `HMAC-SHA256(key = <api key>, message = "ocr-api callback")`, hex encoded,
+/// so receivers can verify payloads without knowing the API key itself.
+pub fn signing_secret(key_name: &str) -> Option<String> {
+    let (_, _, key) = Config::global()
+        .api_keys()
+        .filter(|(_, name, _)| *name == key_name)
+        .last()?;
+
+    let mut mac = HmacSha256::new_from_slice(key.as_bytes()).expect("HMAC accepts any key size");
+    mac.update(SECRET_CONTEXT);
+
+    Some(hex::encode(mac.finalize().into_bytes()))
+}
+
+/// Sign the payload as `sha256=<hex of HMAC-SHA256(key = secret, message = "<timestamp>.<payload>")>`.
+pub fn sign(secret: &str, timestamp: i64, payload: &[u8]) -> String {
+    let mut mac = HmacSha256::new_from_slice(secret.as_bytes()).expect("HMAC accepts any key size");
+    mac.update(timestamp.to_string().as_bytes());
+    mac.update(b".");
+    mac.update(payload);
+
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// POST the job to its callback URL.
+#[tracing::instrument(skip_all, fields(id = job.id, url = %callback.url))]
+pub async fn deliver(job: &Job, callback: &JobCallback) -> CallbackDelivery {
+    let mut delivery = CallbackDelivery {
+        at: Utc::now(),
+        status: None,
+        error: None,
+    };
+
+    let secret = match signing_secret(&callback.key_name) {
+        Some(secret) => secret,
+        None => {
+            delivery.error = Some(format!("API key {:?} no longer exists", callback.key_name));
+            return delivery;
+        }
+    };
+
+    if let Err(e) = JobCallback::check_url(&callback.url).await {
+        warn!(error = ?e, "Not delivering job callback");
+        delivery.error = Some(e);
+        return delivery;
+    }
+
+    let payload = serde_json::to_vec(job).expect("Failed to serialize job");
+    let timestamp = delivery.at.timestamp();
+
+    debug!("Delivering job callback");
+    let response = CLIENT
+        .post(callback.url.clone())
+        .header(reqwest::header::CONTENT_TYPE, "application/json")
+        .header(JOB_ID_HEADER, &job.id)
+        .header(TIMESTAMP_HEADER, timestamp)
+        .header(SIGNATURE_HEADER, sign(&secret, timestamp, &payload))
+        .body(payload)
+        .send()
+        .await;
+
+    match response {
+        Ok(response) => {
+            delivery.status = Some(response.status().as_u16());
+            if !response.status().is_success() {
+                warn!(status = ?response.status(), "Callback URL refused job");
+                delivery.error = Some(format!(
+                    "Callback URL responded with status {}",
+                    response.status()
+                ));
+            }
+        }
+        Err(e) => {
+            warn!(error = ?e, "Failed to deliver job callback");
+            delivery.error = Some(format!("Failed to send request: {e}"));
+        }
+    }
+
+    delivery
+}
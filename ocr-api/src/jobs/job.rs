@@ -0,0 +1,132 @@
+use axum::response::Response;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use tokio::task::AbortHandle;
+
+use super::callback::JobCallback;
+use crate::{
+    config::Priority, helpers::id::time_rand_id, ocr::HANDLER_HEADER, router::MAX_BODY_SIZE,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+    Cancelled,
+}
+
+impl JobStatus {
+    /// Whether the job is done, one way or another.
+    pub const fn is_finished(self) -> bool {
+        matches!(self, Self::Succeeded | Self::Failed | Self::Cancelled)
+    }
+}
+
+/// The response the endpoint gave for a job.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JobResult {
+    /// HTTP status of the response.
+    pub status: u16,
+    /// The handler that actually served the request.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub handler: Option<String>,
+    /// The response body, as JSON if it was valid JSON or as a string otherwise.
+    pub body: serde_json::Value,
+}
+
+impl JobResult {
+    pub async fn from_response(response: Response) -> Result<Self, String> {
+        let (parts, body) = response.into_parts();
+
+        let body = axum::body::to_bytes(body, MAX_BODY_SIZE)
+            .await
+            .map_err(|e| format!("Failed to read response: {e}"))?;
+
+        Ok(Self {
+            status: parts.status.as_u16(),
+            handler: parts
+                .headers
+                .get(HANDLER_HEADER)
+                .and_then(|x| x.to_str().ok())
+                .map(ToString::to_string),
+            body: serde_json::from_slice(&body).unwrap_or_else(|_| {
+                serde_json::Value::String(String::from_utf8_lossy(&body).into_owned())
+            }),
+        })
+    }
+}
+
+/// An OCR request that is processed in the background.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Job {
+    pub id: String,
+    pub handler: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fallback: Option<String>,
+    pub status: JobStatus,
+    /// Jobs stored before priorities existed get the default.
+    #[serde(default)]
+    pub priority: Priority,
+    /// The tenant whose endpoints the job may run on.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tenant: Option<String>,
+    /// The API key the job was submitted with, the only one that may look it up or cancel it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_name: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// How many times the job has been started, including after restarts of the gateway.
+    pub attempts: u32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<JobResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub callback: Option<JobCallback>,
+    /// Content type of the upload, which includes the multipart boundary.
+    #[serde(skip)]
+    pub content_type: Option<String>,
+    #[serde(skip)]
+    pub(super) task: Option<AbortHandle>,
+}
+
+impl Job {
+    pub fn new(
+        handler: String,
+        fallback: Option<String>,
+        priority: Priority,
+        tenant: Option<String>,
+        key_name: Option<String>,
+        callback: Option<JobCallback>,
+        content_type: Option<String>,
+    ) -> Self {
+        Self {
+            id: time_rand_id(),
+            handler,
+            fallback,
+            status: JobStatus::Queued,
+            priority,
+            tenant,
+            key_name,
+            created_at: Utc::now(),
+            started_at: None,
+            finished_at: None,
+            attempts: 0,
+            result: None,
+            error: None,
+            callback,
+            content_type,
+            task: None,
+        }
+    }
+
+    pub(super) fn finish(&mut self, status: JobStatus) {
+        self.status = status;
+        self.finished_at = Some(Utc::now());
+        self.task = None;
+    }
+}
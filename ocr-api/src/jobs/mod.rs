@@ -0,0 +1,483 @@
+mod callback;
+mod job;
+mod store;
+
+use std::{collections::HashMap, io, sync::Arc, time::Duration};
+
+use axum::{
+    body::Body,
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+};
+pub use callback::JobCallback;
+use futures::StreamExt;
+pub use job::{Job, JobResult, JobStatus};
+use once_cell::sync::OnceCell;
+use parking_lot::RwLock;
+use reqwest::{Method, StatusCode};
+use tokio::{
+    fs::File,
+    io::AsyncWriteExt,
+    sync::{Mutex, Semaphore},
+};
+use tracing::{debug, info, warn};
+
+use self::store::JobStore;
+use crate::{
+    config::Config,
+    ocr::{
+        dispatch,
+        priority::{self, PRIORITY_HEADER},
+    },
+    router::MAX_BODY_SIZE,
+    shutdown::Shutdown,
+    tenants::{self, TENANT_HEADER},
+};
+
+static JOB_QUEUE: OnceCell<Arc<JobQueue>> = OnceCell::new();
+
+/// How often to look for finished jobs past their TTL.
+const CLEANUP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Why a job couldn't be submitted.
+#[derive(Debug)]
+pub enum SubmitError {
+    QueueFull,
+    TooLarge,
+    Spool(io::Error),
+}
+
+impl IntoResponse for SubmitError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::QueueFull => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                "Too many jobs queued, try again later".to_string(),
+            )
+                .into_response(),
+            Self::TooLarge => (
+                StatusCode::PAYLOAD_TOO_LARGE,
+                "Upload is too large".to_string(),
+            )
+                .into_response(),
+            Self::Spool(e) => (
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to store upload: {:?}", e),
+            )
+                .into_response(),
+        }
+    }
+}
+
+/// What happened to a job that was deleted.
+#[derive(Debug)]
+pub enum JobRemoval {
+    /// The job was still queued or running and has been stopped.
+    Cancelled(Job),
+    /// The job had already finished and has been forgotten.
+    Removed(Job),
+}
+
+/// Asynchronous OCR jobs.
+///
+/// Uploads are spooled to disk and sent to the endpoints in the background
+/// by a limited number of workers. Job state is kept on disk as well,
+/// so unfinished jobs are picked up again after a restart.
+#[derive(Debug)]
+pub struct JobQueue {
+    jobs: RwLock<HashMap<String, Job>>,
+    workers: Semaphore,
+    store: JobStore,
+    /// Serializes writes to the store, so the latest state of a job is always the one kept.
+    store_writes: Mutex<()>,
+    max_queued: usize,
+    max_attempts: u32,
+    result_ttl: Duration,
+}
+
+impl JobQueue {
+    pub fn global() -> &'static Arc<Self> {
+        JOB_QUEUE.get_or_init(|| {
+            info!("Creating global JobQueue");
+
+            let config = &Config::global().jobs;
+            if config.jobs_spool_dir.is_none() {
+                warn!(
+                    dir = ?config.spool_dir(),
+                    "JOBS_SPOOL_DIR is not set, jobs are kept in the temporary directory and may not survive deploys",
+                );
+            }
+            let store = JobStore::new(config.spool_dir());
+
+            let mut jobs = store.load();
+            let mut unfinished = Vec::new();
+            let mut undelivered = Vec::new();
+            for job in &mut jobs {
+                if !job.status.is_finished() {
+                    job.status = JobStatus::Queued;
+                    unfinished.push(job.id.clone());
+                } else if job.callback.as_ref().is_some_and(JobCallback::should_retry) {
+                    undelivered.push(job.id.clone());
+                }
+            }
+            info!(
+                jobs = jobs.len(),
+                unfinished = unfinished.len(),
+                "Loaded stored jobs"
+            );
+
+            let queue = Arc::new(Self {
+                jobs: RwLock::new(jobs.into_iter().map(|x| (x.id.clone(), x)).collect()),
+                workers: Semaphore::new(config.jobs_max_concurrent.max(1)),
+                store,
+                store_writes: Mutex::new(()),
+                max_queued: config.jobs_max_queued,
+                max_attempts: config.jobs_max_attempts,
+                result_ttl: config.jobs_result_ttl.into(),
+            });
+
+            for id in unfinished {
+                queue.spawn(&id);
+            }
+            for id in undelivered {
+                let queue = queue.clone();
+                tokio::spawn(async move { queue.notify(&id).await });
+            }
+
+            tokio::spawn({
+                debug!("Starting job cleanup task");
+                let queue = queue.clone();
+                async move {
+                    let mut interval = tokio::time::interval(CLEANUP_INTERVAL);
+                    loop {
+                        interval.tick().await;
+                        queue.remove_expired().await;
+                    }
+                }
+            });
+
+            queue
+        })
+    }
+
+    async fn spool(&self, id: &str, body: Body) -> Result<(), SubmitError> {
+        let mut file = File::create(self.store.upload_path(id))
+            .await
+            .map_err(SubmitError::Spool)?;
+
+        let mut size = 0;
+        let mut stream = body.into_data_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(|e| SubmitError::Spool(io::Error::other(e)))?;
+
+            size += chunk.len();
+            if size > MAX_BODY_SIZE {
+                return Err(SubmitError::TooLarge);
+            }
+
+            file.write_all(&chunk).await.map_err(SubmitError::Spool)?;
+        }
+
+        file.flush().await.map_err(SubmitError::Spool)
+    }
+
+    /// Write the current state of the job to the store, or remove it if the job is gone.
+    async fn persist(&self, id: &str) {
+        let _write = self.store_writes.lock().await;
+
+        match self.get(id) {
+            Some(job) => {
+                if let Err(e) = self.store.save(&job).await {
+                    warn!(?id, error = ?e, "Failed to store job");
+                }
+            }
+            None => self.store.remove(id).await,
+        }
+    }
+
+    fn queued(&self) -> usize {
+        self.jobs
+            .read()
+            .values()
+            .filter(|x| x.status == JobStatus::Queued)
+            .count()
+    }
+
+    /// Spool the upload to disk and queue it to be sent to the handler.
+    pub async fn submit(
+        self: &Arc<Self>,
+        handler: String,
+        fallback: Option<String>,
+        key_name: Option<String>,
+        callback: Option<JobCallback>,
+        headers: &HeaderMap,
+        body: Body,
+    ) -> Result<Job, SubmitError> {
+        if self.queued() >= self.max_queued {
+            return Err(SubmitError::QueueFull);
+        }
+
+        let content_type = headers
+            .get(header::CONTENT_TYPE)
+            .and_then(|x| x.to_str().ok())
+            .map(ToString::to_string);
+        let job = Job::new(
+            handler,
+            fallback,
+            priority::from_headers(headers),
+            tenants::from_headers(headers).map(ToString::to_string),
+            key_name,
+            callback,
+            content_type,
+        );
+        let id = job.id.clone();
+
+        if let Err(e) = self.spool(&id, body).await {
+            self.store.remove_upload(&id).await;
+            return Err(e);
+        }
+
+        debug!(?id, handler = ?job.handler, "Queueing job");
+        self.jobs.write().insert(id.clone(), job.clone());
+        self.persist(&id).await;
+
+        self.spawn(&id);
+
+        Ok(job)
+    }
+
+    /// Start a task to run the job once a worker is free.
+    fn spawn(self: &Arc<Self>, id: &str) {
+        let task = tokio::spawn(self.clone().run(id.to_string()));
+
+        let mut jobs = self.jobs.write();
+        if let Some(job) = jobs.get_mut(id).filter(|x| !x.status.is_finished()) {
+            job.task = Some(task.abort_handle());
+        }
+        drop(jobs);
+    }
+
+    /// Mark the job as started, unless it has finished or been started too often already.
+    fn start(&self, id: &str) -> Option<Job> {
+        let mut jobs = self.jobs.write();
+        let job = jobs.get_mut(id).filter(|x| !x.status.is_finished())?;
+
+        if job.attempts >= self.max_attempts {
+            warn!(attempts = job.attempts, "Giving up on job");
+            job.error = Some(format!("Gave up after {} attempts", job.attempts));
+            job.finish(JobStatus::Failed);
+            return None;
+        }
+
+        job.status = JobStatus::Running;
+        job.started_at = Some(chrono::Utc::now());
+        job.attempts += 1;
+
+        let job = job.clone();
+        drop(jobs);
+
+        Some(job)
+    }
+
+    fn finish(&self, id: &str, outcome: Result<JobResult, String>) {
+        let mut jobs = self.jobs.write();
+        let job = match jobs.get_mut(id) {
+            Some(job) if !job.status.is_finished() => job,
+            _ => return,
+        };
+
+        match outcome {
+            Ok(result) => {
+                let status = if StatusCode::from_u16(result.status).is_ok_and(|x| x.is_success()) {
+                    JobStatus::Succeeded
+                } else {
+                    JobStatus::Failed
+                };
+
+                job.result = Some(result);
+                job.finish(status);
+            }
+            Err(e) => {
+                warn!(error = ?e, "Job failed");
+                job.error = Some(e);
+                job.finish(JobStatus::Failed);
+            }
+        }
+
+        debug!(status = ?job.status, "Job finished");
+        drop(jobs);
+    }
+
+    #[tracing::instrument(skip(self))]
+    async fn run(self: Arc<Self>, id: String) {
+        let permit = self.workers.acquire().await.expect("Job workers closed");
+
+        // Jobs that haven't started yet are picked up again after the restart
+        let shutdown = Shutdown::global();
+        if shutdown.is_started() {
+            debug!("Shutting down, not starting job");
+            return;
+        }
+        let in_flight = shutdown.track_request();
+
+        let job = self.start(&id);
+        self.persist(&id).await;
+
+        let job = match job {
+            Some(job) => job,
+            None => {
+                self.store.remove_upload(&id).await;
+                return;
+            }
+        };
+
+        debug!(handler = ?job.handler, attempt = job.attempts, "Running job");
+
+        let outcome = match tokio::fs::read(self.store.upload_path(&id)).await {
+            Ok(body) => {
+                let mut headers = HeaderMap::new();
+                if let Some(content_type) = job
+                    .content_type
+                    .as_deref()
+                    .and_then(|x| HeaderValue::from_str(x).ok())
+                {
+                    headers.insert(header::CONTENT_TYPE, content_type);
+                }
+                headers.insert(
+                    PRIORITY_HEADER,
+                    HeaderValue::from_static(job.priority.as_str()),
+                );
+                if let Some(tenant) = job
+                    .tenant
+                    .as_deref()
+                    .and_then(|x| HeaderValue::from_str(x).ok())
+                {
+                    headers.insert(TENANT_HEADER, tenant);
+                }
+
+                let response = dispatch::buffered(
+                    &job.handler,
+                    job.fallback.as_deref(),
+                    Method::POST,
+                    headers,
+                    body.into(),
+                )
+                .await;
+
+                JobResult::from_response(response).await
+            }
+            Err(e) => Err(format!("Failed to read spooled upload: {e}")),
+        };
+
+        self.finish(&id, outcome);
+        self.persist(&id).await;
+        self.store.remove_upload(&id).await;
+
+        drop(in_flight);
+        drop(permit);
+        self.notify(&id).await;
+    }
+
+    /// The job, if it finished by itself and its result still has to be delivered.
+    fn pending_callback(&self, id: &str) -> Option<(Job, JobCallback)> {
+        let job = self
+            .get(id)
+            .filter(|x| matches!(x.status, JobStatus::Succeeded | JobStatus::Failed))?;
+        let callback = job.callback.clone().filter(JobCallback::should_retry)?;
+
+        Some((job, callback))
+    }
+
+    /// Deliver the result of the job to its callback URL, retrying with backoff.
+    async fn notify(&self, id: &str) {
+        while let Some((_, callback)) = self.pending_callback(id) {
+            tokio::time::sleep(callback.next_attempt_in()).await;
+
+            // The job may have been removed in the meantime
+            let (job, callback) = match self.pending_callback(id) {
+                Some(pending) => pending,
+                None => return,
+            };
+
+            let delivery = callback::deliver(&job, &callback).await;
+
+            if let Some(callback) = self
+                .jobs
+                .write()
+                .get_mut(id)
+                .and_then(|x| x.callback.as_mut())
+            {
+                callback.delivered = delivery.is_success();
+                callback.deliveries.push(delivery);
+            }
+            self.persist(id).await;
+        }
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.jobs.read().get(id).cloned()
+    }
+
+    /// Cancel the job if it hasn't finished yet, or forget it if it has.
+    pub async fn remove(&self, id: &str) -> Option<JobRemoval> {
+        let removal = self.take(id)?;
+
+        self.persist(id).await;
+        self.store.remove_upload(id).await;
+
+        Some(removal)
+    }
+
+    fn take(&self, id: &str) -> Option<JobRemoval> {
+        let mut jobs = self.jobs.write();
+
+        if jobs.get(id)?.status.is_finished() {
+            return jobs.remove(id).map(JobRemoval::Removed);
+        }
+
+        let job = jobs.get_mut(id)?;
+        if let Some(task) = job.task.take() {
+            task.abort();
+        }
+        job.finish(JobStatus::Cancelled);
+
+        let job = job.clone();
+        drop(jobs);
+
+        Some(JobRemoval::Cancelled(job))
+    }
+
+    /// Forget finished jobs whose results have been kept for long enough.
+    async fn remove_expired(&self) {
+        let now = chrono::Utc::now();
+
+        let expired = {
+            let mut jobs = self.jobs.write();
+
+            let expired = jobs
+                .values()
+                .filter(|job| {
+                    job.status.is_finished()
+                        && job.finished_at.is_some_and(|at| {
+                            (now - at).to_std().unwrap_or_default() > self.result_ttl
+                        })
+                })
+                .map(|job| job.id.clone())
+                .collect::<Vec<_>>();
+
+            for id in &expired {
+                jobs.remove(id);
+            }
+
+            expired
+        };
+
+        if !expired.is_empty() {
+            debug!(jobs = expired.len(), "Removing expired jobs");
+        }
+
+        for id in expired {
+            self.persist(&id).await;
+        }
+    }
+}
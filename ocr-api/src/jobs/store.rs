@@ -0,0 +1,145 @@
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+use super::job::Job;
+
+const RECORD_EXTENSION: &str = "json";
+const UPLOAD_EXTENSION: &str = "upload";
+
+/// How a job is kept on disk.
+#[derive(Debug, Serialize, Deserialize)]
+struct JobRecord {
+    #[serde(flatten)]
+    job: Job,
+    content_type: Option<String>,
+    upload: PathBuf,
+}
+
+/// Job state on disk, as one JSON file per job next to its spooled upload.
+#[derive(Debug)]
+pub struct JobStore {
+    dir: PathBuf,
+}
+
+impl JobStore {
+    pub fn new(dir: PathBuf) -> Self {
+        debug!(?dir, "Creating job store directory");
+        std::fs::create_dir_all(&dir).expect("Failed to create job store directory!");
+
+        Self { dir }
+    }
+
+    pub fn upload_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.{UPLOAD_EXTENSION}"))
+    }
+
+    fn record_path(&self, id: &str) -> PathBuf {
+        self.dir.join(format!("{id}.{RECORD_EXTENSION}"))
+    }
+
+    /// Write the current state of the job, replacing the previous one.
+    pub async fn save(&self, job: &Job) -> io::Result<()> {
+        let record = JobRecord {
+            job: job.clone(),
+            content_type: job.content_type.clone(),
+            upload: self.upload_path(&job.id),
+        };
+        let json = serde_json::to_vec(&record).map_err(io::Error::other)?;
+
+        // Written to a temporary file first so a crash never leaves a half-written record
+        let path = self.record_path(&job.id);
+        let tmp_path = path.with_extension(format!("{RECORD_EXTENSION}.tmp"));
+        tokio::fs::write(&tmp_path, json).await?;
+        tokio::fs::rename(&tmp_path, &path).await
+    }
+
+    pub async fn remove_upload(&self, id: &str) {
+        remove_file(&self.upload_path(id)).await;
+    }
+
+    /// Remove everything stored for the job.
+    pub async fn remove(&self, id: &str) {
+        remove_file(&self.record_path(id)).await;
+        remove_file(&self.upload_path(id)).await;
+    }
+
+    /// Read all stored jobs, cleaning up files the store left behind.
+    ///
+    /// Only files the store writes itself are removed, anything else in the directory is left alone.
+    pub fn load(&self) -> Vec<Job> {
+        let entries = match std::fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!(dir = ?self.dir, error = ?e, "Failed to read job store directory");
+                return Vec::new();
+            }
+        };
+
+        let mut jobs = Vec::new();
+        let mut uploads = Vec::new();
+        // Records that couldn't be read, whose uploads are kept along with them
+        let mut unreadable = Vec::new();
+
+        for path in entries.filter_map(Result::ok).map(|x| x.path()) {
+            let stem = path
+                .file_stem()
+                .and_then(|x| x.to_str())
+                .map(ToString::to_string);
+
+            match path.extension().and_then(|x| x.to_str()) {
+                Some(RECORD_EXTENSION) => match load_record(&path) {
+                    Ok(record) => {
+                        let mut job = record.job;
+                        job.content_type = record.content_type;
+                        jobs.push(job);
+                    }
+                    Err(e) => {
+                        warn!(?path, error = ?e, "Failed to read job record, leaving it alone");
+                        unreadable.extend(stem);
+                    }
+                },
+                Some(UPLOAD_EXTENSION) => uploads.push(path),
+                Some("tmp") if path.to_str().is_some_and(is_record_tmp) => {
+                    debug!(?path, "Removing half-written job record");
+                    let _ = std::fs::remove_file(&path);
+                }
+                _ => {}
+            }
+        }
+
+        for path in uploads {
+            let has_job = path.file_stem().and_then(|x| x.to_str()).is_some_and(|id| {
+                jobs.iter().any(|job| job.id == id) || unreadable.iter().any(|x| x == id)
+            });
+
+            if !has_job {
+                debug!(?path, "Removing upload without a job");
+                let _ = std::fs::remove_file(&path);
+            }
+        }
+
+        jobs
+    }
+}
+
+/// Whether the path is a record that was being written when the gateway stopped.
+fn is_record_tmp(path: &str) -> bool {
+    path.ends_with(&format!(".{RECORD_EXTENSION}.tmp"))
+}
+
+fn load_record(path: &Path) -> anyhow::Result<JobRecord> {
+    Ok(serde_json::from_slice(&std::fs::read(path)?)?)
+}
+
+async fn remove_file(path: &Path) {
+    match tokio::fs::remove_file(path).await {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => warn!(?path, error = ?e, "Failed to remove job file"),
+    }
+}
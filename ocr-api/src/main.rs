@@ -1,15 +1,25 @@
+use std::net::SocketAddr;
+
 use axum::{extract::Request, ServiceExt};
 use config::Config;
-use tokio::{net::TcpListener, signal};
+use tokio::net::TcpListener;
 use tower::Layer;
 use tower_http::normalize_path::NormalizePathLayer;
 use tracing::{debug, info, warn};
 
+mod alerts;
+mod audit_log;
+mod cache;
 pub mod config;
 mod endpoint_watcher;
 pub mod helpers;
+mod hops;
+mod jobs;
 mod logger;
+mod ocr;
 mod router;
+mod shutdown;
+mod tenants;
 
 #[tokio::main]
 async fn main() {
@@ -33,6 +43,10 @@ async fn main() {
 
     // Reference the global endpoint watcher to start global init
     endpoint_watcher::EndpointWatcher::global();
+    audit_log::AuditLog::global();
+    alerts::AlertManager::global();
+    jobs::JobQueue::global();
+    cache::ResultCache::global();
 
     let app = router::create_router();
     let app = NormalizePathLayer::trim_trailing_slash().layer(app);
@@ -57,35 +71,17 @@ async fn main() {
     );
 
     info!("API auth key is {:?}", Config::global().auth.api_auth_key);
-    axum::serve(listener, ServiceExt::<Request>::into_make_service(app))
-        .with_graceful_shutdown(shutdown_signal())
-        .await
-        .expect("Failed to start server!");
-}
-
-async fn shutdown_signal() {
-    let ctrl_c = async {
-        signal::ctrl_c()
-            .await
-            .expect("failed to install Ctrl+C handler");
-    };
-
-    #[cfg(unix)]
-    let terminate = async {
-        signal::unix::signal(signal::unix::SignalKind::terminate())
-            .expect("failed to install signal handler")
-            .recv()
-            .await;
-    };
-
-    #[cfg(not(unix))]
-    let terminate = std::future::pending::<()>();
+    let server = axum::serve(
+        listener,
+        ServiceExt::<Request>::into_make_service_with_connect_info::<SocketAddr>(app),
+    )
+    .with_graceful_shutdown(shutdown::graceful());
 
     #[allow(clippy::redundant_pub_crate)]
     {
         tokio::select! {
-            () = ctrl_c => {},
-            () = terminate => {},
+            res = server => res.expect("Failed to start server!"),
+            () = shutdown::forced() => warn!("Forcing shutdown with connections still open"),
         }
     }
 }
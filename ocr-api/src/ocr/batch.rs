@@ -0,0 +1,231 @@
+use std::collections::BTreeMap;
+
+use axum::{
+    body::Bytes,
+    extract::Multipart,
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+    Json,
+};
+use futures::StreamExt;
+use ocr_common::archive::{self, ArchiveKind, ExtractLimits};
+use reqwest::{Method, StatusCode};
+use serde::Serialize;
+use tracing::{debug, trace};
+
+use super::{
+    dispatch,
+    forward::multipart_file,
+    result::{OcrResponse, OcrResult},
+    HANDLER_HEADER,
+};
+use crate::{config::Config, router::MAX_BODY_SIZE};
+
+/// One file of a batch.
+#[derive(Debug)]
+pub struct BatchFile {
+    pub name: String,
+    pub content_type: Option<String>,
+    pub data: Bytes,
+}
+
+/// Collects the files of a batch, giving each a unique name.
+#[derive(Debug, Default)]
+struct BatchFiles(Vec<BatchFile>);
+
+impl BatchFiles {
+    fn push(&mut self, mut file: BatchFile) -> Result<(), String> {
+        if self.0.len() >= Config::global().batch.batch_max_files {
+            return Err(format!(
+                "Too many files, at most {} are allowed",
+                Config::global().batch.batch_max_files
+            ));
+        }
+
+        if self.0.iter().any(|x| x.name == file.name) {
+            // There are more candidates than files, so one of them is always free
+            let name = (2..=self.0.len() + 2)
+                .map(|i| format!("{} ({i})", file.name))
+                .find(|name| self.0.iter().all(|x| x.name != *name))
+                .expect("Some name is always free");
+
+            file.name = name;
+        }
+
+        self.0.push(file);
+
+        Ok(())
+    }
+}
+
+/// Read the `file` fields of the request, expanding any ZIP or TAR archives into the files they contain.
+#[tracing::instrument(skip_all)]
+pub async fn read_files(mut multipart: Multipart) -> Result<Vec<BatchFile>, String> {
+    let mut files = BatchFiles::default();
+
+    while let Some(field) = multipart
+        .next_field()
+        .await
+        .map_err(|e| format!("Failed to read upload: {e}"))?
+    {
+        if field.name() != Some("file") {
+            continue;
+        }
+
+        let name = field.file_name().map_or_else(
+            || format!("file-{}", files.0.len() + 1),
+            ToString::to_string,
+        );
+        let content_type = field.content_type().map(ToString::to_string);
+        let data = field
+            .bytes()
+            .await
+            .map_err(|e| format!("Failed to read file {name:?}: {e}"))?;
+
+        let kind = match ArchiveKind::detect(&data) {
+            Some(kind) => kind,
+            None => {
+                files.push(BatchFile {
+                    name,
+                    content_type,
+                    data,
+                })?;
+                continue;
+            }
+        };
+
+        debug!(?name, ?kind, "Extracting archive");
+        let config = &Config::global().batch;
+        let limits = ExtractLimits {
+            files: config.batch_max_files.saturating_sub(files.0.len()),
+            file_size: config
+                .batch_max_extracted_file_mb
+                .saturating_mul(1024 * 1024),
+            total_size: config.batch_max_extracted_mb.saturating_mul(1024 * 1024),
+        };
+        let entries = tokio::task::spawn_blocking(move || archive::extract(kind, &data, limits))
+            .await
+            .map_err(|e| format!("Failed to extract archive {name:?}: {e}"))?
+            .map_err(|e| format!("Failed to extract archive {name:?}: {e}"))?;
+
+        for entry in entries {
+            files.push(BatchFile {
+                content_type: archive::guess_content_type(&entry.name).map(ToString::to_string),
+                name: entry.name,
+                data: entry.data.into(),
+            })?;
+        }
+    }
+
+    Ok(files.0)
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchResponse {
+    pub engine: String,
+    pub succeeded: usize,
+    pub failed: usize,
+    /// The result of each file, by file name.
+    pub files: BTreeMap<String, BatchFileResult>,
+}
+
+impl IntoResponse for BatchResponse {
+    fn into_response(self) -> Response {
+        let status = if self.succeeded > 0 || self.files.is_empty() {
+            StatusCode::OK
+        } else {
+            StatusCode::BAD_GATEWAY
+        };
+
+        (status, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchFileResult {
+    /// The handler that actually served the file.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub handler: Option<String>,
+    #[serde(flatten)]
+    pub result: OcrResult,
+}
+
+/// Run each file through the handler, a few at a time.
+#[tracing::instrument(skip(headers, files), fields(files = files.len()))]
+pub async fn run(
+    handler: String,
+    fallback: Option<String>,
+    headers: &HeaderMap,
+    files: Vec<BatchFile>,
+) -> BatchResponse {
+    debug!("Running batch");
+
+    let files = futures::stream::iter(files)
+        .map(|file| {
+            let handler = &handler;
+            let fallback = fallback.as_deref();
+
+            async move {
+                let result = run_file(handler, fallback, headers, &file).await;
+                trace!(name = ?file.name, ?result, "Batch file done");
+
+                (file.name, result)
+            }
+        })
+        .buffer_unordered(Config::global().batch.batch_max_concurrent.max(1))
+        .collect::<BTreeMap<_, _>>()
+        .await;
+
+    let succeeded = files
+        .values()
+        .filter(|x| matches!(x.result, OcrResult::Data(_)))
+        .count();
+
+    BatchResponse {
+        engine: handler,
+        succeeded,
+        failed: files.len() - succeeded,
+        files,
+    }
+}
+
+async fn run_file(
+    handler: &str,
+    fallback: Option<&str>,
+    headers: &HeaderMap,
+    file: &BatchFile,
+) -> BatchFileResult {
+    let (content_type, body) = multipart_file(&file.name, file.content_type.as_deref(), &file.data);
+
+    let mut headers = headers.clone();
+    headers.remove(header::CONTENT_LENGTH);
+    headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_str(&content_type).expect("Invalid content type"),
+    );
+
+    let response = dispatch::buffered(handler, fallback, Method::POST, headers, body).await;
+
+    let status = response.status();
+    let served_by = response
+        .headers()
+        .get(HANDLER_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .map(ToString::to_string);
+
+    let result = match axum::body::to_bytes(response.into_body(), MAX_BODY_SIZE).await {
+        Ok(body) => match serde_json::from_slice::<OcrResponse>(&body) {
+            Ok(response) => response.result,
+            Err(_) => OcrResult::Error(format!(
+                "Invalid response from endpoint (status {status}): {}",
+                String::from_utf8_lossy(&body)
+            )),
+        },
+        Err(e) => OcrResult::Error(format!("Failed to read response: {e}")),
+    };
+
+    BatchFileResult {
+        handler: served_by,
+        result,
+    }
+}
@@ -0,0 +1,132 @@
+use std::{collections::HashMap, future::Future, sync::Arc};
+
+use axum::{
+    body::{Body, Bytes},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use futures::{
+    future::{BoxFuture, Shared, WeakShared},
+    FutureExt,
+};
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use reqwest::StatusCode;
+use tracing::{debug, info, warn};
+
+use crate::{config::Config, router::MAX_BODY_SIZE};
+
+static COALESCER: OnceCell<Arc<Coalescer>> = OnceCell::new();
+
+type SharedRequest = Shared<BoxFuture<'static, BufferedResponse>>;
+
+/// A response read into memory, so every request waiting on it can get a copy.
+#[derive(Debug, Clone)]
+struct BufferedResponse {
+    status: StatusCode,
+    headers: HeaderMap,
+    body: Bytes,
+}
+
+impl BufferedResponse {
+    async fn read(response: Response) -> Self {
+        let (parts, body) = response.into_parts();
+
+        match axum::body::to_bytes(body, MAX_BODY_SIZE).await {
+            Ok(body) => Self {
+                status: parts.status,
+                headers: parts.headers,
+                body,
+            },
+            Err(e) => {
+                warn!(error = ?e, "Failed to read response");
+                Self {
+                    status: StatusCode::BAD_GATEWAY,
+                    headers: HeaderMap::new(),
+                    body: format!("Failed to read response: {:?}", e).into(),
+                }
+            }
+        }
+    }
+}
+
+impl IntoResponse for BufferedResponse {
+    fn into_response(self) -> Response {
+        let mut response = Response::new(Body::from(self.body));
+        *response.status_mut() = self.status;
+        *response.headers_mut() = self.headers;
+
+        response
+    }
+}
+
+/// Identical OCR requests in flight, so they share one call to the endpoints.
+///
+/// Requests are identical if they have the same [`request_key`](super::key::request_key).
+#[derive(Debug, Default)]
+pub struct Coalescer {
+    /// Only weak references, so a request is dropped once nobody waits on it anymore.
+    in_flight: Mutex<HashMap<String, WeakShared<BoxFuture<'static, BufferedResponse>>>>,
+}
+
+impl Coalescer {
+    pub fn global() -> &'static Arc<Self> {
+        COALESCER.get_or_init(|| {
+            info!("Creating global Coalescer");
+
+            Arc::new(Self::default())
+        })
+    }
+
+    pub fn is_enabled() -> bool {
+        Config::global().cache.coalesce_requests
+    }
+
+    /// Run the request, or wait for the result of an identical one already in flight.
+    pub async fn run<F>(self: &Arc<Self>, key: Option<&str>, request: F) -> Response
+    where
+        F: Future<Output = Response> + Send + 'static,
+    {
+        match key.filter(|_| Self::is_enabled()) {
+            Some(key) => self.join(key, request).await.into_response(),
+            None => request.await,
+        }
+    }
+
+    fn join<F>(self: &Arc<Self>, key: &str, request: F) -> SharedRequest
+    where
+        F: Future<Output = Response> + Send + 'static,
+    {
+        let mut in_flight = self.in_flight.lock();
+
+        if let Some(shared) = in_flight.get(key).and_then(WeakShared::upgrade) {
+            drop(in_flight);
+            debug!(?key, "Joining identical request in flight");
+            return shared;
+        }
+
+        let shared = {
+            let coalescer = self.clone();
+            let key = key.to_string();
+
+            async move {
+                let response = BufferedResponse::read(request.await).await;
+                coalescer.in_flight.lock().remove(&key);
+
+                response
+            }
+            .boxed()
+            .shared()
+        };
+
+        // Requests nobody waited on until the end never removed themselves
+        in_flight.retain(|_, x| x.upgrade().is_some());
+        in_flight.insert(
+            key.to_string(),
+            shared.downgrade().expect("Request hasn't run yet"),
+        );
+        drop(in_flight);
+
+        shared
+    }
+}
@@ -0,0 +1,87 @@
+use axum::{
+    body::Bytes,
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use reqwest::{Method, StatusCode};
+use tracing::trace;
+
+use super::{
+    coalesce::Coalescer,
+    ensemble::{self, QueryEnsemble},
+    fallback,
+    key::request_key,
+};
+use crate::{
+    cache::CacheLookup,
+    config::{Config, VirtualHandlerStrategy},
+    tenants,
+};
+
+/// Run an already buffered OCR request for the handler, the same way the proxy would.
+///
+/// Ensemble virtual handlers are run and merged, anything else goes through the handler chain.
+/// Results are served from and stored in the result cache, and identical requests in flight
+/// share one run.
+pub async fn buffered(
+    handler: &str,
+    fallback: Option<&str>,
+    method: Method,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let key = request_key(handler, fallback, &method, &headers, &body);
+
+    let lookup = CacheLookup::new(handler, key.as_deref(), &headers);
+    if let Some(response) = lookup.hit().await {
+        return response;
+    }
+
+    let request = {
+        let handler = handler.to_string();
+        let fallback = fallback.map(ToString::to_string);
+
+        async move {
+            let response = run(&handler, fallback.as_deref(), method, headers, body).await;
+
+            lookup.store(response).await
+        }
+    };
+
+    Coalescer::global().run(key.as_deref(), request).await
+}
+
+async fn run(
+    handler: &str,
+    fallback: Option<&str>,
+    method: Method,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    if let Some(virtual_handler) = Config::global()
+        .virtual_handler(handler)
+        .filter(|x| x.strategy == VirtualHandlerStrategy::Ensemble)
+    {
+        return ensemble::run(
+            virtual_handler.handlers.clone(),
+            &QueryEnsemble::default(),
+            &headers,
+            body,
+        )
+        .await
+        .into_response();
+    }
+
+    let routes = fallback::routes(handler, fallback, tenants::from_headers(&headers));
+    trace!(?routes, "Chose routes");
+
+    if routes.is_empty() {
+        return (
+            StatusCode::NOT_FOUND,
+            "No live endpoints found supporting that handler".to_string(),
+        )
+            .into_response();
+    }
+
+    fallback::proxy(routes, method, headers, body).await
+}
@@ -0,0 +1,344 @@
+use std::{collections::BTreeMap, time::Instant};
+
+use axum::{
+    body::Bytes,
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+    Json,
+};
+use rand::prelude::*;
+use reqwest::{Method, StatusCode};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace};
+
+use super::{
+    forward::send_buffered,
+    priority,
+    queue::EndpointQueue,
+    result::{OcrResponse, OcrResult, OcrTextItem},
+};
+use crate::{
+    endpoint_watcher::{endpoint::EndpointId, EndpointWatcher},
+    tenants,
+};
+
+/// Confidence used for voting when a handler doesn't report one.
+const UNKNOWN_CONFIDENCE: f64 = 0.5;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct QueryEnsemble {
+    /// Comma separated list of handlers to use. Defaults to all live handlers.
+    pub handlers: Option<String>,
+    /// How much two boxes have to overlap to be considered the same text.
+    pub iou_threshold: Option<f64>,
+    /// How many handlers have to find a piece of text for it to be in the merged result.
+    pub min_votes: Option<usize>,
+}
+
+impl QueryEnsemble {
+    /// The handlers to use for a request of the tenant.
+    pub fn handlers(&self, tenant: Option<&str>) -> Vec<String> {
+        let mut handlers = self.handlers.as_ref().map_or_else(
+            || {
+                EndpointWatcher::global()
+                    .routing_table()
+                    .handlers(tenant)
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+            },
+            |handlers| {
+                handlers
+                    .split([',', ' '])
+                    .map(str::trim)
+                    .filter(|x| !x.is_empty())
+                    .map(ToString::to_string)
+                    .collect()
+            },
+        );
+
+        handlers.sort_unstable();
+        handlers.dedup();
+
+        handlers
+    }
+
+    fn merge_options(&self) -> MergeOptions {
+        MergeOptions {
+            iou_threshold: self.iou_threshold.unwrap_or(0.5).clamp(0.0, 1.0),
+            min_votes: self.min_votes.unwrap_or(1),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct EnsembleResponse {
+    pub engine: &'static str,
+    pub handlers: Vec<String>,
+    pub results: BTreeMap<String, EnsembleHandlerResult>,
+    pub data: Vec<EnsembleItem>,
+}
+
+impl EnsembleResponse {
+    pub fn any_succeeded(&self) -> bool {
+        self.results
+            .values()
+            .any(|x| matches!(x.result, OcrResult::Data(_)))
+    }
+}
+
+impl IntoResponse for EnsembleResponse {
+    fn into_response(self) -> Response {
+        let status = if self.any_succeeded() {
+            StatusCode::OK
+        } else {
+            StatusCode::BAD_GATEWAY
+        };
+
+        (status, Json(self)).into_response()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct EnsembleHandlerResult {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub endpoint_id: Option<EndpointId>,
+    pub latency_ms: f64,
+    #[serde(flatten)]
+    pub result: OcrResult,
+}
+
+/// A piece of text from the merged result.
+#[derive(Debug, Serialize)]
+pub struct EnsembleItem {
+    #[serde(flatten)]
+    pub item: OcrTextItem,
+    /// Handlers that found this text at this place.
+    pub handlers: Vec<String>,
+    /// Handlers that found some text at this place.
+    pub votes: usize,
+    /// Share of all handlers with a result that agree on the text.
+    pub agreement: f64,
+}
+
+#[tracing::instrument(skip(headers, body))]
+pub async fn run(
+    handlers: Vec<String>,
+    query: &QueryEnsemble,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> EnsembleResponse {
+    debug!("Running ensemble OCR");
+
+    // One deadline for all handlers, so the slowest queue bounds the whole request
+    let deadline = EndpointQueue::deadline();
+
+    let results = futures::future::join_all(handlers.iter().map(|handler| {
+        let body = body.clone();
+
+        async move {
+            (
+                handler.clone(),
+                run_handler(handler, headers, body, deadline).await,
+            )
+        }
+    }))
+    .await
+    .into_iter()
+    .collect::<BTreeMap<_, _>>();
+
+    let data = merge(&results, &query.merge_options());
+
+    EnsembleResponse {
+        engine: "ensemble",
+        handlers,
+        results,
+        data,
+    }
+}
+
+async fn run_handler(
+    handler: &str,
+    headers: &HeaderMap,
+    body: Bytes,
+    deadline: tokio::time::Instant,
+) -> EnsembleHandlerResult {
+    let tenant = tenants::from_headers(headers);
+    let route = EndpointWatcher::global()
+        .routing_table()
+        .routes(handler, tenant)
+        .choose(&mut rand::thread_rng())
+        .cloned();
+
+    let route = match route {
+        Some(route) => route,
+        None => {
+            return EnsembleHandlerResult {
+                endpoint_id: None,
+                latency_ms: 0.0,
+                result: OcrResult::Error(
+                    "No live endpoints found supporting that handler".to_string(),
+                ),
+            }
+        }
+    };
+
+    trace!(?route, "Chose route");
+
+    let acquired = EndpointQueue::global()
+        .acquire(
+            handler,
+            tenant,
+            route,
+            priority::from_headers(headers),
+            deadline,
+        )
+        .await;
+    let (route, _in_flight) = match acquired {
+        Ok(acquired) => acquired,
+        Err(_) => {
+            return EnsembleHandlerResult {
+                endpoint_id: None,
+                latency_ms: 0.0,
+                result: OcrResult::Error(
+                    "All endpoints supporting that handler are busy".to_string(),
+                ),
+            }
+        }
+    };
+    let started = Instant::now();
+
+    let result = match send_buffered(&route, Method::POST, headers, body).await {
+        Ok(response) => {
+            let status = response.status();
+
+            match response.json::<OcrResponse>().await {
+                Ok(response) => response.result,
+                Err(e) => OcrResult::Error(format!(
+                    "Invalid response from endpoint (status {status}): {e}"
+                )),
+            }
+        }
+        Err(e) => OcrResult::Error(format!("Failed to proxy request: {e}")),
+    };
+
+    EnsembleHandlerResult {
+        endpoint_id: Some(route.endpoint.id.clone()),
+        latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+        result,
+    }
+}
+
+#[derive(Debug)]
+struct MergeOptions {
+    iou_threshold: f64,
+    min_votes: usize,
+}
+
+#[derive(Debug)]
+struct Candidate<'a> {
+    handler: &'a str,
+    item: &'a OcrTextItem,
+}
+
+impl Candidate<'_> {
+    fn confidence(&self) -> f64 {
+        self.item.confidence.unwrap_or(UNKNOWN_CONFIDENCE)
+    }
+
+    fn normalized_text(&self) -> String {
+        self.item
+            .text
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    /// Whether the two candidates describe the same piece of the image.
+    fn overlaps(&self, other: &Self, iou_threshold: f64) -> bool {
+        match (&self.item.text_box, &other.item.text_box) {
+            (Some(a), Some(b)) => a.iou(b) >= iou_threshold,
+            (None, None) => self.normalized_text() == other.normalized_text(),
+            _ => false,
+        }
+    }
+}
+
+/// Merge the results of all handlers into one.
+///
+/// Items from different handlers are grouped by how much their boxes overlap
+/// (or by text if there are no boxes). Each group then votes on the text,
+/// each vote weighted by the confidence of the handler that cast it.
+fn merge(
+    results: &BTreeMap<String, EnsembleHandlerResult>,
+    options: &MergeOptions,
+) -> Vec<EnsembleItem> {
+    let succeeded = results
+        .values()
+        .filter(|x| matches!(x.result, OcrResult::Data(_)))
+        .count();
+
+    let mut candidates = results
+        .iter()
+        .filter_map(|(handler, result)| match &result.result {
+            OcrResult::Data(items) => Some(items.iter().map(|item| Candidate { handler, item })),
+            OcrResult::Error(_) => None,
+        })
+        .flatten()
+        .collect::<Vec<_>>();
+
+    candidates.sort_by(|a, b| b.confidence().total_cmp(&a.confidence()));
+
+    // The first candidate of each group has the highest confidence and is what others are compared against
+    let mut groups = Vec::<Vec<Candidate>>::new();
+    for candidate in candidates {
+        let group = groups.iter_mut().find(|group| {
+            group[0].overlaps(&candidate, options.iou_threshold)
+                && group.iter().all(|x| x.handler != candidate.handler)
+        });
+
+        match group {
+            Some(group) => group.push(candidate),
+            None => groups.push(vec![candidate]),
+        }
+    }
+
+    let mut merged = groups
+        .into_iter()
+        .filter(|group| group.len() >= options.min_votes)
+        .map(|group| {
+            let mut scores = BTreeMap::<String, f64>::new();
+            for candidate in &group {
+                *scores.entry(candidate.normalized_text()).or_default() += candidate.confidence();
+            }
+
+            let text = scores
+                .into_iter()
+                .max_by(|a, b| a.1.total_cmp(&b.1))
+                .map(|(text, _)| text)
+                .unwrap_or_default();
+
+            let agreeing = group
+                .iter()
+                .filter(|x| x.normalized_text() == text)
+                .collect::<Vec<_>>();
+
+            #[allow(clippy::cast_precision_loss)]
+            EnsembleItem {
+                item: agreeing[0].item.clone(),
+                handlers: agreeing.iter().map(|x| x.handler.to_string()).collect(),
+                votes: group.len(),
+                agreement: agreeing.len() as f64 / succeeded.max(1) as f64,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    // Reading order, with items without a box at the end
+    merged.sort_by_key(|x| {
+        x.item.text_box.map_or((true, 0, 0), |b| {
+            let (left, top, _, _) = b.bounds();
+            (false, top, left)
+        })
+    });
+
+    merged
+}
@@ -0,0 +1,187 @@
+use axum::{
+    body::{Body, Bytes},
+    http::{HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use rand::prelude::*;
+use reqwest::{Method, StatusCode};
+use tracing::{debug, warn};
+
+use super::{
+    hedge, priority,
+    queue::EndpointQueue,
+    result::{OcrResponse, OcrResult},
+    HANDLER_HEADER,
+};
+use crate::{
+    config::{Config, VirtualHandlerStrategy},
+    endpoint_watcher::{routing::Route, EndpointWatcher},
+    tenants,
+};
+
+/// The handlers to try for a request, in order, starting with the requested one.
+///
+/// Virtual handlers are expanded to the real handlers they map to.
+/// `fallback` is the list supplied by the client, which replaces the configured chain.
+pub fn handler_chain(handler: &str, fallback: Option<&str>) -> Vec<String> {
+    let fallbacks = fallback.map_or_else(
+        || Config::global().handler_fallbacks(handler),
+        |fallback| {
+            fallback
+                .split([',', ' '])
+                .map(str::trim)
+                .filter(|x| !x.is_empty())
+                .map(ToString::to_string)
+                .collect()
+        },
+    );
+
+    let handlers = Config::global().virtual_handler(handler).map_or_else(
+        || vec![handler.to_string()],
+        |virtual_handler| {
+            let mut handlers = virtual_handler.handlers.clone();
+            if virtual_handler.strategy == VirtualHandlerStrategy::Random {
+                handlers.shuffle(&mut rand::thread_rng());
+            }
+            handlers
+        },
+    );
+
+    let mut chain = Vec::<String>::new();
+    for handler in handlers.into_iter().chain(fallbacks) {
+        if !chain.contains(&handler) {
+            chain.push(handler);
+        }
+    }
+
+    chain
+}
+
+/// A live route for each handler in the chain that has one for the tenant.
+pub fn routes(handler: &str, fallback: Option<&str>, tenant: Option<&str>) -> Vec<(String, Route)> {
+    let routing_table = EndpointWatcher::global().routing_table();
+
+    handler_chain(handler, fallback)
+        .into_iter()
+        .filter_map(|handler| {
+            let route = routing_table
+                .routes(&handler, tenant)
+                .choose(&mut rand::thread_rng())
+                .cloned()?;
+
+            Some((handler, route))
+        })
+        .collect()
+}
+
+/// Whether the response means the next handler in the chain should be tried.
+///
+/// Only the endpoint failing or not having the handler count: errors about the upload itself
+/// (eg. an unreadable image) would fail the same way with every handler.
+pub fn should_fall_back(status: StatusCode) -> bool {
+    status.is_server_error() || status == StatusCode::NOT_FOUND
+}
+
+/// Whether the response is an error rather than an OCR result.
+pub fn is_failed_response(status: StatusCode, body: &[u8]) -> bool {
+    if status.is_server_error() || status == StatusCode::NOT_FOUND {
+        return true;
+    }
+
+    serde_json::from_slice::<OcrResponse>(body)
+        .is_ok_and(|response| matches!(response.result, OcrResult::Error(_)))
+}
+
+/// Send the request to each route in turn until one of them succeeds.
+///
+/// The response of the last route is returned even if it failed.
+#[tracing::instrument(skip_all, fields(handlers = ?routes.iter().map(|x| &x.0).collect::<Vec<_>>()))]
+pub async fn proxy(
+    routes: Vec<(String, Route)>,
+    method: Method,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let last = routes.len().saturating_sub(1);
+    let mut last_error = None;
+    let mut busy = None;
+    let priority = priority::from_headers(&headers);
+    let tenant = tenants::from_headers(&headers);
+    // One deadline for the whole chain, so falling back doesn't restart the wait
+    let deadline = EndpointQueue::deadline();
+
+    for (i, (handler, route)) in routes.into_iter().enumerate() {
+        let acquired = EndpointQueue::global()
+            .acquire(&handler, tenant, route, priority, deadline)
+            .await;
+        let (route, in_flight) = match acquired {
+            Ok(acquired) => acquired,
+            Err(e) => {
+                warn!(?handler, "Endpoints are busy, trying next handler");
+                busy = Some(e);
+                continue;
+            }
+        };
+
+        debug!(?handler, endpoint = ?route.endpoint.id, "Trying handler");
+
+        let hedged = hedge::send(
+            &handler,
+            route,
+            in_flight,
+            method.clone(),
+            &headers,
+            body.clone(),
+        )
+        .await;
+        let _in_flight = hedged.in_flight;
+
+        let endpoint_response = match hedged.response {
+            Ok(endpoint_response) => endpoint_response,
+            Err(e) => {
+                warn!(?handler, error = ?e, "Failed to proxy request, trying next handler");
+                last_error = Some(e);
+                continue;
+            }
+        };
+
+        let status = endpoint_response.status();
+        let mut response_headers = endpoint_response.headers().clone();
+        let response_body = match endpoint_response.bytes().await {
+            Ok(response_body) => response_body,
+            Err(e) => {
+                warn!(?handler, error = ?e, "Failed to read response, trying next handler");
+                last_error = Some(e);
+                continue;
+            }
+        };
+
+        if i != last && should_fall_back(status) {
+            warn!(?handler, ?status, "Handler failed, trying next handler");
+            continue;
+        }
+
+        if let Ok(handler) = HeaderValue::from_str(&handler) {
+            response_headers.insert(HANDLER_HEADER, handler);
+        }
+
+        let mut response = Response::builder().status(status);
+        *response.headers_mut().expect("Failed to get headers") = response_headers;
+
+        return response
+            .body(Body::from(response_body))
+            .expect("Failed to build response");
+    }
+
+    match (last_error, busy) {
+        (None, Some(busy)) => busy.into_response(),
+        (last_error, _) => {
+            warn!(error = ?last_error, "Failed to proxy request to any handler");
+            (
+                StatusCode::BAD_GATEWAY,
+                "Failed to proxy request".to_string(),
+            )
+                .into_response()
+        }
+    }
+}
@@ -0,0 +1,66 @@
+use axum::{
+    body::Bytes,
+    http::{HeaderMap, HeaderValue},
+};
+use reqwest::Method;
+
+use crate::{
+    endpoint_watcher::{routing::Route, Endpoint},
+    helpers::id::time_rand_id,
+    hops,
+};
+
+/// The client request headers, adjusted to be sent on to the endpoint.
+pub fn forward_headers(headers: &HeaderMap, endpoint: &Endpoint) -> HeaderMap {
+    let mut headers = headers.clone();
+
+    headers.insert(
+        "Host",
+        HeaderValue::from_str(endpoint.url.host_str().unwrap_or_default()).expect("Invalid host"),
+    );
+    headers.insert(hops::HOPS_HEADER, hops::forwarded(&headers));
+
+    headers
+}
+
+/// Send an already buffered request to the route.
+///
+/// Used when the same request body has to be sent to more than one endpoint.
+pub async fn send_buffered(
+    route: &Route,
+    method: Method,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> reqwest::Result<reqwest::Response> {
+    reqwest::Client::new()
+        .request(method, route.handler_url.clone())
+        .headers(forward_headers(headers, &route.endpoint))
+        .body(body)
+        .send()
+        .await
+}
+
+/// Encode a single file as a `multipart/form-data` body with a `file` field,
+/// the way clients upload it to the OCR APIs.
+///
+/// Returns the content type (with the boundary) and the body.
+pub fn multipart_file(file_name: &str, content_type: Option<&str>, data: &[u8]) -> (String, Bytes) {
+    let boundary = format!("ocr-api-{}", time_rand_id());
+    let file_name = file_name.replace(['"', '\r', '\n'], "_");
+
+    let mut body = format!(
+        "--{boundary}\r\nContent-Disposition: form-data; name=\"file\"; filename=\"{file_name}\"\r\n"
+    )
+    .into_bytes();
+    if let Some(content_type) = content_type {
+        body.extend_from_slice(format!("Content-Type: {content_type}\r\n").as_bytes());
+    }
+    body.extend_from_slice(b"\r\n");
+    body.extend_from_slice(data);
+    body.extend_from_slice(format!("\r\n--{boundary}--\r\n").as_bytes());
+
+    (
+        format!("multipart/form-data; boundary={boundary}"),
+        body.into(),
+    )
+}
@@ -0,0 +1,216 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use axum::{body::Bytes, http::HeaderMap};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use reqwest::Method;
+use tracing::{debug, trace};
+
+use super::{forward::send_buffered, priority, queue::EndpointQueue};
+use crate::{
+    config::Config,
+    endpoint_watcher::{routing::Route, EndpointWatcher},
+    helpers::in_flight::InFlightGuard,
+    tenants,
+};
+
+static HEDGING: Lazy<Hedging> = Lazy::new(Hedging::default);
+
+/// How many recent response times to keep per handler.
+const LATENCY_SAMPLES: usize = 500;
+/// How many response times are needed before the percentile is used for the hedge delay.
+const MIN_LATENCY_SAMPLES: usize = 20;
+/// How many unused hedges can be saved up.
+const MAX_BUDGET: f64 = 10.0;
+
+/// Keeps track of response times and the hedge budget.
+#[derive(Debug, Default)]
+pub struct Hedging {
+    latencies: Mutex<HashMap<String, VecDeque<Duration>>>,
+    budget: Mutex<f64>,
+}
+
+impl Hedging {
+    pub fn global() -> &'static Self {
+        &HEDGING
+    }
+
+    pub fn is_enabled(handler: &str) -> bool {
+        Config::global().hedging.is_enabled_for(handler)
+    }
+
+    fn record_latency(&self, handler: &str, latency: Duration) {
+        let mut latencies = self.latencies.lock();
+        let samples = latencies.entry(handler.to_string()).or_default();
+
+        if samples.len() >= LATENCY_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(latency);
+
+        drop(latencies);
+    }
+
+    /// How long to wait for the first endpoint before sending the request to another one.
+    pub fn delay(&self, handler: &str) -> Duration {
+        let config = &Config::global().hedging;
+
+        let mut latencies = match self.latencies.lock().get(handler) {
+            Some(latencies) if latencies.len() >= MIN_LATENCY_SAMPLES => {
+                latencies.iter().copied().collect::<Vec<_>>()
+            }
+            _ => return config.hedge_default_delay.into(),
+        };
+        latencies.sort_unstable();
+
+        #[allow(
+            clippy::cast_possible_truncation,
+            clippy::cast_precision_loss,
+            clippy::cast_sign_loss
+        )]
+        let idx = ((latencies.len() - 1) as f64 * config.hedge_percentile.clamp(0.0, 100.0) / 100.0)
+            .round() as usize;
+
+        latencies[idx].max(config.hedge_min_delay.into())
+    }
+
+    /// Every request earns a fraction of a hedge.
+    fn deposit(&self) {
+        let mut budget = self.budget.lock();
+        *budget = (*budget + Config::global().hedging.hedge_budget).min(MAX_BUDGET);
+    }
+
+    fn try_withdraw(&self) -> bool {
+        let mut budget = self.budget.lock();
+
+        if *budget < 1.0 {
+            return false;
+        }
+
+        *budget -= 1.0;
+        true
+    }
+}
+
+/// The response of whichever endpoint answered a (possibly hedged) request.
+#[derive(Debug)]
+pub struct Hedged {
+    pub response: reqwest::Result<reqwest::Response>,
+    /// Keeps the request counted as in flight on the endpoint that answered.
+    pub in_flight: InFlightGuard,
+}
+
+async fn send_one(
+    handler: &str,
+    route: Route,
+    in_flight: InFlightGuard,
+    method: Method,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Hedged {
+    let started = Instant::now();
+
+    let response = send_buffered(&route, method, headers, body).await;
+
+    if response.is_ok() {
+        Hedging::global().record_latency(handler, started.elapsed());
+    }
+
+    Hedged {
+        response,
+        in_flight,
+    }
+}
+
+/// Send the request to the route, and if hedging is enabled for the handler and it is slow
+/// to respond, to another endpoint supporting the handler with free capacity as well.
+///
+/// Whichever responds first (successfully) wins, and the other request is cancelled.
+#[tracing::instrument(skip(route, in_flight, method, headers, body), fields(endpoint = ?route.endpoint.id))]
+pub async fn send(
+    handler: &str,
+    route: Route,
+    in_flight: InFlightGuard,
+    method: Method,
+    headers: &HeaderMap,
+    body: Bytes,
+) -> Hedged {
+    if !Hedging::is_enabled(handler) {
+        return send_one(handler, route, in_flight, method, headers, body).await;
+    }
+
+    let hedging = Hedging::global();
+    hedging.deposit();
+
+    let delay = hedging.delay(handler);
+    trace!(?delay, "Hedge delay");
+
+    let primary_endpoint = route.endpoint.id.clone();
+    let primary_started = Instant::now();
+    let primary = send_one(
+        handler,
+        route,
+        in_flight,
+        method.clone(),
+        headers,
+        body.clone(),
+    );
+    tokio::pin!(primary);
+
+    #[allow(clippy::redundant_pub_crate)]
+    {
+        tokio::select! {
+            hedged = &mut primary => return hedged,
+            () = tokio::time::sleep(delay) => {}
+        }
+    }
+
+    let routing_table = EndpointWatcher::global().routing_table();
+    let secondaries = routing_table
+        .routes(handler, tenants::from_headers(headers))
+        .iter()
+        .filter(|x| x.endpoint.id != primary_endpoint);
+
+    // Hedges are only worth it if they don't have to wait for capacity
+    let priority = priority::from_headers(headers);
+    let (secondary, secondary_in_flight) = match EndpointQueue::try_acquire(secondaries, priority) {
+        Some(secondary) if hedging.try_withdraw() => secondary,
+        _ => return primary.await,
+    };
+
+    debug!(secondary = ?secondary.endpoint.id, "Hedging request");
+
+    let secondary = send_one(
+        handler,
+        secondary,
+        secondary_in_flight,
+        method,
+        headers,
+        body,
+    );
+    tokio::pin!(secondary);
+
+    #[allow(clippy::redundant_pub_crate)]
+    {
+        tokio::select! {
+            hedged = &mut primary => {
+                if hedged.response.is_ok() {
+                    return hedged;
+                }
+                secondary.await
+            }
+            hedged = &mut secondary => {
+                if hedged.response.is_ok() {
+                    // The primary is cancelled, but took at least this long. Leaving it out
+                    // would make the delay shrink every time a hedge wins.
+                    hedging.record_latency(handler, primary_started.elapsed());
+                    return hedged;
+                }
+                primary.await
+            }
+        }
+    }
+}
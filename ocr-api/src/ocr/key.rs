@@ -0,0 +1,133 @@
+use axum::{
+    body::Bytes,
+    http::{header, HeaderMap},
+};
+use reqwest::Method;
+use sha2::{Digest, Sha256};
+
+use crate::{config::Config, tenants};
+
+/// Hash what determines the result of an OCR request.
+///
+/// The body is hashed as it was received. For multipart uploads the boundary is left out,
+/// as clients pick a new one for every request, so the same upload twice gets the same key.
+///
+/// `None` if nothing needs the key.
+pub fn request_key(
+    handler: &str,
+    fallback: Option<&str>,
+    method: &Method,
+    headers: &HeaderMap,
+    body: &Bytes,
+) -> Option<String> {
+    let config = &Config::global().cache;
+    if !config.is_enabled() && !config.coalesce_requests {
+        return None;
+    }
+
+    let mut hasher = Sha256::new();
+    let mut update = |part: &[u8]| {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    };
+
+    update(method.as_str().as_bytes());
+    update(handler.as_bytes());
+    update(fallback.unwrap_or_default().as_bytes());
+    // Tenants may have their own endpoints, which can give different results
+    update(
+        tenants::from_headers(headers)
+            .unwrap_or_default()
+            .as_bytes(),
+    );
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|x| x.to_str().ok())
+        .unwrap_or_default();
+    let hashed_content_type =
+        multipart_boundary(content_type).map_or(content_type, |_| "multipart/form-data");
+
+    update(hashed_content_type.as_bytes());
+
+    match multipart_boundary(content_type) {
+        Some(boundary) => {
+            let delimiter = format!("--{boundary}");
+            for part in split(body, delimiter.as_bytes()) {
+                update(part);
+            }
+        }
+        None => update(body),
+    }
+
+    Some(hex::encode(hasher.finalize()))
+}
+
+/// The boundary parameter of a `multipart/form-data` content type.
+fn multipart_boundary(content_type: &str) -> Option<&str> {
+    let (mime, params) = content_type.split_once(';')?;
+    if !mime.trim().eq_ignore_ascii_case("multipart/form-data") {
+        return None;
+    }
+
+    params
+        .split(';')
+        .filter_map(|x| x.split_once('='))
+        .find(|(name, _)| name.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, value)| value.trim().trim_matches('"'))
+        .filter(|x| !x.is_empty())
+}
+
+/// The parts of `data` between occurrences of `delimiter`.
+fn split<'a>(data: &'a [u8], delimiter: &'a [u8]) -> impl Iterator<Item = &'a [u8]> + 'a {
+    let mut rest = Some(data);
+
+    std::iter::from_fn(move || {
+        let data = rest?;
+
+        match data.windows(delimiter.len()).position(|x| x == delimiter) {
+            Some(at) => {
+                rest = Some(&data[at + delimiter.len()..]);
+                Some(&data[..at])
+            }
+            None => {
+                rest = None;
+                Some(data)
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_multipart_boundary() {
+        assert_eq!(
+            multipart_boundary("multipart/form-data; boundary=abc123"),
+            Some("abc123")
+        );
+        assert_eq!(
+            multipart_boundary("Multipart/Form-Data; charset=utf-8; boundary=\"x y\""),
+            Some("x y")
+        );
+        assert_eq!(multipart_boundary("multipart/form-data"), None);
+        assert_eq!(multipart_boundary("image/png; boundary=abc"), None);
+    }
+
+    #[test]
+    fn splits_on_delimiter() {
+        let parts = split(b"--b\r\nfirst\r\n--b\r\nsecond\r\n--b--", b"--b").collect::<Vec<_>>();
+
+        assert_eq!(
+            parts,
+            [&b""[..], b"\r\nfirst\r\n", b"\r\nsecond\r\n", b"--"]
+        );
+    }
+
+    #[test]
+    fn split_without_delimiter_is_whole() {
+        assert_eq!(split(b"data", b"--b").collect::<Vec<_>>(), [&b"data"[..]]);
+    }
+}
@@ -0,0 +1,15 @@
+pub mod batch;
+pub mod coalesce;
+pub mod dispatch;
+pub mod ensemble;
+pub mod fallback;
+pub mod forward;
+pub mod hedge;
+pub mod key;
+pub mod priority;
+pub mod queue;
+pub mod result;
+pub mod shadow;
+
+/// Response header with the name of the handler that actually served the request.
+pub const HANDLER_HEADER: &str = "x-ocr-handler";
@@ -0,0 +1,17 @@
+use axum::http::HeaderMap;
+
+use crate::config::{Config, Priority};
+
+/// Request header with the priority of the request.
+///
+/// Set by the gateway to the resolved priority, so it is passed on to chained gateways as well.
+pub const PRIORITY_HEADER: &str = "x-ocr-priority";
+
+/// The priority of a request, from its header or the configured default.
+pub fn from_headers(headers: &HeaderMap) -> Priority {
+    headers
+        .get(PRIORITY_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse().ok())
+        .unwrap_or_else(|| Config::global().priority.default_priority)
+}
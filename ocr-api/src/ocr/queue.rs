@@ -0,0 +1,507 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    time::Duration,
+};
+
+use axum::{
+    http::header,
+    response::{IntoResponse, Response},
+};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rand::prelude::*;
+use reqwest::StatusCode;
+use tokio::{sync::Notify, time::Instant};
+use tracing::{debug, trace};
+
+use crate::{
+    config::{Config, Priority},
+    endpoint_watcher::{routing::Route, EndpointWatcher},
+    helpers::in_flight::InFlightGuard,
+};
+
+static ENDPOINT_QUEUE: Lazy<EndpointQueue> = Lazy::new(EndpointQueue::default);
+
+/// Requests waiting for an endpoint with free capacity.
+///
+/// Waiting requests are let through highest priority first,
+/// and in the order they arrived within a priority.
+#[derive(Debug, Default)]
+pub struct EndpointQueue {
+    waiting: AtomicUsize,
+    /// Tickets of the waiting requests in the order they arrived, by handler, tenant and priority.
+    by_priority: Mutex<HashMap<WaitingKey, VecDeque<u64>>>,
+    next_ticket: AtomicU64,
+    /// Notified when a request stops waiting, so lower priority ones can check if it's their turn.
+    left: Notify,
+}
+
+/// No endpoint supporting the handler had free capacity in time.
+#[derive(Debug)]
+pub struct EndpointsBusy;
+
+impl IntoResponse for EndpointsBusy {
+    fn into_response(self) -> Response {
+        let retry_after = Duration::from(Config::global().endpoint_queue_timeout)
+            .as_secs()
+            .max(1);
+
+        (
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::RETRY_AFTER, retry_after.to_string())],
+            "All endpoints supporting that handler are busy",
+        )
+            .into_response()
+    }
+}
+
+type WaitingKey = (String, Option<String>, Priority);
+
+/// Counts a request as waiting until dropped.
+struct Waiting<'a> {
+    queue: &'a EndpointQueue,
+    key: WaitingKey,
+    ticket: u64,
+}
+
+impl Drop for Waiting<'_> {
+    fn drop(&mut self) {
+        let mut by_priority = self.queue.by_priority.lock();
+        if let Some(tickets) = by_priority.get_mut(&self.key) {
+            tickets.retain(|x| *x != self.ticket);
+            if tickets.is_empty() {
+                by_priority.remove(&self.key);
+            }
+        }
+        drop(by_priority);
+
+        self.queue.waiting.fetch_sub(1, Ordering::AcqRel);
+        self.queue.left.notify_waiters();
+    }
+}
+
+impl EndpointQueue {
+    pub fn global() -> &'static Self {
+        &ENDPOINT_QUEUE
+    }
+
+    /// Any of the routes whose endpoint has free capacity for the priority, in random order.
+    pub fn try_acquire<'a, I>(routes: I, priority: Priority) -> Option<(Route, InFlightGuard)>
+    where
+        I: IntoIterator<Item = &'a Route>,
+    {
+        let mut routes = routes.into_iter().collect::<Vec<_>>();
+        routes.shuffle(&mut rand::thread_rng());
+
+        routes.into_iter().find_map(|route| {
+            route
+                .endpoint
+                .try_track_request(priority)
+                .map(|in_flight| (route.clone(), in_flight))
+        })
+    }
+
+    /// Whether requests of the tenant for the handler with a matching priority are waiting.
+    fn has_waiting<F>(&self, handler: &str, tenant: Option<&str>, filter: F) -> bool
+    where
+        F: Fn(Priority) -> bool,
+    {
+        self.by_priority
+            .lock()
+            .keys()
+            .any(|(x, x_tenant, priority)| {
+                x == handler && x_tenant.as_deref() == tenant && filter(*priority)
+            })
+    }
+
+    /// Count a request as waiting, if the queue has room for its priority.
+    fn enter(
+        &self,
+        handler: &str,
+        tenant: Option<&str>,
+        priority: Priority,
+    ) -> Option<Waiting<'_>> {
+        let config = Config::global();
+        let size = config
+            .priority
+            .available(config.endpoint_queue_size, priority);
+
+        self.waiting
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |waiting| {
+                (waiting < size).then_some(waiting + 1)
+            })
+            .ok()?;
+
+        let key = (
+            handler.to_string(),
+            tenant.map(ToString::to_string),
+            priority,
+        );
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        self.by_priority
+            .lock()
+            .entry(key.clone())
+            .or_default()
+            .push_back(ticket);
+
+        Some(Waiting {
+            queue: self,
+            key,
+            ticket,
+        })
+    }
+
+    /// Whether the request arrived before all others waiting with the same priority.
+    fn is_next(&self, waiting: &Waiting<'_>) -> bool {
+        self.by_priority
+            .lock()
+            .get(&waiting.key)
+            .and_then(VecDeque::front)
+            == Some(&waiting.ticket)
+    }
+
+    /// When a request arriving now has to give up waiting for an endpoint.
+    ///
+    /// Shared by all the handlers a request falls back to.
+    pub fn deadline() -> Instant {
+        Instant::now() + Duration::from(Config::global().endpoint_queue_timeout)
+    }
+
+    /// Reserve a request on the route, or on another endpoint supporting the handler for the tenant
+    /// if it is at capacity.
+    ///
+    /// If all of them are, or requests with the same or higher priority are already waiting,
+    /// waits in the queue until it's this request's turn or the deadline has passed.
+    #[tracing::instrument(skip(self, route, deadline), fields(endpoint = ?route.endpoint.id))]
+    pub async fn acquire(
+        &self,
+        handler: &str,
+        tenant: Option<&str>,
+        route: Route,
+        priority: Priority,
+        deadline: Instant,
+    ) -> Result<(Route, InFlightGuard), EndpointsBusy> {
+        let routes = || {
+            EndpointWatcher::global()
+                .routing_table()
+                .routes(handler, tenant)
+                .to_vec()
+        };
+
+        self.acquire_from(handler, tenant, route, priority, deadline, routes)
+            .await
+    }
+
+    /// Like [`Self::acquire`], with the routes supporting the handler for the tenant coming from `routes`.
+    async fn acquire_from<F>(
+        &self,
+        handler: &str,
+        tenant: Option<&str>,
+        route: Route,
+        priority: Priority,
+        deadline: Instant,
+        routes: F,
+    ) -> Result<(Route, InFlightGuard), EndpointsBusy>
+    where
+        F: Fn() -> Vec<Route>,
+    {
+        if !self.has_waiting(handler, tenant, |x| x >= priority) {
+            if let Some(in_flight) = route.endpoint.try_track_request(priority) {
+                return Ok((route, in_flight));
+            }
+
+            if let Some(acquired) = Self::try_acquire(&routes(), priority) {
+                return Ok(acquired);
+            }
+        }
+
+        if Instant::now() >= deadline {
+            debug!("No time left to wait for an endpoint with capacity");
+            return Err(EndpointsBusy);
+        }
+
+        let waiting = self.enter(handler, tenant, priority).ok_or_else(|| {
+            debug!("Endpoint queue is full");
+            EndpointsBusy
+        })?;
+
+        debug!("All endpoints are at capacity, waiting");
+
+        loop {
+            let routes = routes();
+
+            if routes.is_empty() {
+                debug!("No endpoints supporting the handler are left");
+                return Err(EndpointsBusy);
+            }
+
+            // Subscribed before checking, so changes in between aren't missed
+            let left = self.left.notified();
+            tokio::pin!(left);
+            left.as_mut().enable();
+            let finished = routes
+                .iter()
+                .map(|x| x.endpoint.request_finished())
+                .collect::<Vec<_>>();
+
+            // Higher priority requests go first, then the ones that have waited longest
+            if !self.has_waiting(handler, tenant, |x| x > priority) && self.is_next(&waiting) {
+                if let Some(acquired) = Self::try_acquire(&routes, priority) {
+                    trace!(endpoint = ?acquired.0.endpoint.id, "Endpoint has capacity again");
+                    return Ok(acquired);
+                }
+            }
+
+            let changed = futures::future::select(left, futures::future::select_all(finished));
+            if tokio::time::timeout_at(deadline, changed).await.is_err() {
+                debug!("Timed out waiting for an endpoint with capacity");
+                return Err(EndpointsBusy);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::sync::Arc;
+
+    use url::Url;
+
+    use super::*;
+    use crate::endpoint_watcher::Endpoint;
+
+    type Order = Arc<Mutex<Vec<&'static str>>>;
+
+    /// A queue of its own for each test, living as long as the tasks waiting in it.
+    fn queue() -> &'static EndpointQueue {
+        Box::leak(Box::default())
+    }
+
+    fn route(max_concurrency: usize) -> Route {
+        let mut endpoint = Endpoint::new(Url::parse("http://127.0.0.1:8000").unwrap());
+        endpoint.max_concurrency = Some(max_concurrency);
+
+        Route {
+            handler_url: endpoint.url.join("/ocr/test").unwrap(),
+            endpoint,
+        }
+    }
+
+    fn only(route: &Route) -> impl Fn() -> Vec<Route> {
+        let route = route.clone();
+        move || vec![route.clone()]
+    }
+
+    fn in_a_while() -> Instant {
+        Instant::now() + Duration::from_secs(5)
+    }
+
+    /// Wait until as many requests are in the queue.
+    async fn until_waiting(queue: &EndpointQueue, waiting: usize) {
+        while queue.waiting.load(Ordering::Acquire) != waiting {
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+    }
+
+    /// Queue a request, recording its name once it gets the endpoint.
+    async fn spawn_waiting(
+        queue: &'static EndpointQueue,
+        route: &Route,
+        priority: Priority,
+        name: &'static str,
+        order: &Order,
+    ) -> tokio::task::JoinHandle<()> {
+        let waiting = queue.waiting.load(Ordering::Acquire);
+        let route = route.clone();
+        let order = order.clone();
+
+        let task = tokio::spawn(async move {
+            let routes = only(&route);
+            let (_, in_flight) = queue
+                .acquire_from("test", None, route, priority, in_a_while(), routes)
+                .await
+                .unwrap();
+
+            order.lock().push(name);
+            drop(in_flight);
+        });
+
+        // So the next request arrives after this one
+        until_waiting(queue, waiting + 1).await;
+
+        task
+    }
+
+    #[tokio::test]
+    async fn waits_in_arrival_order() {
+        let queue = queue();
+        let route = route(1);
+        let busy = route.endpoint.try_track_request(Priority::Normal).unwrap();
+        let order = Order::default();
+
+        let mut tasks = Vec::new();
+        for name in ["first", "second", "third"] {
+            tasks.push(spawn_waiting(queue, &route, Priority::Normal, name, &order).await);
+        }
+
+        drop(busy);
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(*order.lock(), ["first", "second", "third"]);
+        assert_eq!(queue.waiting.load(Ordering::Acquire), 0);
+        assert!(queue.by_priority.lock().is_empty());
+    }
+
+    #[tokio::test]
+    async fn lets_higher_priority_through_first() {
+        let queue = queue();
+        let route = route(1);
+        let busy = route.endpoint.try_track_request(Priority::Normal).unwrap();
+        let order = Order::default();
+
+        let tasks = [
+            spawn_waiting(queue, &route, Priority::Low, "low", &order).await,
+            spawn_waiting(queue, &route, Priority::Normal, "normal", &order).await,
+            spawn_waiting(queue, &route, Priority::High, "high", &order).await,
+        ];
+
+        drop(busy);
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(*order.lock(), ["high", "normal", "low"]);
+    }
+
+    #[tokio::test]
+    async fn doesnt_skip_waiting_requests() {
+        let queue = queue();
+        let route = route(1);
+        let busy = route.endpoint.try_track_request(Priority::Normal).unwrap();
+        let order = Order::default();
+
+        let waiting = spawn_waiting(queue, &route, Priority::Normal, "waiting", &order).await;
+
+        // Arrives when the endpoint is free again, but before the waiting request got to it
+        drop(busy);
+        let (_, in_flight) = queue
+            .acquire_from(
+                "test",
+                None,
+                route.clone(),
+                Priority::Normal,
+                in_a_while(),
+                only(&route),
+            )
+            .await
+            .unwrap();
+        order.lock().push("arrived");
+        drop(in_flight);
+
+        waiting.await.unwrap();
+        assert_eq!(*order.lock(), ["waiting", "arrived"]);
+    }
+
+    #[tokio::test]
+    async fn gives_up_at_deadline() {
+        let queue = queue();
+        let route = route(1);
+        let busy = route.endpoint.try_track_request(Priority::Normal).unwrap();
+
+        let started = Instant::now();
+        let deadline = started + Duration::from_millis(50);
+        let acquired = queue
+            .acquire_from(
+                "test",
+                None,
+                route.clone(),
+                Priority::Normal,
+                deadline,
+                only(&route),
+            )
+            .await;
+
+        assert!(acquired.is_err());
+        assert!(started.elapsed() >= Duration::from_millis(50));
+        assert_eq!(queue.waiting.load(Ordering::Acquire), 0);
+
+        // A deadline used up by earlier handlers in a fallback chain doesn't wait again
+        let started = Instant::now();
+        let acquired = queue
+            .acquire_from(
+                "test",
+                None,
+                route.clone(),
+                Priority::Normal,
+                deadline,
+                only(&route),
+            )
+            .await;
+
+        assert!(acquired.is_err());
+        assert!(started.elapsed() < Duration::from_millis(50));
+
+        // But can still take free capacity
+        drop(busy);
+        let acquired = queue
+            .acquire_from(
+                "test",
+                None,
+                route.clone(),
+                Priority::Normal,
+                deadline,
+                only(&route),
+            )
+            .await;
+
+        assert!(acquired.is_ok());
+    }
+
+    #[tokio::test]
+    async fn refuses_when_queue_is_full() {
+        let queue = queue();
+        let route = route(1);
+        let _busy = route.endpoint.try_track_request(Priority::Normal).unwrap();
+
+        let config = Config::global();
+        let size = config
+            .priority
+            .available(config.endpoint_queue_size, Priority::Normal);
+        let _waiting = (0..size)
+            .map(|_| queue.enter("test", None, Priority::Normal).unwrap())
+            .collect::<Vec<_>>();
+
+        // The rest of the queue is reserved for high priority requests
+        assert!(queue.enter("test", None, Priority::Normal).is_none());
+        assert!(queue.enter("test", None, Priority::High).is_some());
+
+        let started = Instant::now();
+        let busy = queue
+            .acquire_from(
+                "test",
+                None,
+                route.clone(),
+                Priority::Normal,
+                in_a_while(),
+                only(&route),
+            )
+            .await
+            .unwrap_err();
+        assert!(started.elapsed() < Duration::from_secs(1));
+
+        let response = busy.into_response();
+        let retry_after = Duration::from(config.endpoint_queue_timeout)
+            .as_secs()
+            .max(1);
+
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(
+            response.headers().get(header::RETRY_AFTER).unwrap(),
+            retry_after.to_string().as_str()
+        );
+    }
+}
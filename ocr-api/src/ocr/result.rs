@@ -0,0 +1,78 @@
+use serde::{Deserialize, Serialize};
+
+/// The response body of an OCR request as returned by the endpoints.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OcrResponse {
+    pub engine: String,
+    #[serde(flatten)]
+    pub result: OcrResult,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum OcrResult {
+    Error(String),
+    Data(Vec<OcrTextItem>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OcrTextItem {
+    pub text: String,
+    #[serde(rename = "box", default, skip_serializing_if = "Option::is_none")]
+    pub text_box: Option<CoordBox>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub confidence: Option<f64>,
+    /// Any other fields the handler returned, passed through as-is.
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, serde_json::Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CoordBox {
+    pub tl: Point,
+    pub tr: Point,
+    pub br: Point,
+    pub bl: Point,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Point {
+    pub x: i64,
+    pub y: i64,
+}
+
+impl CoordBox {
+    /// The axis-aligned rectangle containing the box, as `(left, top, right, bottom)`.
+    pub fn bounds(&self) -> (i64, i64, i64, i64) {
+        let points = [self.tl, self.tr, self.br, self.bl];
+
+        (
+            points.iter().map(|p| p.x).min().unwrap_or_default(),
+            points.iter().map(|p| p.y).min().unwrap_or_default(),
+            points.iter().map(|p| p.x).max().unwrap_or_default(),
+            points.iter().map(|p| p.y).max().unwrap_or_default(),
+        )
+    }
+
+    /// Intersection over union of the bounding rectangles of the two boxes.
+    ///
+    /// Computed in floating point, as the coordinates come from endpoints and may be too large to multiply.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn iou(&self, other: &Self) -> f64 {
+        let bounds = |x: &Self| {
+            let (l, t, r, b) = x.bounds();
+            (l as f64, t as f64, r as f64, b as f64)
+        };
+        let (l1, t1, r1, b1) = bounds(self);
+        let (l2, t2, r2, b2) = bounds(other);
+
+        let intersection = (r1.min(r2) - l1.max(l2)).max(0.0) * (b1.min(b2) - t1.max(t2)).max(0.0);
+        let union = (r1 - l1).mul_add(b1 - t1, (r2 - l2) * (b2 - t2)) - intersection;
+
+        if union <= 0.0 || !union.is_finite() {
+            return 0.0;
+        }
+
+        intersection / union
+    }
+}
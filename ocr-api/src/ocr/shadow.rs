@@ -0,0 +1,353 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    time::{Duration, Instant},
+};
+
+use axum::{
+    body::{Body, Bytes},
+    http::HeaderMap,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use rand::prelude::*;
+use reqwest::{Method, StatusCode};
+use serde::Serialize;
+use tracing::{debug, trace, warn};
+
+use super::{
+    forward::send_buffered,
+    queue::EndpointQueue,
+    result::{OcrResponse, OcrResult},
+    HANDLER_HEADER,
+};
+use crate::{
+    config::{Config, Priority},
+    endpoint_watcher::{endpoint::EndpointId, EndpointWatcher},
+    router::MAX_BODY_SIZE,
+};
+
+static SHADOW_REPORT: Lazy<ShadowReport> = Lazy::new(ShadowReport::default);
+
+/// Whether to mirror this request to shadow endpoints.
+pub fn should_mirror<'a, I>(handlers: I) -> bool
+where
+    I: IntoIterator<Item = &'a String>,
+{
+    let routing_table = EndpointWatcher::global().routing_table();
+
+    let has_shadow_routes = handlers
+        .into_iter()
+        .any(|handler| !routing_table.shadow_routes(handler).is_empty());
+
+    has_shadow_routes
+        && rand::thread_rng().gen_range(0.0..100.0) < Config::global().shadow.shadow_traffic_percent
+}
+
+/// Send a copy of the request to a shadow endpoint of the handler that served `response`
+/// and compare the two responses in the background.
+///
+/// The live response is passed through unchanged.
+pub async fn mirror(
+    response: Response,
+    latency: Duration,
+    method: Method,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let handler = response
+        .headers()
+        .get(HANDLER_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .map(ToString::to_string);
+
+    let handler = match handler {
+        Some(handler) => handler,
+        None => return response,
+    };
+
+    // Low priority and never queued, so mirroring doesn't take capacity from live requests
+    let acquired = EndpointQueue::try_acquire(
+        EndpointWatcher::global()
+            .routing_table()
+            .shadow_routes(&handler),
+        Priority::Low,
+    );
+
+    let (route, in_flight) = match acquired {
+        Some(acquired) => acquired,
+        None => {
+            debug!(
+                ?handler,
+                "Shadow endpoints are busy or missing, not mirroring request"
+            );
+            return response;
+        }
+    };
+
+    let (parts, response_body) = response.into_parts();
+    let response_body = match axum::body::to_bytes(response_body, MAX_BODY_SIZE).await {
+        Ok(response_body) => response_body,
+        Err(e) => {
+            return (
+                StatusCode::BAD_GATEWAY,
+                format!("Failed to read response: {:?}", e),
+            )
+                .into_response()
+        }
+    };
+
+    let live_status = parts.status;
+    let live_body = response_body.clone();
+
+    tokio::spawn(async move {
+        debug!(?handler, endpoint = ?route.endpoint.id, "Mirroring request to shadow endpoint");
+
+        let _in_flight = in_flight;
+        let started = Instant::now();
+
+        let shadow_response = match send_buffered(&route, method, &headers, body).await {
+            Ok(shadow_response) => {
+                let status = shadow_response.status();
+                shadow_response
+                    .bytes()
+                    .await
+                    .map(|body| (status, body, started.elapsed()))
+            }
+            Err(e) => Err(e),
+        };
+
+        let comparison = ShadowComparison::new(
+            handler,
+            route.endpoint.id.clone(),
+            (live_status, &live_body, latency),
+            shadow_response
+                .as_ref()
+                .map(|(status, body, latency)| (*status, body, *latency)),
+        );
+        trace!(?comparison, "Shadow comparison");
+
+        ShadowReport::global().record(comparison);
+    });
+
+    Response::from_parts(parts, Body::from(response_body))
+}
+
+/// The outcome of mirroring one request to a shadow endpoint.
+#[derive(Debug, Clone, Serialize)]
+pub struct ShadowComparison {
+    pub at: DateTime<Utc>,
+    pub handler: String,
+    pub shadow_endpoint_id: EndpointId,
+    pub live_status: u16,
+    pub shadow_status: Option<u16>,
+    pub status_match: bool,
+    /// How much slower the shadow endpoint was.
+    pub latency_delta_ms: Option<f64>,
+    /// Share of words the two results have in common, from 0 to 1.
+    pub text_similarity: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl ShadowComparison {
+    fn new(
+        handler: String,
+        shadow_endpoint_id: EndpointId,
+        live: (StatusCode, &Bytes, Duration),
+        shadow: Result<(StatusCode, &Bytes, Duration), &reqwest::Error>,
+    ) -> Self {
+        let (live_status, live_body, live_latency) = live;
+
+        let mut comparison = Self {
+            at: Utc::now(),
+            handler,
+            shadow_endpoint_id,
+            live_status: live_status.as_u16(),
+            shadow_status: None,
+            status_match: false,
+            latency_delta_ms: None,
+            text_similarity: None,
+            error: None,
+        };
+
+        let (shadow_status, shadow_body, shadow_latency) = match shadow {
+            Ok(shadow) => shadow,
+            Err(e) => {
+                warn!(error = ?e, "Shadow request failed");
+                comparison.error = Some(format!("Failed to send request: {e}"));
+                return comparison;
+            }
+        };
+
+        comparison.shadow_status = Some(shadow_status.as_u16());
+        comparison.status_match = live_status == shadow_status;
+        comparison.latency_delta_ms =
+            Some((shadow_latency.as_secs_f64() - live_latency.as_secs_f64()) * 1000.0);
+        comparison.text_similarity = match (result_text(live_body), result_text(shadow_body)) {
+            (Some(live), Some(shadow)) => Some(text_similarity(&live, &shadow)),
+            _ => None,
+        };
+
+        comparison
+    }
+}
+
+/// All the text in a successful OCR response.
+fn result_text(body: &[u8]) -> Option<String> {
+    match serde_json::from_slice::<OcrResponse>(body).ok()?.result {
+        OcrResult::Data(items) => Some(
+            items
+                .into_iter()
+                .map(|x| x.text)
+                .collect::<Vec<_>>()
+                .join(" "),
+        ),
+        OcrResult::Error(_) => None,
+    }
+}
+
+/// Dice coefficient of the words in the two texts, ignoring order.
+#[allow(clippy::cast_precision_loss)]
+fn text_similarity(a: &str, b: &str) -> f64 {
+    let mut words = HashMap::<&str, (usize, usize)>::new();
+    for word in a.split_whitespace() {
+        words.entry(word).or_default().0 += 1;
+    }
+    for word in b.split_whitespace() {
+        words.entry(word).or_default().1 += 1;
+    }
+
+    let total = words.values().map(|(a, b)| a + b).sum::<usize>();
+    if total == 0 {
+        return 1.0;
+    }
+
+    let common = words.values().map(|(a, b)| a.min(b)).sum::<usize>();
+
+    (2 * common) as f64 / total as f64
+}
+
+#[derive(Debug, Default)]
+struct ShadowStatsAccumulator {
+    requests: usize,
+    errors: usize,
+    status_matches: usize,
+    latency_delta_ms_sum: f64,
+    latency_deltas: usize,
+    text_similarity_sum: f64,
+    text_similarities: usize,
+}
+
+impl ShadowStatsAccumulator {
+    fn add(&mut self, comparison: &ShadowComparison) {
+        self.requests += 1;
+        if comparison.error.is_some() {
+            self.errors += 1;
+        }
+        if comparison.status_match {
+            self.status_matches += 1;
+        }
+        if let Some(latency_delta_ms) = comparison.latency_delta_ms {
+            self.latency_delta_ms_sum += latency_delta_ms;
+            self.latency_deltas += 1;
+        }
+        if let Some(text_similarity) = comparison.text_similarity {
+            self.text_similarity_sum += text_similarity;
+            self.text_similarities += 1;
+        }
+    }
+}
+
+/// Summary of all comparisons for a handler on a shadow endpoint.
+#[derive(Debug, Clone, Serialize)]
+pub struct ShadowStats {
+    pub handler: String,
+    pub shadow_endpoint_id: EndpointId,
+    pub requests: usize,
+    pub errors: usize,
+    pub status_match_percent: Option<f64>,
+    pub mean_latency_delta_ms: Option<f64>,
+    pub mean_text_similarity: Option<f64>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ShadowReportSummary {
+    pub stats: Vec<ShadowStats>,
+    pub recent: Vec<ShadowComparison>,
+}
+
+/// Comparison stats of mirrored requests, for the admin report.
+#[derive(Debug, Default)]
+pub struct ShadowReport {
+    stats: Mutex<HashMap<(String, EndpointId), ShadowStatsAccumulator>>,
+    recent: Mutex<VecDeque<ShadowComparison>>,
+}
+
+impl ShadowReport {
+    pub fn global() -> &'static Self {
+        &SHADOW_REPORT
+    }
+
+    fn record(&self, comparison: ShadowComparison) {
+        self.stats
+            .lock()
+            .entry((
+                comparison.handler.clone(),
+                comparison.shadow_endpoint_id.clone(),
+            ))
+            .or_default()
+            .add(&comparison);
+
+        let max_recent = Config::global().shadow.shadow_report_size;
+        if max_recent == 0 {
+            return;
+        }
+
+        let mut recent = self.recent.lock();
+        if recent.len() >= max_recent {
+            recent.pop_front();
+        }
+        recent.push_back(comparison);
+    }
+
+    /// The stats per handler and shadow endpoint, with the most recent comparisons newest first.
+    #[allow(clippy::cast_precision_loss)]
+    pub fn summary(&self, limit: usize) -> ShadowReportSummary {
+        let mut stats = self
+            .stats
+            .lock()
+            .iter()
+            .map(|((handler, shadow_endpoint_id), stats)| ShadowStats {
+                handler: handler.clone(),
+                shadow_endpoint_id: shadow_endpoint_id.clone(),
+                requests: stats.requests,
+                errors: stats.errors,
+                status_match_percent: (stats.requests > 0)
+                    .then(|| stats.status_matches as f64 / stats.requests as f64 * 100.0),
+                mean_latency_delta_ms: (stats.latency_deltas > 0)
+                    .then(|| stats.latency_delta_ms_sum / stats.latency_deltas as f64),
+                mean_text_similarity: (stats.text_similarities > 0)
+                    .then(|| stats.text_similarity_sum / stats.text_similarities as f64),
+            })
+            .collect::<Vec<_>>();
+        stats.sort_by_cached_key(|x| (x.handler.clone(), x.shadow_endpoint_id.to_string()));
+
+        let recent = self
+            .recent
+            .lock()
+            .iter()
+            .rev()
+            .take(limit)
+            .cloned()
+            .collect();
+
+        ShadowReportSummary { stats, recent }
+    }
+
+    pub fn reset(&self) {
+        self.stats.lock().clear();
+        self.recent.lock().clear();
+    }
+}
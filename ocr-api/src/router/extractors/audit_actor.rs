@@ -0,0 +1,72 @@
+use std::{
+    convert::Infallible,
+    net::{IpAddr, SocketAddr},
+};
+
+use axum::{
+    async_trait,
+    extract::{ConnectInfo, FromRequestParts},
+    http::{request::Parts, HeaderMap},
+};
+use tower_http::request_id::RequestId;
+
+use crate::{audit_log::AuditActor, config::Config, router::middleware::auth::AuthData};
+
+/// The address of the client, following `X-Forwarded-For` only as far back as trusted proxies added it.
+fn client_ip(peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    let trusted = &Config::global().trusted_proxies;
+
+    let forwarded = headers
+        .get_all("x-forwarded-for")
+        .iter()
+        .filter_map(|x| x.to_str().ok())
+        .flat_map(|x| x.split(','))
+        .map(str::trim)
+        .collect::<Vec<_>>();
+
+    let mut ip = peer;
+    for hop in forwarded.into_iter().rev() {
+        if !trusted.contains(&ip) {
+            break;
+        }
+
+        match hop.parse() {
+            Ok(hop) => ip = hop,
+            Err(_) => break,
+        }
+    }
+
+    ip
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for AuditActor
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let auth_data = parts.extensions.get::<AuthData>();
+        let key_name = auth_data.map(|x| x.key_name.clone());
+        let tenant = auth_data.and_then(|x| x.tenant.clone());
+
+        let source_ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|x| client_ip(x.0.ip(), &parts.headers).to_string());
+
+        let request_id = parts
+            .extensions
+            .get::<RequestId>()
+            .and_then(|id| id.header_value().to_str().ok())
+            .map(ToString::to_string);
+
+        Ok(Self {
+            key_name,
+            tenant,
+            source_ip,
+            request_id,
+        })
+    }
+}
@@ -1,6 +1,6 @@
 use axum::{
     extract::Request,
-    http::{header, StatusCode},
+    http::{header, HeaderMap, StatusCode},
     middleware::Next,
     response::{IntoResponse, Response},
 };
@@ -12,23 +12,17 @@ pub const AUTH_HEADER: &str = "x-api-key";
 pub const AUTH_COOKIE: &str = "api-key";
 
 #[derive(Debug, Clone)]
-pub struct AuthData;
-
-pub async fn parse_auth_header(mut request: Request, next: Next) -> Result<Response, Response> {
-    if request.extensions().get::<AuthData>().is_some() {
-        return Ok(next.run(request).await);
-    };
+pub struct AuthData {
+    pub key_name: String,
+    /// The tenant the key belongs to, unset for the gateway's own keys.
+    pub tenant: Option<String>,
+}
 
-    let auth_value = None
-        .or_else(|| {
-            request
-                .headers()
-                .get(AUTH_HEADER)
-                .and_then(|x| x.to_str().ok())
-        })
+/// The API key the client sent, if any.
+fn auth_value(headers: &HeaderMap) -> Option<&str> {
+    None.or_else(|| headers.get(AUTH_HEADER).and_then(|x| x.to_str().ok()))
         .or_else(|| {
-            request
-                .headers()
+            headers
                 .get(header::AUTHORIZATION)
                 .and_then(|x| x.to_str().ok())
                 .and_then(|h| {
@@ -42,8 +36,7 @@ pub async fn parse_auth_header(mut request: Request, next: Next) -> Result<Respo
                 })
         })
         .or_else(|| {
-            request
-                .headers()
+            headers
                 .get(header::COOKIE)
                 .and_then(|x| x.to_str().ok())
                 .and_then(|h| {
@@ -52,17 +45,39 @@ pub async fn parse_auth_header(mut request: Request, next: Next) -> Result<Respo
                         .find(|(k, _)| k.to_lowercase() == AUTH_COOKIE)
                         .map(|(_, v)| v)
                 })
-        });
+        })
+}
+
+fn auth_data_for(auth_value: &str) -> Option<AuthData> {
+    Config::global()
+        .api_keys()
+        .filter(|(_, _, key)| constant_time_eq(auth_value.as_bytes(), key.as_bytes()))
+        .map(|(tenant, name, _)| AuthData {
+            key_name: name.to_string(),
+            tenant: tenant.map(|x| x.name.clone()),
+        })
+        .last()
+}
+
+/// The valid API key the request was made with, without rejecting invalid ones.
+pub fn auth_data(headers: &HeaderMap) -> Option<AuthData> {
+    auth_value(headers).and_then(auth_data_for)
+}
 
-    if let Some(auth_value) = auth_value {
-        if !constant_time_eq(
-            auth_value.as_bytes(),
-            Config::global().auth.api_auth_key.as_bytes(),
-        ) {
-            return Err((StatusCode::UNAUTHORIZED, "Invalid credentials").into_response());
-        }
+pub async fn parse_auth_header(mut request: Request, next: Next) -> Result<Response, Response> {
+    if request.extensions().get::<AuthData>().is_some() {
+        return Ok(next.run(request).await);
+    }
+
+    if let Some(auth_value) = auth_value(request.headers()) {
+        let auth_data = match auth_data_for(auth_value) {
+            Some(auth_data) => auth_data,
+            None => {
+                return Err((StatusCode::UNAUTHORIZED, "Invalid credentials").into_response());
+            }
+        };
 
-        request.extensions_mut().insert(AuthData);
+        request.extensions_mut().insert(auth_data);
     }
 
     let response = next.run(request).await;
@@ -81,3 +96,19 @@ pub async fn require_auth(request: Request, next: Next) -> Result<Response, Resp
 
     Ok(response)
 }
+
+/// Only lets through requests made with one of the gateway's own keys, not a tenant's.
+pub async fn require_gateway_key(request: Request, next: Next) -> Result<Response, Response> {
+    let is_tenant = request
+        .extensions()
+        .get::<AuthData>()
+        .is_some_and(|x| x.tenant.is_some());
+
+    if is_tenant {
+        return Err((StatusCode::FORBIDDEN, "Not available to tenant API keys").into_response());
+    }
+
+    let response = next.run(request).await;
+
+    Ok(response)
+}
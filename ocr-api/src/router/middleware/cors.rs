@@ -0,0 +1,67 @@
+use std::time::Duration;
+
+use axum::http::{header, HeaderName, HeaderValue, Method};
+use tower_http::cors::{AllowHeaders, AllowMethods, AllowOrigin, CorsLayer};
+
+use crate::{cache::CACHE_HEADER, config::CorsPolicy, ocr::HANDLER_HEADER};
+
+/// The CORS layer for a group of routes, if browsers may call them from other origins at all.
+///
+/// Browsers may also read the response headers the gateway sets itself.
+pub fn layer(policy: CorsPolicy<'_>) -> Option<CorsLayer> {
+    if policy.allowed_origins.is_empty() {
+        return None;
+    }
+
+    let is_any = |values: &[String]| values.iter().any(|x| x == "*");
+
+    let allowed_origins = if is_any(policy.allowed_origins) {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            policy
+                .allowed_origins
+                .iter()
+                .filter_map(|x| HeaderValue::from_str(x).ok()),
+        )
+    };
+
+    // Mirrored rather than `*`, as that isn't allowed along with credentials
+    let allowed_methods = if is_any(policy.allowed_methods) {
+        AllowMethods::mirror_request()
+    } else {
+        AllowMethods::list(
+            policy
+                .allowed_methods
+                .iter()
+                .filter_map(|x| Method::from_bytes(x.as_bytes()).ok()),
+        )
+    };
+
+    let allowed_headers = if is_any(policy.allowed_headers) {
+        AllowHeaders::mirror_request()
+    } else {
+        AllowHeaders::list(
+            policy
+                .allowed_headers
+                .iter()
+                .filter_map(|x| HeaderName::from_bytes(x.as_bytes()).ok()),
+        )
+    };
+
+    Some(
+        CorsLayer::new()
+            .allow_origin(allowed_origins)
+            .allow_methods(allowed_methods)
+            .allow_headers(allowed_headers)
+            .allow_credentials(policy.allow_credentials)
+            .max_age(Duration::from(policy.max_age))
+            .expose_headers([
+                HeaderName::from_static("x-request-id"),
+                HeaderName::from_static(HANDLER_HEADER),
+                HeaderName::from_static(CACHE_HEADER),
+                header::RETRY_AFTER,
+                header::LOCATION,
+            ]),
+    )
+}
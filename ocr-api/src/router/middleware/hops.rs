@@ -0,0 +1,33 @@
+use axum::{
+    extract::Request,
+    http::StatusCode,
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::warn;
+
+use crate::{config::Config, hops};
+
+/// Refuses requests that already passed through this gateway or through too many gateways.
+pub async fn check_hops(request: Request, next: Next) -> Result<Response, Response> {
+    let config = Config::global();
+    let hops = hops::from_headers(request.headers());
+
+    if hops.contains(&config.gateway_id) {
+        warn!(?hops, "Request loop detected");
+
+        return Err((StatusCode::LOOP_DETECTED, "Request loop detected").into_response());
+    }
+
+    if hops.len() >= config.gateway_max_hops {
+        warn!(?hops, "Request passed through too many gateways");
+
+        return Err((
+            StatusCode::LOOP_DETECTED,
+            "Request passed through too many gateways",
+        )
+            .into_response());
+    }
+
+    Ok(next.run(request).await)
+}
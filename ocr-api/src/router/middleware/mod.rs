@@ -1 +1,6 @@
 pub mod auth;
+pub mod cors;
+pub mod hops;
+pub mod priority;
+pub mod shutdown;
+pub mod tenant;
@@ -0,0 +1,50 @@
+use axum::{
+    extract::Request,
+    http::{HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use tracing::trace;
+
+use super::auth::{self, AuthData};
+use crate::{
+    config::{Config, Priority},
+    ocr::priority::PRIORITY_HEADER,
+};
+
+/// Resolves the priority of the request and sets it as its `X-Ocr-Priority` header.
+///
+/// The request gets the priority of the API key it used, or the configured default.
+/// The header the client sent can only lower that, so callers can't take the capacity reserved for
+/// high priority requests unless their key is allowed to.
+pub async fn resolve_priority(mut request: Request, next: Next) -> Result<Response, Response> {
+    let config = &Config::global().priority;
+
+    let requested = request
+        .headers()
+        .get(PRIORITY_HEADER)
+        .map(|x| {
+            x.to_str()
+                .map_err(|e| e.to_string())
+                .and_then(str::parse::<Priority>)
+        })
+        .transpose()
+        .map_err(|e| (StatusCode::BAD_REQUEST, e).into_response())?;
+
+    let allowed = request
+        .extensions()
+        .get::<AuthData>()
+        .map(|x| x.key_name.clone())
+        .or_else(|| auth::auth_data(request.headers()).map(|x| x.key_name))
+        .and_then(|key_name| config.key_priority(&key_name))
+        .unwrap_or(config.default_priority);
+
+    let priority = requested.map_or(allowed, |x| x.min(allowed));
+    trace!(?priority, ?requested, "Resolved request priority");
+
+    request
+        .headers_mut()
+        .insert(PRIORITY_HEADER, HeaderValue::from_static(priority.as_str()));
+
+    Ok(next.run(request).await)
+}
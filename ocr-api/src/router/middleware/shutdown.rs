@@ -0,0 +1,37 @@
+use axum::{
+    body::Body,
+    extract::Request,
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures::StreamExt;
+
+use crate::shutdown::Shutdown;
+
+/// Refuses new requests once shutdown has started and keeps track of the ones in flight.
+pub async fn track_in_flight(request: Request, next: Next) -> Result<Response, Response> {
+    let shutdown = Shutdown::global();
+
+    if shutdown.is_started() {
+        return Err((
+            StatusCode::SERVICE_UNAVAILABLE,
+            [(header::CONNECTION, "close")],
+            "Server is shutting down",
+        )
+            .into_response());
+    }
+
+    let in_flight = shutdown.track_request();
+
+    let response = next.run(request).await;
+
+    // The request is in flight until the whole response has been sent
+    let (parts, body) = response.into_parts();
+    let body = body.into_data_stream().map(move |chunk| {
+        let _in_flight = &in_flight;
+        chunk
+    });
+
+    Ok(Response::from_parts(parts, Body::from_stream(body)))
+}
@@ -0,0 +1,112 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    body::Body,
+    extract::{Path, Request},
+    http::{header, HeaderValue},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use futures::StreamExt;
+use parking_lot::Mutex;
+use tracing::{debug, trace};
+
+use super::auth;
+use crate::{
+    config::Config,
+    tenants::{
+        self,
+        usage::{QuotaExceeded, UsageOutcome},
+        TenantUsage, TENANT_HEADER,
+    },
+};
+
+/// Sets the `X-Ocr-Tenant` header to the tenant of the API key the request was made with.
+///
+/// Whatever the client sent in it is removed, so requests can't be routed to another tenant's endpoints.
+pub async fn resolve_tenant(mut request: Request, next: Next) -> Response {
+    let tenant = auth::auth_data(request.headers()).and_then(|x| x.tenant);
+    trace!(?tenant, "Resolved request tenant");
+
+    let headers = request.headers_mut();
+    headers.remove(TENANT_HEADER);
+    if let Some(value) = tenant.and_then(|x| HeaderValue::from_str(&x).ok()) {
+        headers.insert(TENANT_HEADER, value);
+    }
+
+    next.run(request).await
+}
+
+/// Counts OCR requests towards the quota of their tenant, refusing them once it is used up.
+///
+/// The upload is counted as it is read, so it is cut off once it goes over the quota,
+/// whatever size the client claimed it has.
+/// Handlers that count more than one request, like batches, report how they ended with a [`UsageOutcome`].
+pub async fn track_usage(
+    path: Option<Path<HashMap<String, String>>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let tenant = match tenants::from_headers(request.headers())
+        .and_then(|x| Config::global().tenants.tenant(x))
+    {
+        Some(tenant) => tenant,
+        None => return next.run(request).await,
+    };
+
+    let handler = path
+        .as_ref()
+        .and_then(|Path(params)| params.get("handler"))
+        .map_or("ensemble", String::as_str);
+    let expected_upload_bytes = request
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|x| x.to_str().ok())
+        .and_then(|x| x.parse().ok())
+        .unwrap_or_default();
+
+    if let Err(e) = TenantUsage::global().start(tenant, handler, 1, expected_upload_bytes) {
+        debug!(tenant = ?tenant.name, "Tenant is over its quota");
+        return e.into_response();
+    }
+
+    let exceeded = Arc::new(Mutex::new(None::<QuotaExceeded>));
+    let request = request.map(|body| {
+        let exceeded = exceeded.clone();
+
+        Body::from_stream(body.into_data_stream().map(move |chunk| {
+            let chunk = chunk?;
+            if let Err(e) = TenantUsage::global().add_upload(tenant, chunk.len() as u64) {
+                *exceeded.lock() = Some(e);
+                return Err(axum::Error::new("Upload quota exceeded"));
+            }
+
+            Ok(chunk)
+        }))
+    });
+
+    let response = next.run(request).await;
+
+    // The handler only saw a broken upload, so tell the client why
+    let over_quota = exceeded.lock().take();
+    let response = over_quota.map_or(response, |e| {
+        debug!(tenant = ?tenant.name, "Tenant went over its upload quota");
+        e.into_response()
+    });
+
+    let outcome = response
+        .extensions()
+        .get::<UsageOutcome>()
+        .copied()
+        .unwrap_or_else(|| {
+            let succeeded = response.status().is_success();
+
+            UsageOutcome {
+                succeeded: succeeded.into(),
+                failed: (!succeeded).into(),
+            }
+        });
+    TenantUsage::global().finish(&tenant.name, outcome);
+
+    response
+}
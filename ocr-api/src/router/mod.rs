@@ -1,3 +1,4 @@
+mod extractors;
 mod middleware;
 mod routes;
 
@@ -5,6 +6,7 @@ use std::time::Duration;
 
 use axum::{
     extract::DefaultBodyLimit,
+    handler::Handler,
     http::{HeaderValue, Request, Response},
     routing::{delete, get, post},
     Router,
@@ -19,7 +21,10 @@ use tower_http::{
 };
 use tracing::{debug, field, info, Span};
 
-use crate::helpers::id::time_thread_id;
+use crate::{
+    config::{Config, CorsPolicy},
+    helpers::id::time_thread_id,
+};
 
 pub const MAX_BODY_SIZE: usize = {
     const KB: usize = 1024;
@@ -29,35 +34,22 @@ pub const MAX_BODY_SIZE: usize = {
 };
 
 pub fn create_router() -> Router {
-    Router::new()
-        .route("/", get(routes::get_root))
-        .route("/endpoints", get(routes::get_endpoints_public))
-        .route(
-            "/endpoints/supporting/:handler",
-            get(routes::get_endpoints_supporting_handler_public),
-        )
-        .route(
-            "/ocr/:handler",
-            get(routes::get_endpoint_supporting_handler_public)
-                .post(routes::any_endpoint_proxy_handler),
-        )
-        .nest(
-            "/admin",
-            Router::new()
-                .route(
-                    "/endpoints",
-                    get(routes::get_endpoints)
-                        .post(routes::any_add_endpoint)
-                        .put(routes::any_add_endpoint),
-                )
-                .route("/endpoints/:id", delete(routes::delete_remove_endpoint))
-                .route("/endpoints/:id/disable", post(routes::any_disable_endpoint))
-                .route("/endpoints/:id/enable", post(routes::any_enable_endpoint))
-                .layer(axum::middleware::from_fn(middleware::auth::require_auth))
-                .layer(axum::middleware::from_fn(
-                    middleware::auth::parse_auth_header,
-                )),
-        )
+    let config = Config::global();
+
+    with_cors(public_router(), config.cors.public())
+        .nest("/admin", with_cors(admin_router(), config.cors.admin()))
+        .layer(axum::middleware::from_fn(
+            middleware::priority::resolve_priority,
+        ))
+        .layer(axum::middleware::from_fn(
+            middleware::tenant::resolve_tenant,
+        ))
+        .layer(axum::middleware::from_fn(middleware::hops::check_hops))
+        .layer(axum::middleware::from_fn(
+            middleware::shutdown::track_in_flight,
+        ))
+        .route("/healthz", get(routes::get_healthz))
+        .route("/readyz", get(routes::get_readyz))
         .layer(CatchPanicLayer::new())
         .layer(DefaultBodyLimit::max(MAX_BODY_SIZE))
         .layer(
@@ -115,11 +107,124 @@ pub fn create_router() -> Router {
                             );
                         }),
                 )
-                .layer(TimeoutLayer::new(Duration::from_secs(300)))
                 .layer(PropagateRequestIdLayer::x_request_id()),
         )
 }
 
+/// Adds the CORS policy to the routes, if browsers may call them from other origins at all.
+///
+/// Goes outside of the auth layers, as preflight requests don't have credentials.
+fn with_cors(router: Router, policy: CorsPolicy<'_>) -> Router {
+    match middleware::cors::layer(policy) {
+        Some(cors) => router.layer(cors),
+        None => router,
+    }
+}
+
+/// Routes for clients, as opposed to the admin ones.
+fn public_router() -> Router {
+    Router::new()
+        .route("/", get(routes::get_root))
+        .route("/endpoints", get(routes::get_endpoints_public))
+        .route(
+            "/endpoints/supporting/:handler",
+            get(routes::get_endpoints_supporting_handler_public),
+        )
+        // Not under `/ocr/`, where it would shadow a handler called `ensemble`
+        .route(
+            "/ensemble",
+            post(
+                routes::post_ocr_ensemble
+                    .layer(axum::middleware::from_fn(middleware::tenant::track_usage)),
+            ),
+        )
+        .route(
+            "/ocr/:handler",
+            get(routes::get_endpoint_supporting_handler_public).post(
+                routes::any_endpoint_proxy_handler
+                    .layer(axum::middleware::from_fn(middleware::tenant::track_usage)),
+            ),
+        )
+        .route(
+            "/jobs/:handler",
+            post(
+                routes::post_job.layer(axum::middleware::from_fn(middleware::tenant::track_usage)),
+            )
+            .layer(axum::middleware::from_fn(
+                middleware::auth::parse_auth_header,
+            )),
+        )
+        .route(
+            "/jobs/id/:id",
+            get(routes::get_job)
+                .delete(routes::delete_job)
+                .layer(axum::middleware::from_fn(
+                    middleware::auth::parse_auth_header,
+                )),
+        )
+        .layer(TimeoutLayer::new(Duration::from_secs(300)))
+        // Batches can take much longer than single requests
+        .route(
+            "/ocr/:handler/batch",
+            post(
+                routes::post_ocr_batch
+                    .layer(axum::middleware::from_fn(middleware::tenant::track_usage)),
+            )
+            .layer(TimeoutLayer::new(
+                Config::global().batch.batch_timeout.into(),
+            )),
+        )
+}
+
+fn admin_router() -> Router {
+    Router::new()
+        .route(
+            "/endpoints",
+            get(routes::get_endpoints)
+                .post(routes::any_add_endpoint)
+                .put(routes::any_add_endpoint),
+        )
+        .route("/endpoints/:id", delete(routes::delete_remove_endpoint))
+        .route("/endpoints/:id/disable", post(routes::any_disable_endpoint))
+        .route("/endpoints/:id/enable", post(routes::any_enable_endpoint))
+        .route("/endpoints/:id/drain", post(routes::any_drain_endpoint))
+        .route("/endpoints/:id/undrain", post(routes::any_undrain_endpoint))
+        .route("/endpoints/:id/history", get(routes::get_endpoint_history))
+        .route("/endpoints/:id/check", post(routes::any_check_endpoint))
+        .route("/events", get(routes::get_events))
+        .route("/audit", get(routes::get_audit_log))
+        .route("/tenants", get(routes::get_tenants))
+        .route("/tenants/:name", get(routes::get_tenant))
+        // Shadow endpoints and the cache are shared by all tenants, so only the gateway's own keys manage them
+        .route(
+            "/shadow",
+            get(routes::get_shadow_report)
+                .delete(routes::delete_shadow_report)
+                .layer(axum::middleware::from_fn(
+                    middleware::auth::require_gateway_key,
+                )),
+        )
+        .route(
+            "/cache",
+            get(routes::get_cache)
+                .delete(routes::delete_cache)
+                .layer(axum::middleware::from_fn(
+                    middleware::auth::require_gateway_key,
+                )),
+        )
+        .route(
+            "/cache/:key",
+            delete(routes::delete_cache_entry).layer(axum::middleware::from_fn(
+                middleware::auth::require_gateway_key,
+            )),
+        )
+        .layer(axum::middleware::from_fn(middleware::auth::require_auth))
+        .layer(axum::middleware::from_fn(
+            middleware::auth::parse_auth_header,
+        ))
+        .layer(TimeoutLayer::new(Duration::from_secs(300)))
+}
+
 #[derive(Clone)]
 struct AppMakeRequestId;
 impl MakeRequestId for AppMakeRequestId {
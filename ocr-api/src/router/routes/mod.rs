@@ -1,20 +1,111 @@
+use std::time::{Duration, Instant};
+
 use axum::{
-    body::Body,
-    extract::Path,
-    http::{HeaderMap, HeaderValue},
-    response::{IntoResponse, Response},
-    Json,
+    body::{Body, Bytes},
+    extract::{Multipart, Path, Query},
+    http::{header, HeaderMap, HeaderValue},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Extension, Json,
 };
+use futures::StreamExt;
+use ocr_common::timeframe::{Timeframe, TimeframeParseError};
 use rand::prelude::*;
 use reqwest::{Method, StatusCode};
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::error::RecvError;
 use tracing::{debug, trace};
 use url::Url;
 
-use crate::endpoint_watcher::{endpoint::EndpointId, Endpoint, EndpointWatcher};
+use crate::{
+    audit_log::{log::AuditFilter, AuditAction, AuditActor, AuditEntry, AuditLog, AuditTarget},
+    cache::{CacheLookup, ResultCache},
+    config::{parse_timeframes, Config, Tenant, VirtualHandlerStrategy},
+    endpoint_watcher::{
+        endpoint::{EndpointId, EndpointInfo},
+        Endpoint, EndpointWatcher,
+    },
+    hops,
+    jobs::{Job, JobCallback, JobQueue, JobRemoval},
+    ocr::{
+        batch,
+        coalesce::Coalescer,
+        dispatch,
+        ensemble::{self, QueryEnsemble},
+        fallback,
+        forward::forward_headers,
+        hedge::Hedging,
+        key::request_key,
+        priority,
+        queue::EndpointQueue,
+        shadow::{self, ShadowReport},
+        HANDLER_HEADER,
+    },
+    router::{middleware::auth::AuthData, MAX_BODY_SIZE},
+    shutdown::Shutdown,
+    tenants::{
+        self,
+        usage::{UsageOutcome, UsageReport},
+        TenantUsage,
+    },
+};
+
+/// Metadata in the same shape the OCR APIs return, so gateways can be chained.
+///
+/// Handlers only available through gateways the request already passed through are left out.
+pub async fn get_root(headers: HeaderMap) -> impl IntoResponse {
+    let hops = hops::from_headers(&headers);
+    let tenant = tenants::from_headers(&headers);
+    let routing_table = EndpointWatcher::global().routing_table();
+
+    let available_handlers = routing_table
+        .handlers(tenant)
+        .filter(|handler| {
+            routing_table.routes(handler, tenant).iter().any(|route| {
+                route
+                    .endpoint
+                    .gateway_id()
+                    .map_or(true, |id| !hops.contains(&id))
+            })
+        })
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+
+    let mut available_handlers = EndpointPublic::public_handlers(&available_handlers);
+    available_handlers.sort_unstable();
+
+    Json(EndpointInfo {
+        available_handlers,
+        handler_template: "/ocr/{handler_name}".to_string(),
+        gateway_id: Some(Config::global().gateway_id.clone()),
+        // Requests are queued here until the endpoints behind have capacity
+        max_concurrency: None,
+    })
+}
+
+pub async fn get_healthz() -> impl IntoResponse {
+    "OK"
+}
+
+pub async fn get_readyz() -> impl IntoResponse {
+    if Shutdown::global().is_started() {
+        return (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(serde_json::json!({
+                "ready": false,
+                "reason": "Server is shutting down",
+            })),
+        );
+    }
 
-pub async fn get_root() -> impl IntoResponse {
-    Json("OCR API Gateway".to_string())
+    (
+        StatusCode::OK,
+        Json(serde_json::json!({
+            "ready": true,
+        })),
+    )
 }
 
 #[derive(Debug, Serialize)]
@@ -23,12 +114,29 @@ pub struct EndpointPublic {
     pub available_handlers: Vec<String>,
 }
 
+impl EndpointPublic {
+    /// The handlers of the endpoint, along with the virtual handlers it can serve.
+    fn public_handlers(available_handlers: &[String]) -> Vec<String> {
+        let mut handlers = available_handlers.to_vec();
+
+        for virtual_handler in &Config::global().virtual_handlers {
+            if virtual_handler.is_served_by(available_handlers)
+                && !handlers.contains(&virtual_handler.name)
+            {
+                handlers.push(virtual_handler.name.clone());
+            }
+        }
+
+        handlers
+    }
+}
+
 impl TryFrom<Endpoint> for EndpointPublic {
     type Error = String;
 
     fn try_from(endpoint: Endpoint) -> Result<Self, Self::Error> {
         let available_handlers = match (*endpoint.status.read()).info() {
-            Some(info) => info.available_handlers.clone(),
+            Some(info) => Self::public_handlers(&info.available_handlers),
             None => return Err("No info available".to_string()),
         };
 
@@ -44,7 +152,7 @@ impl TryFrom<&Endpoint> for EndpointPublic {
 
     fn try_from(endpoint: &Endpoint) -> Result<Self, Self::Error> {
         let available_handlers = match (*endpoint.status.read()).info() {
-            Some(info) => info.available_handlers.clone(),
+            Some(info) => Self::public_handlers(&info.available_handlers),
             None => return Err("No info available".to_string()),
         };
 
@@ -55,13 +163,14 @@ impl TryFrom<&Endpoint> for EndpointPublic {
     }
 }
 
-pub async fn get_endpoints_public() -> impl IntoResponse {
+pub async fn get_endpoints_public(headers: HeaderMap) -> impl IntoResponse {
+    let tenant = tenants::from_headers(&headers);
+
     let endpoints = EndpointWatcher::global()
         .endpoints()
-        .await
-        .into_iter()
+        .iter()
         .filter_map(|endpoint| {
-            if endpoint.disabled() {
+            if endpoint.disabled() || endpoint.shadow || !endpoint.serves_tenant(tenant) {
                 return None;
             }
 
@@ -74,10 +183,10 @@ pub async fn get_endpoints_public() -> impl IntoResponse {
 
 pub async fn get_endpoints_supporting_handler_public(
     Path(handler): Path<String>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
     let endpoints = EndpointWatcher::global()
-        .endpoints_supporting_handler(&handler)
-        .await
+        .endpoints_supporting_handler(&handler, tenants::from_headers(&headers))
         .into_iter()
         .flat_map(EndpointPublic::try_from)
         .collect::<Vec<_>>();
@@ -87,12 +196,10 @@ pub async fn get_endpoints_supporting_handler_public(
 
 pub async fn get_endpoint_supporting_handler_public(
     Path(handler): Path<String>,
+    headers: HeaderMap,
 ) -> impl IntoResponse {
-    let endpoints = EndpointWatcher::global()
-        .endpoints_supporting_handler(&handler)
-        .await;
-
-    let endpoint = endpoints
+    let endpoint = EndpointWatcher::global()
+        .endpoints_supporting_handler(&handler, tenants::from_headers(&headers))
         .choose(&mut rand::thread_rng())
         .and_then(|x| EndpointPublic::try_from(x).ok());
 
@@ -110,31 +217,119 @@ pub async fn get_endpoint_supporting_handler_public(
     Json(endpoint).into_response()
 }
 
-pub async fn get_endpoints() -> impl IntoResponse {
-    let endpoints = EndpointWatcher::global().endpoints().await;
+pub async fn get_endpoints(Extension(auth): Extension<AuthData>) -> impl IntoResponse {
+    let endpoints = EndpointWatcher::global()
+        .endpoints()
+        .iter()
+        .filter(|endpoint| can_manage(&auth, endpoint))
+        .cloned()
+        .collect::<Vec<_>>();
 
     Json(endpoints)
 }
 
+/// Whether the API key may see and manage the endpoint.
+///
+/// The gateway's own keys can manage all endpoints, tenant keys only the ones of their tenant.
+fn can_manage(auth: &AuthData, endpoint: &Endpoint) -> bool {
+    auth.tenant.is_none() || auth.tenant == endpoint.tenant
+}
+
+/// The endpoint with the ID, if the API key may manage it.
+fn managed_endpoint(auth: &AuthData, id: &str) -> Option<Endpoint> {
+    EndpointWatcher::global()
+        .endpoint(id)
+        .filter(|endpoint| can_manage(auth, endpoint))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryProxy {
+    /// Handlers to try if the requested one isn't available or fails. Replaces the configured chain.
+    fallback: Option<String>,
+}
+
 #[tracing::instrument]
 pub async fn any_endpoint_proxy_handler(
     Path(handler): Path<String>,
+    Query(query): Query<QueryProxy>,
     method: Method,
     headers: HeaderMap,
     body: Body,
 ) -> impl IntoResponse {
     debug!(?handler, "Proxying request");
 
-    let endpoints = EndpointWatcher::global()
-        .endpoints_supporting_handler(&handler)
-        .await;
+    if Config::global()
+        .virtual_handler(&handler)
+        .is_some_and(|x| x.strategy == VirtualHandlerStrategy::Ensemble)
+    {
+        let body = match axum::body::to_bytes(body, MAX_BODY_SIZE).await {
+            Ok(body) => body,
+            Err(e) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    format!("Failed to read request body: {:?}", e),
+                )
+                    .into_response()
+            }
+        };
 
-    let endpoint = endpoints.choose(&mut rand::thread_rng());
+        return dispatch::buffered(&handler, None, method, headers, body).await;
+    }
 
-    trace!(?endpoint, "Chose endpoint");
+    let tenant = tenants::from_headers(&headers);
+    let mut routes = fallback::routes(&handler, query.fallback.as_deref(), tenant);
 
-    let endpoint = match endpoint {
-        Some(endpoint) => endpoint,
+    trace!(?routes, "Chose routes");
+
+    // Anything that may need the request sent more than once or cached needs the whole body up front
+    let mirror = shadow::should_mirror(routes.iter().map(|(handler, _)| handler));
+    let buffered = routes.len() > 1
+        || routes.first().is_some_and(|(handler, _)| {
+            Hedging::is_enabled(handler) || ResultCache::is_enabled() || Coalescer::is_enabled()
+        });
+
+    if buffered || mirror {
+        let body = match axum::body::to_bytes(body, MAX_BODY_SIZE).await {
+            Ok(body) => body,
+            Err(e) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    format!("Failed to read request body: {:?}", e),
+                )
+                    .into_response()
+            }
+        };
+
+        let key = request_key(
+            &handler,
+            query.fallback.as_deref(),
+            &method,
+            &headers,
+            &body,
+        );
+
+        let lookup = CacheLookup::new(&handler, key.as_deref(), &headers);
+        if let Some(response) = lookup.hit().await {
+            return response;
+        }
+
+        let request = async move {
+            let started = Instant::now();
+            let mut response =
+                fallback::proxy(routes, method.clone(), headers.clone(), body.clone()).await;
+
+            if mirror {
+                response = shadow::mirror(response, started.elapsed(), method, headers, body).await;
+            }
+
+            lookup.store(response).await
+        };
+
+        return Coalescer::global().run(key.as_deref(), request).await;
+    }
+
+    let (handler, route) = match routes.pop() {
+        Some(route) => route,
         None => {
             return (
                 StatusCode::NOT_FOUND,
@@ -144,36 +339,28 @@ pub async fn any_endpoint_proxy_handler(
         }
     };
 
-    let handler_url = match endpoint.handler_url(&handler) {
-        Some(url) => url,
-        None => {
-            return (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                "Endpoint info not available".to_string(),
-            )
-                .into_response()
-        }
+    let priority = priority::from_headers(&headers);
+    let acquired = EndpointQueue::global()
+        .acquire(
+            &handler,
+            tenants::from_headers(&headers),
+            route,
+            priority,
+            EndpointQueue::deadline(),
+        )
+        .await;
+    let (route, in_flight) = match acquired {
+        Ok(acquired) => acquired,
+        Err(e) => return e.into_response(),
     };
 
-    trace!(?handler_url, "Got handler url");
-
     trace!("Forwarding request to endpoint");
     let endpoint_response = {
         let client = reqwest::Client::new();
 
-        let mut request_builder = client.request(method, handler_url);
-
-        request_builder = request_builder.headers({
-            let mut headers = headers.clone();
+        let mut request_builder = client.request(method, route.handler_url);
 
-            headers.insert(
-                "Host",
-                HeaderValue::from_str(endpoint.url.host_str().unwrap_or_default())
-                    .expect("Invalid host"),
-            );
-
-            headers
-        });
+        request_builder = request_builder.headers(forward_headers(&headers, &route.endpoint));
 
         request_builder = request_builder.body(reqwest::Body::wrap_stream(body.into_data_stream()));
 
@@ -189,8 +376,16 @@ pub async fn any_endpoint_proxy_handler(
             *response_builder
                 .headers_mut()
                 .expect("Failed to get headers") = endpoint_response.headers().clone();
+            if let Ok(handler) = HeaderValue::from_str(&handler) {
+                response_builder = response_builder.header(HANDLER_HEADER, handler);
+            }
+            // The request is in flight until the whole response has been streamed back
+            let body = endpoint_response.bytes_stream().map(move |chunk| {
+                let _in_flight = &in_flight;
+                chunk
+            });
             response_builder
-                .body(Body::from_stream(endpoint_response.bytes_stream()))
+                .body(Body::from_stream(body))
                 .expect("Failed to build response")
         }
         Err(e) => (
@@ -201,36 +396,361 @@ pub async fn any_endpoint_proxy_handler(
     }
 }
 
+#[tracing::instrument(skip(headers, body))]
+pub async fn post_ocr_ensemble(
+    Query(query): Query<QueryEnsemble>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> impl IntoResponse {
+    let handlers = query.handlers(tenants::from_headers(&headers));
+
+    if handlers.is_empty() {
+        return (
+            StatusCode::BAD_REQUEST,
+            "No handlers to run the ensemble with".to_string(),
+        )
+            .into_response();
+    }
+
+    ensemble::run(handlers, &query, &headers, body)
+        .await
+        .into_response()
+}
+
+/// Run many files through the handler in one request.
+#[tracing::instrument(skip(headers, multipart))]
+pub async fn post_ocr_batch(
+    Path(handler): Path<String>,
+    Query(query): Query<QueryProxy>,
+    headers: HeaderMap,
+    multipart: Multipart,
+) -> impl IntoResponse {
+    let files = match batch::read_files(multipart).await {
+        Ok(files) => files,
+        Err(e) => return (StatusCode::BAD_REQUEST, e).into_response(),
+    };
+
+    if files.is_empty() {
+        return (StatusCode::BAD_REQUEST, "No files uploaded".to_string()).into_response();
+    }
+
+    // Each file counts as a request, the first one was already counted for the batch itself
+    let tenant = tenants::from_headers(&headers).and_then(|x| Config::global().tenants.tenant(x));
+    if let Some(tenant) = tenant {
+        let more_files = u64::try_from(files.len() - 1).unwrap_or(u64::MAX);
+        if let Err(e) = TenantUsage::global().start(tenant, &handler, more_files, 0) {
+            return e.into_response();
+        }
+    }
+
+    let batch = batch::run(handler, query.fallback, &headers, files).await;
+    let outcome = UsageOutcome {
+        succeeded: batch.succeeded as u64,
+        failed: batch.failed as u64,
+    };
+
+    let mut response = batch.into_response();
+    response.extensions_mut().insert(outcome);
+
+    response
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryJob {
+    /// Handlers to try if the requested one isn't available or fails. Replaces the configured chain.
+    fallback: Option<String>,
+    /// Where to POST the job once it has finished. Requires an API key, whose secret signs the payload.
+    callback_url: Option<Url>,
+}
+
+/// Submit an OCR request to be processed in the background.
+#[tracing::instrument(skip(auth, headers, body))]
+pub async fn post_job(
+    Path(handler): Path<String>,
+    Query(query): Query<QueryJob>,
+    auth: Option<Extension<AuthData>>,
+    headers: HeaderMap,
+    body: Body,
+) -> impl IntoResponse {
+    let key_name = auth.as_ref().map(|Extension(auth)| auth.key_name.clone());
+    let callback = match (query.callback_url, auth) {
+        (None, _) => None,
+        (Some(_), None) => {
+            return (
+                StatusCode::UNAUTHORIZED,
+                "Callbacks require an API key".to_string(),
+            )
+                .into_response()
+        }
+        (Some(url), Some(Extension(auth))) => {
+            if let Err(e) = JobCallback::check_url(&url).await {
+                return (StatusCode::BAD_REQUEST, e).into_response();
+            }
+
+            Some(JobCallback::new(url, auth.key_name))
+        }
+    };
+
+    let tenant = tenants::from_headers(&headers);
+    if fallback::routes(&handler, query.fallback.as_deref(), tenant).is_empty() {
+        return (
+            StatusCode::NOT_FOUND,
+            "No live endpoints found supporting that handler".to_string(),
+        )
+            .into_response();
+    }
+
+    match JobQueue::global()
+        .submit(handler, query.fallback, key_name, callback, &headers, body)
+        .await
+    {
+        Ok(job) => (
+            StatusCode::ACCEPTED,
+            [(header::LOCATION, format!("/jobs/id/{}", job.id))],
+            Json(job),
+        )
+            .into_response(),
+        Err(e) => e.into_response(),
+    }
+}
+
+/// The job, unless it was submitted with another API key (or another tenant) than the caller's,
+/// in which case it doesn't exist for them.
+fn visible_job(id: &str, auth: Option<&AuthData>, headers: &HeaderMap) -> Option<Job> {
+    let key_name = auth.map(|x| x.key_name.as_str());
+    let tenant = tenants::from_headers(headers);
+
+    JobQueue::global()
+        .get(id)
+        .filter(|job| job.key_name.as_deref() == key_name && job.tenant.as_deref() == tenant)
+}
+
+pub async fn get_job(
+    Path(id): Path<String>,
+    auth: Option<Extension<AuthData>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    visible_job(&id, auth.as_deref(), &headers).map_or_else(
+        || (StatusCode::NOT_FOUND, "Job not found".to_string()).into_response(),
+        |job| Json(job).into_response(),
+    )
+}
+
+pub async fn delete_job(
+    Path(id): Path<String>,
+    auth: Option<Extension<AuthData>>,
+    headers: HeaderMap,
+) -> impl IntoResponse {
+    if visible_job(&id, auth.as_deref(), &headers).is_none() {
+        return (StatusCode::NOT_FOUND, "Job not found".to_string()).into_response();
+    }
+
+    match JobQueue::global().remove(&id).await {
+        Some(JobRemoval::Cancelled(job)) => Json(serde_json::json!({
+            "success": true,
+            "message": "Cancelled job",
+            "job": job,
+        }))
+        .into_response(),
+        Some(JobRemoval::Removed(job)) => Json(serde_json::json!({
+            "success": true,
+            "message": "Removed job",
+            "job": job,
+        }))
+        .into_response(),
+        None => (StatusCode::NOT_FOUND, "Job not found".to_string()).into_response(),
+    }
+}
+
 #[derive(Debug, Deserialize)]
 pub struct PayloadAddEndpoint {
     url: Url,
+    /// Only mirror live traffic to the endpoint, for comparison.
+    #[serde(default)]
+    shadow: bool,
+    /// How many requests the endpoint can take at the same time, instead of what it advertises.
+    max_concurrency: Option<usize>,
+    /// Only route requests of the tenant to the endpoint. Always the tenant of the API key for tenant keys.
+    tenant: Option<String>,
 }
 pub async fn any_add_endpoint(
+    actor: AuditActor,
+    Extension(auth): Extension<AuthData>,
     axum::extract::Json(endpoint_payload): axum::extract::Json<PayloadAddEndpoint>,
 ) -> impl IntoResponse {
     let url = endpoint_payload.url.to_string();
 
-    let added = EndpointWatcher::global()
-        .add_endpoint(endpoint_payload.url)
-        .await;
+    let tenant = match (&auth.tenant, endpoint_payload.tenant) {
+        (Some(tenant), Some(requested)) if *tenant != requested => {
+            return (
+                StatusCode::FORBIDDEN,
+                Json(serde_json::json!({
+                    "success": false,
+                    "message": "Can't add endpoints for another tenant",
+                    "url": url,
+                })),
+            )
+                .into_response();
+        }
+        (Some(tenant), _) => Some(tenant.clone()),
+        (None, Some(requested)) if Config::global().tenants.tenant(&requested).is_none() => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({
+                    "success": false,
+                    "message": "Unknown tenant",
+                    "url": url,
+                })),
+            )
+                .into_response();
+        }
+        (None, requested) => requested,
+    };
 
-    if !added {
-        return Json(serde_json::json!({
-            "success": false,
-            "message": "Endpoint already exists",
-            "url": url,
-        }));
+    // Shadow endpoints get copies of everyone's traffic
+    if endpoint_payload.shadow && tenant.is_some() {
+        return (
+            StatusCode::FORBIDDEN,
+            Json(serde_json::json!({
+                "success": false,
+                "message": "Shadow endpoints can't belong to a tenant",
+                "url": url,
+            })),
+        )
+            .into_response();
     }
 
+    let endpoint = if endpoint_payload.shadow {
+        Endpoint::shadow(endpoint_payload.url)
+    } else {
+        Endpoint::new(endpoint_payload.url)
+    }
+    .with_max_concurrency(endpoint_payload.max_concurrency)
+    .with_tenant(tenant);
+
+    let added = EndpointWatcher::global().add_endpoint(endpoint).await;
+
+    let added = match added {
+        Some(added) => added,
+        None => {
+            return Json(serde_json::json!({
+                "success": false,
+                "message": "Endpoint already exists",
+                "url": url,
+            }))
+            .into_response();
+        }
+    };
+
+    AuditLog::global()
+        .record(
+            AuditEntry::new(actor, AuditAction::EndpointAdd, (&added).into())
+                .with_after(Some(&added)),
+        )
+        .await;
+
     Json(serde_json::json!({
         "success": true,
         "message": "Added endpoint",
         "url": url,
+        "id": added.id,
     }))
+    .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryDrainEndpoint {
+    /// Stop sending new requests to the endpoint and wait for in-flight ones to finish first.
+    #[serde(default)]
+    drain: bool,
+    /// How long to wait for the endpoint to become idle.
+    timeout: Option<String>,
+    /// Remove the endpoint even if it didn't become idle in time.
+    ///
+    /// Otherwise the endpoint is kept and stops draining again, unless it was already draining before.
+    #[serde(default)]
+    force: bool,
 }
+impl QueryDrainEndpoint {
+    fn timeout(&self) -> Result<Duration, TimeframeParseError> {
+        self.timeout.as_deref().map_or_else(
+            || Ok(Config::global().endpoint_drain_timeout.into()),
+            |timeout| Timeframe::parse_str(timeout).map(Into::into),
+        )
+    }
+}
+
+pub async fn delete_remove_endpoint(
+    actor: AuditActor,
+    Extension(auth): Extension<AuthData>,
+    Path(id): Path<String>,
+    Query(query): Query<QueryDrainEndpoint>,
+) -> impl IntoResponse {
+    if auth.tenant.is_some() && managed_endpoint(&auth, &id).is_none() {
+        return (
+            StatusCode::NOT_FOUND,
+            Json(serde_json::json!({
+                "success": false,
+                "message": "Endpoint not found",
+                "id": id,
+            })),
+        )
+            .into_response();
+    }
+
+    if query.drain {
+        let timeout = match query.timeout() {
+            Ok(timeout) => timeout,
+            Err(e) => {
+                return (
+                    StatusCode::BAD_REQUEST,
+                    Json(serde_json::json!({
+                        "success": false,
+                        "message": e.to_string(),
+                    })),
+                )
+                    .into_response();
+            }
+        };
+
+        if let Some(endpoint) = managed_endpoint(&auth, &id) {
+            let was_draining = endpoint.draining();
+            let idle = drain_endpoint(actor.clone(), &endpoint, timeout).await;
 
-pub async fn delete_remove_endpoint(Path(id): Path<String>) -> impl IntoResponse {
-    EndpointWatcher::global().remove_endpoint(&id).await;
+            if !idle && !query.force {
+                // The endpoint stays, so it goes back to getting requests unless it was drained before
+                if !was_draining {
+                    undrain_endpoint(actor, &endpoint).await;
+                }
+
+                return (
+                    StatusCode::CONFLICT,
+                    Json(serde_json::json!({
+                        "success": false,
+                        "message": "Endpoint still has requests in flight",
+                        "id": id,
+                        "in_flight": endpoint.in_flight(),
+                        "draining": was_draining,
+                    })),
+                )
+                    .into_response();
+            }
+        }
+    }
+
+    // Removing an endpoint that doesn't exist succeeds, but there is nothing to audit
+    if let Some(removed) = EndpointWatcher::global().remove_endpoint(&id).await {
+        AuditLog::global()
+            .record(
+                AuditEntry::new(
+                    actor,
+                    AuditAction::EndpointRemove,
+                    AuditTarget::endpoint_id(&id),
+                )
+                .with_before(Some(&removed)),
+            )
+            .await;
+    }
 
     Json(serde_json::json!({
         "success": true,
@@ -240,13 +760,44 @@ pub async fn delete_remove_endpoint(Path(id): Path<String>) -> impl IntoResponse
     .into_response()
 }
 
-pub async fn any_disable_endpoint(Path(id): Path<String>) -> impl IntoResponse {
-    let endpoint = EndpointWatcher::global().endpoint(&id).await;
+pub async fn any_disable_endpoint(
+    actor: AuditActor,
+    Extension(auth): Extension<AuthData>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    set_endpoint_disabled(actor, &auth, id, true).await
+}
 
-    match endpoint {
-        Some(endpoint) => {
-            endpoint.set_disabled(true);
+pub async fn any_enable_endpoint(
+    actor: AuditActor,
+    Extension(auth): Extension<AuthData>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    set_endpoint_disabled(actor, &auth, id, false).await
+}
+
+pub async fn any_drain_endpoint(
+    actor: AuditActor,
+    Extension(auth): Extension<AuthData>,
+    Path(id): Path<String>,
+    Query(query): Query<QueryDrainEndpoint>,
+) -> impl IntoResponse {
+    let timeout = match query.timeout() {
+        Ok(timeout) => timeout,
+        Err(e) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({
+                    "success": false,
+                    "message": e.to_string(),
+                })),
+            )
+                .into_response();
         }
+    };
+
+    let endpoint = match managed_endpoint(&auth, &id) {
+        Some(endpoint) => endpoint,
         None => {
             return Json(serde_json::json!({
                 "success": false,
@@ -257,21 +808,80 @@ pub async fn any_disable_endpoint(Path(id): Path<String>) -> impl IntoResponse {
         }
     };
 
+    let idle = drain_endpoint(actor, &endpoint, timeout).await;
+
     Json(serde_json::json!({
         "success": true,
-        "message": "Disabled endpoint",
+        "message": if idle { "Endpoint drained" } else { "Endpoint is draining" },
         "id": id,
+        "idle": idle,
+        "in_flight": endpoint.in_flight(),
     }))
     .into_response()
 }
 
-pub async fn any_enable_endpoint(Path(id): Path<String>) -> impl IntoResponse {
-    let endpoint = EndpointWatcher::global().endpoint(&id).await;
-
-    match endpoint {
-        Some(endpoint) => {
-            endpoint.set_disabled(false);
+pub async fn any_undrain_endpoint(
+    actor: AuditActor,
+    Extension(auth): Extension<AuthData>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let endpoint = match managed_endpoint(&auth, &id) {
+        Some(endpoint) => endpoint,
+        None => {
+            return Json(serde_json::json!({
+                "success": false,
+                "message": "Endpoint not found",
+                "id": id,
+            }))
+            .into_response();
         }
+    };
+
+    undrain_endpoint(actor, &endpoint).await;
+
+    Json(serde_json::json!({
+        "success": true,
+        "message": "Stopped draining endpoint",
+        "id": id,
+    }))
+    .into_response()
+}
+
+/// Stops routing new requests to the endpoint and waits for it to become idle.
+///
+/// Returns whether it became idle before the timeout.
+async fn drain_endpoint(actor: AuditActor, endpoint: &Endpoint, timeout: Duration) -> bool {
+    let entry = AuditEntry::new(actor, AuditAction::EndpointDrain, endpoint.into())
+        .with_before(Some(endpoint));
+    EndpointWatcher::global().set_endpoint_draining(endpoint, true);
+    AuditLog::global()
+        .record(entry.with_after(Some(endpoint)))
+        .await;
+
+    debug!(id = %endpoint.id, ?timeout, in_flight = endpoint.in_flight(), "Waiting for endpoint to drain");
+
+    endpoint.wait_until_idle(timeout).await
+}
+
+async fn undrain_endpoint(actor: AuditActor, endpoint: &Endpoint) {
+    let entry = AuditEntry::new(actor, AuditAction::EndpointUndrain, endpoint.into())
+        .with_before(Some(endpoint));
+    EndpointWatcher::global().set_endpoint_draining(endpoint, false);
+    AuditLog::global()
+        .record(entry.with_after(Some(endpoint)))
+        .await;
+}
+
+async fn set_endpoint_disabled(
+    actor: AuditActor,
+    auth: &AuthData,
+    id: String,
+    disabled: bool,
+) -> Response {
+    let endpoint = managed_endpoint(auth, &id);
+
+    let endpoint = match endpoint {
+        Some(endpoint) => endpoint,
         None => {
             return Json(serde_json::json!({
                 "success": false,
@@ -282,10 +892,279 @@ pub async fn any_enable_endpoint(Path(id): Path<String>) -> impl IntoResponse {
         }
     };
 
+    let (action, message) = if disabled {
+        (AuditAction::EndpointDisable, "Disabled endpoint")
+    } else {
+        (AuditAction::EndpointEnable, "Enabled endpoint")
+    };
+
+    let entry = AuditEntry::new(actor, action, (&endpoint).into()).with_before(Some(&endpoint));
+    EndpointWatcher::global().set_endpoint_disabled(&endpoint, disabled);
+    let entry = entry.with_after(Some(&endpoint));
+
+    AuditLog::global().record(entry).await;
+
     Json(serde_json::json!({
         "success": true,
-        "message": "Enabled endpoint",
+        "message": message,
+        "id": id,
+    }))
+    .into_response()
+}
+
+pub async fn any_check_endpoint(
+    Extension(auth): Extension<AuthData>,
+    Path(id): Path<String>,
+) -> impl IntoResponse {
+    let endpoint = match managed_endpoint(&auth, &id) {
+        Some(endpoint) => endpoint,
+        None => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(serde_json::json!({
+                    "success": false,
+                    "message": "Endpoint not found",
+                    "id": id,
+                })),
+            )
+                .into_response();
+        }
+    };
+
+    if !EndpointWatcher::global().check_endpoint(&endpoint).await {
+        return (
+            StatusCode::CONFLICT,
+            Json(serde_json::json!({
+                "success": false,
+                "message": "Endpoint is already being checked",
+                "id": id,
+            })),
+        )
+            .into_response();
+    }
+
+    Json(endpoint).into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryEndpointHistory {
+    /// Comma-separated list of windows to calculate statistics for.
+    windows: Option<String>,
+    /// How many of the latest checks to include.
+    limit: Option<usize>,
+}
+pub async fn get_endpoint_history(
+    Extension(auth): Extension<AuthData>,
+    Path(id): Path<String>,
+    Query(query): Query<QueryEndpointHistory>,
+) -> impl IntoResponse {
+    let windows = match query.windows.as_deref().map(parse_timeframes) {
+        Some(Ok(windows)) => windows,
+        Some(Err(e)) => {
+            return (
+                StatusCode::BAD_REQUEST,
+                Json(serde_json::json!({
+                    "success": false,
+                    "message": e,
+                })),
+            )
+                .into_response();
+        }
+        None => Config::global().endpoint_history_windows.clone(),
+    };
+
+    let endpoint = match managed_endpoint(&auth, &id) {
+        Some(endpoint) => endpoint,
+        None => {
+            return (
+                StatusCode::NOT_FOUND,
+                Json(serde_json::json!({
+                    "success": false,
+                    "message": "Endpoint not found",
+                    "id": id,
+                })),
+            )
+                .into_response();
+        }
+    };
+
+    let history = endpoint.history.lock();
+
+    Json(serde_json::json!({
         "id": id,
+        "stats": windows.into_iter().map(|w| history.stats(w)).collect::<Vec<_>>(),
+        "checks": history.latest(query.limit.unwrap_or(100)),
+    }))
+    .into_response()
+}
+
+pub async fn get_events(Extension(auth): Extension<AuthData>) -> impl IntoResponse {
+    let events = futures::stream::unfold(
+        (EndpointWatcher::global().subscribe(), auth.tenant),
+        |(mut rx, tenant)| async move {
+            let event = loop {
+                match rx.recv().await {
+                    // Tenant keys only see the events of their own endpoints
+                    Ok(event) if tenant.is_some() && event.tenant != tenant => {}
+                    Ok(event) => break Event::default().event(event.name()).json_data(&event),
+                    Err(RecvError::Lagged(missed)) => {
+                        break Ok(Event::default().event("lagged").data(missed.to_string()))
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            };
+
+            Some((event, (rx, tenant)))
+        },
+    );
+
+    // Close the stream on shutdown so it doesn't hold up the server
+    let events = events.take_until(Shutdown::global().started());
+
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+pub async fn get_audit_log(
+    Extension(auth): Extension<AuthData>,
+    Query(mut filter): Query<AuditFilter>,
+) -> impl IntoResponse {
+    // Tenant keys only see what was done with their tenant's keys
+    if auth.tenant.is_some() {
+        filter.tenant = auth.tenant;
+    }
+
+    Json(AuditLog::global().query(&filter))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryShadowReport {
+    limit: Option<usize>,
+}
+
+pub async fn get_shadow_report(Query(query): Query<QueryShadowReport>) -> impl IntoResponse {
+    Json(ShadowReport::global().summary(query.limit.unwrap_or(20)))
+}
+
+pub async fn delete_shadow_report(actor: AuditActor) -> impl IntoResponse {
+    ShadowReport::global().reset();
+
+    AuditLog::global()
+        .record(AuditEntry::new(
+            actor,
+            AuditAction::ShadowReportReset,
+            AuditTarget::default(),
+        ))
+        .await;
+
+    Json(serde_json::json!({
+        "success": true,
+        "message": "Reset shadow report",
+    }))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QueryCache {
+    limit: Option<usize>,
+}
+
+pub async fn get_cache(Query(query): Query<QueryCache>) -> impl IntoResponse {
+    Json(ResultCache::global().stats(query.limit.unwrap_or(20)).await)
+}
+
+pub async fn delete_cache(actor: AuditActor) -> impl IntoResponse {
+    let (in_memory, on_disk) = ResultCache::global().purge().await;
+
+    AuditLog::global()
+        .record(AuditEntry::new(
+            actor,
+            AuditAction::CachePurge,
+            AuditTarget::default(),
+        ))
+        .await;
+
+    Json(serde_json::json!({
+        "success": true,
+        "message": format!("Purged {in_memory} cached results from memory and {on_disk} from disk"),
+    }))
+}
+
+pub async fn delete_cache_entry(Path(key): Path<String>, actor: AuditActor) -> impl IntoResponse {
+    // Keys are hex digests, anything else can't be in the cache (or be a safe file name)
+    if key.is_empty() || !key.bytes().all(|x| x.is_ascii_hexdigit()) {
+        return (StatusCode::BAD_REQUEST, "Invalid cache key").into_response();
+    }
+
+    if !ResultCache::global().remove(&key).await {
+        return (StatusCode::NOT_FOUND, "No cached result with that key").into_response();
+    }
+
+    AuditLog::global()
+        .record(
+            AuditEntry::new(actor, AuditAction::CachePurge, AuditTarget::default())
+                .with_before(Some(&serde_json::json!({ "key": key }))),
+        )
+        .await;
+
+    Json(serde_json::json!({
+        "success": true,
+        "message": "Purged cached result",
     }))
     .into_response()
 }
+
+#[derive(Debug, Serialize)]
+pub struct TenantReport {
+    name: String,
+    /// The endpoints only the tenant's requests are routed to.
+    endpoints: Vec<EndpointId>,
+    usage: UsageReport,
+}
+
+impl From<&Tenant> for TenantReport {
+    fn from(tenant: &Tenant) -> Self {
+        let endpoints = EndpointWatcher::global()
+            .endpoints()
+            .iter()
+            .filter(|x| x.tenant.as_ref() == Some(&tenant.name))
+            .map(|x| x.id.clone())
+            .collect();
+
+        Self {
+            name: tenant.name.clone(),
+            endpoints,
+            usage: TenantUsage::global().report(tenant),
+        }
+    }
+}
+
+pub async fn get_tenants(Extension(auth): Extension<AuthData>) -> impl IntoResponse {
+    let tenants = Config::global()
+        .tenants
+        .tenants_file
+        .iter()
+        .filter(|x| {
+            auth.tenant
+                .as_ref()
+                .map_or(true, |tenant| *tenant == x.name)
+        })
+        .map(TenantReport::from)
+        .collect::<Vec<_>>();
+
+    Json(tenants)
+}
+
+pub async fn get_tenant(
+    Extension(auth): Extension<AuthData>,
+    Path(name): Path<String>,
+) -> impl IntoResponse {
+    let tenant = Config::global().tenants.tenant(&name).filter(|x| {
+        auth.tenant
+            .as_ref()
+            .map_or(true, |tenant| *tenant == x.name)
+    });
+
+    tenant.map_or_else(
+        || (StatusCode::NOT_FOUND, "Tenant not found").into_response(),
+        |tenant| Json(TenantReport::from(tenant)).into_response(),
+    )
+}
@@ -0,0 +1,117 @@
+use std::{sync::Arc, time::Duration};
+
+use once_cell::sync::Lazy;
+use tokio::{signal, sync::watch};
+use tracing::{info, warn};
+
+use crate::{
+    config::Config,
+    helpers::in_flight::{InFlight, InFlightGuard},
+};
+
+static SHUTDOWN: Lazy<Shutdown> = Lazy::new(Shutdown::new);
+
+/// Tracks whether the server is shutting down and which requests and running jobs still need to finish.
+#[derive(Debug)]
+pub struct Shutdown {
+    started: watch::Sender<bool>,
+    in_flight: Arc<InFlight>,
+}
+
+impl Shutdown {
+    fn new() -> Self {
+        Self {
+            started: watch::channel(false).0,
+            in_flight: Arc::new(InFlight::default()),
+        }
+    }
+
+    pub fn global() -> &'static Self {
+        &SHUTDOWN
+    }
+
+    pub fn is_started(&self) -> bool {
+        *self.started.borrow()
+    }
+
+    /// Resolves once shutdown has started.
+    pub async fn started(&self) {
+        let mut rx = self.started.subscribe();
+        let _ = rx.wait_for(|started| *started).await;
+    }
+
+    pub fn track_request(&self) -> InFlightGuard {
+        self.in_flight.track()
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.count()
+    }
+
+    fn start(&self) {
+        self.started.send_replace(true);
+    }
+}
+
+/// Resolves when the server should stop accepting connections.
+///
+/// Waits for a shutdown signal, then gives in-flight requests and running jobs
+/// up to the configured grace period to finish.
+pub async fn graceful() {
+    wait_for_signal().await;
+
+    let grace_period: Duration = Config::global().shutdown_grace_period.into();
+    let shutdown = Shutdown::global();
+
+    info!(
+        in_flight = shutdown.in_flight(),
+        ?grace_period,
+        "Shutting down, waiting for in-flight requests",
+    );
+    shutdown.start();
+
+    if tokio::time::timeout(grace_period, shutdown.in_flight.wait_idle())
+        .await
+        .is_err()
+    {
+        warn!(
+            in_flight = shutdown.in_flight(),
+            "Grace period over with requests still in flight",
+        );
+    }
+}
+
+/// Resolves once the grace period has passed after shutdown started.
+///
+/// Used to cut off connections that are still open after the graceful shutdown.
+pub async fn forced() {
+    Shutdown::global().started().await;
+    tokio::time::sleep(Config::global().shutdown_grace_period.into()).await;
+}
+
+async fn wait_for_signal() {
+    let ctrl_c = async {
+        signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        signal::unix::signal(signal::unix::SignalKind::terminate())
+            .expect("failed to install signal handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    #[allow(clippy::redundant_pub_crate)]
+    {
+        tokio::select! {
+            () = ctrl_c => {},
+            () = terminate => {},
+        }
+    }
+}
@@ -0,0 +1,18 @@
+pub mod usage;
+
+use axum::http::HeaderMap;
+
+pub use self::usage::TenantUsage;
+
+/// Request header with the tenant the request is made for.
+///
+/// Set by the gateway from the API key of the request, whatever the client sent.
+pub const TENANT_HEADER: &str = "x-ocr-tenant";
+
+/// The tenant of a request, from its header.
+pub fn from_headers(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(TENANT_HEADER)
+        .and_then(|x| x.to_str().ok())
+        .filter(|x| !x.is_empty())
+}
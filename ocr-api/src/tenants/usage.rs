@@ -0,0 +1,231 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use axum::{
+    http::{header, StatusCode},
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+use crate::config::{Tenant, TenantQuota};
+
+static TENANT_USAGE: Lazy<TenantUsage> = Lazy::new(TenantUsage::default);
+
+/// What each tenant used the gateway for, in the current quota period and since the gateway started.
+///
+/// Only kept in memory, so it starts over on restart.
+#[derive(Debug, Default)]
+pub struct TenantUsage {
+    usage: Mutex<HashMap<String, Usage>>,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+pub struct UsageCounters {
+    pub requests: u64,
+    pub succeeded: u64,
+    pub failed: u64,
+    /// Requests refused because the quota was used up, which don't count as requests.
+    pub rejected: u64,
+    pub upload_bytes: u64,
+    /// Requests by the handler they were made to.
+    pub handlers: BTreeMap<String, u64>,
+}
+
+#[derive(Debug, Default)]
+struct Usage {
+    /// Start of the current quota period, in seconds since the UNIX epoch.
+    period_start: i64,
+    current: UsageCounters,
+    total: UsageCounters,
+}
+
+impl Usage {
+    /// Start counting a new period if the current one is over.
+    fn roll_over(&mut self, period_start: i64) {
+        if self.period_start != period_start {
+            self.period_start = period_start;
+            self.current = UsageCounters::default();
+        }
+    }
+
+    const fn counters(&mut self) -> [&mut UsageCounters; 2] {
+        [&mut self.current, &mut self.total]
+    }
+}
+
+/// The quota period a tenant is in, as its start and length in seconds.
+fn current_period(quota: &TenantQuota) -> (i64, i64) {
+    let length = i64::try_from(Duration::from(quota.period).as_secs())
+        .unwrap_or(i64::MAX)
+        .max(1);
+    let now = Utc::now().timestamp();
+
+    (now - now.rem_euclid(length), length)
+}
+
+/// How the requests counted for one HTTP request ended, when it wasn't just one request.
+///
+/// Added to the response by handlers that count more than one request, like batches do for each file.
+#[derive(Debug, Clone, Copy)]
+pub struct UsageOutcome {
+    pub succeeded: u64,
+    pub failed: u64,
+}
+
+/// The tenant used up its quota for the current period.
+#[derive(Debug)]
+pub struct QuotaExceeded {
+    /// Until the next period starts.
+    pub retry_after: Duration,
+}
+
+impl QuotaExceeded {
+    /// Until the end of the period, which is at least a second away so clients don't retry right away.
+    fn until(period_start: i64, period_length: i64) -> Self {
+        let left = period_start.saturating_add(period_length) - Utc::now().timestamp();
+
+        Self {
+            retry_after: Duration::from_secs(u64::try_from(left).unwrap_or_default().max(1)),
+        }
+    }
+}
+
+impl IntoResponse for QuotaExceeded {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, self.retry_after.as_secs().to_string())],
+            "Quota exceeded for the current period",
+        )
+            .into_response()
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct UsageReport {
+    pub quota: TenantQuota,
+    pub period_start: DateTime<Utc>,
+    pub period_end: DateTime<Utc>,
+    pub current: UsageCounters,
+    pub total: UsageCounters,
+}
+
+impl TenantUsage {
+    pub fn global() -> &'static Self {
+        &TENANT_USAGE
+    }
+
+    /// Count requests of the tenant, unless they would go over its quota.
+    ///
+    /// Also refused if the tenant already used up its upload quota,
+    /// or if the size the upload is expected to have wouldn't fit in it.
+    /// The upload itself is counted with [`Self::add_upload`] as it is read.
+    pub fn start(
+        &self,
+        tenant: &Tenant,
+        handler: &str,
+        requests: u64,
+        expected_upload_bytes: u64,
+    ) -> Result<(), QuotaExceeded> {
+        let quota = &tenant.quota;
+        let (period_start, period_length) = current_period(quota);
+
+        let mut all_usage = self.usage.lock();
+        let usage = all_usage.entry(tenant.name.clone()).or_default();
+        usage.roll_over(period_start);
+
+        let over_requests = quota
+            .max_requests
+            .is_some_and(|max| usage.current.requests.saturating_add(requests) > max);
+        let over_upload = quota.max_upload_mb.is_some_and(|max| {
+            let max = max.saturating_mul(1024 * 1024);
+            usage.current.upload_bytes >= max
+                || usage
+                    .current
+                    .upload_bytes
+                    .saturating_add(expected_upload_bytes)
+                    > max
+        });
+
+        if over_requests || over_upload {
+            for counters in usage.counters() {
+                counters.rejected += 1;
+            }
+            drop(all_usage);
+
+            return Err(QuotaExceeded::until(period_start, period_length));
+        }
+
+        for counters in usage.counters() {
+            counters.requests += requests;
+            *counters.handlers.entry(handler.to_string()).or_default() += requests;
+        }
+        drop(all_usage);
+
+        Ok(())
+    }
+
+    /// Count uploaded bytes of the tenant, failing once they go over its quota.
+    pub fn add_upload(&self, tenant: &Tenant, bytes: u64) -> Result<(), QuotaExceeded> {
+        let quota = &tenant.quota;
+        let (period_start, period_length) = current_period(quota);
+
+        let mut all_usage = self.usage.lock();
+        let usage = all_usage.entry(tenant.name.clone()).or_default();
+        usage.roll_over(period_start);
+
+        for counters in usage.counters() {
+            counters.upload_bytes = counters.upload_bytes.saturating_add(bytes);
+        }
+        let upload_bytes = usage.current.upload_bytes;
+        drop(all_usage);
+
+        if quota
+            .max_upload_mb
+            .is_some_and(|max| upload_bytes > max.saturating_mul(1024 * 1024))
+        {
+            return Err(QuotaExceeded::until(period_start, period_length));
+        }
+
+        Ok(())
+    }
+
+    /// Count how requests of the tenant ended.
+    pub fn finish(&self, tenant: &str, outcome: UsageOutcome) {
+        let mut all_usage = self.usage.lock();
+        let usage = match all_usage.get_mut(tenant) {
+            Some(usage) => usage,
+            None => return,
+        };
+
+        for counters in usage.counters() {
+            counters.succeeded += outcome.succeeded;
+            counters.failed += outcome.failed;
+        }
+        drop(all_usage);
+    }
+
+    pub fn report(&self, tenant: &Tenant) -> UsageReport {
+        let (period_start, period_length) = current_period(&tenant.quota);
+
+        let mut all_usage = self.usage.lock();
+        let usage = all_usage.entry(tenant.name.clone()).or_default();
+        usage.roll_over(period_start);
+        let (current, total) = (usage.current.clone(), usage.total.clone());
+        drop(all_usage);
+
+        UsageReport {
+            quota: tenant.quota,
+            period_start: DateTime::from_timestamp(period_start, 0).unwrap_or_default(),
+            period_end: DateTime::from_timestamp(period_start.saturating_add(period_length), 0)
+                .unwrap_or_default(),
+            current,
+            total,
+        }
+    }
+}
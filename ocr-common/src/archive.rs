@@ -0,0 +1,353 @@
+use std::{
+    io::{Cursor, Read},
+    path::{Component, Path},
+};
+
+/// Archive formats that can be uploaded in place of individual files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+    Zip,
+    Tar,
+}
+
+impl ArchiveKind {
+    /// Detect the archive format from the first bytes of the data.
+    #[must_use]
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        if data.starts_with(b"PK\x03\x04") || data.starts_with(b"PK\x05\x06") {
+            return Some(Self::Zip);
+        }
+
+        if data.get(257..262) == Some(b"ustar") {
+            return Some(Self::Tar);
+        }
+
+        None
+    }
+}
+
+/// A file extracted from an archive.
+#[derive(Debug)]
+pub struct ArchiveEntry {
+    /// Path of the file within the archive.
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+/// Whether an archive entry is metadata added by the tool that created the archive.
+fn is_ignored(name: &str) -> bool {
+    name.split('/')
+        .any(|x| (x.starts_with('.') && x != ".") || x == "__MACOSX")
+}
+
+/// Fails if the path would point outside the archive when extracted to disk.
+fn check_path(name: &str) -> anyhow::Result<()> {
+    let is_outside = Path::new(name)
+        .components()
+        .any(|x| !matches!(x, Component::Normal(_) | Component::CurDir));
+
+    if is_outside {
+        anyhow::bail!("File {name:?} has a path outside the archive");
+    }
+
+    Ok(())
+}
+
+/// How far an archive may be extracted, so a small upload can't expand to fill up the memory.
+#[derive(Debug, Clone, Copy)]
+pub struct ExtractLimits {
+    /// The most files to extract, which may be less than a batch allows if it already has some.
+    pub files: usize,
+    /// The most bytes a single file may extract to.
+    pub file_size: u64,
+    /// The most bytes all files together may extract to.
+    pub total_size: u64,
+}
+
+/// Collects the extracted files, stopping as soon as they go over the limits.
+#[derive(Debug)]
+struct Extracted {
+    entries: Vec<ArchiveEntry>,
+    total_size: u64,
+    limits: ExtractLimits,
+}
+
+impl Extracted {
+    const fn new(limits: ExtractLimits) -> Self {
+        Self {
+            entries: Vec::new(),
+            total_size: 0,
+            limits,
+        }
+    }
+
+    /// Read the file, never trusting the size the archive claims it has.
+    fn read(&mut self, name: String, file: impl Read) -> anyhow::Result<()> {
+        let limits = self.limits;
+        if self.entries.len() >= limits.files {
+            anyhow::bail!("Too many files, at most {} more are allowed", limits.files);
+        }
+
+        let total_left = limits.total_size.saturating_sub(self.total_size);
+        let limit = limits.file_size.min(total_left);
+
+        let mut data = Vec::new();
+        file.take(limit.saturating_add(1)).read_to_end(&mut data)?;
+
+        let size = data.len() as u64;
+        if size > limits.file_size {
+            anyhow::bail!(
+                "File {name:?} is larger than {} bytes when extracted",
+                limits.file_size
+            );
+        }
+        if size > total_left {
+            anyhow::bail!(
+                "Archive is larger than {} bytes when extracted",
+                limits.total_size
+            );
+        }
+
+        self.total_size += size;
+        self.entries.push(ArchiveEntry { name, data });
+
+        Ok(())
+    }
+}
+
+/// All regular files in the archive, leaving out hidden files and directories.
+///
+/// Fails if the archive goes over the limits or has files with absolute paths or `..` in them.
+pub fn extract(
+    kind: ArchiveKind,
+    data: &[u8],
+    limits: ExtractLimits,
+) -> anyhow::Result<Vec<ArchiveEntry>> {
+    let mut extracted = Extracted::new(limits);
+
+    match kind {
+        ArchiveKind::Zip => {
+            let mut archive = zip::ZipArchive::new(Cursor::new(data))?;
+
+            for i in 0..archive.len() {
+                let file = archive.by_index(i)?;
+                if !file.is_file() {
+                    continue;
+                }
+
+                check_path(file.name())?;
+                if is_ignored(file.name()) {
+                    continue;
+                }
+
+                let name = file.name().to_string();
+                extracted.read(name, file)?;
+            }
+        }
+        ArchiveKind::Tar => {
+            let mut archive = tar::Archive::new(Cursor::new(data));
+
+            for file in archive.entries()? {
+                let file = file?;
+                let name = file.path()?.to_string_lossy().into_owned();
+                if !file.header().entry_type().is_file() {
+                    continue;
+                }
+
+                check_path(&name)?;
+                if is_ignored(&name) {
+                    continue;
+                }
+
+                extracted.read(name, file)?;
+            }
+        }
+    }
+
+    Ok(extracted.entries)
+}
+
+/// The content type of an image, going by the extension of its file name.
+#[must_use]
+pub fn guess_content_type(name: &str) -> Option<&'static str> {
+    let (_, ext) = name.rsplit_once('.')?;
+
+    let content_type = match ext.to_ascii_lowercase().as_str() {
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "webp" => "image/webp",
+        "gif" => "image/gif",
+        "bmp" => "image/bmp",
+        "tif" | "tiff" => "image/tiff",
+        "pdf" => "application/pdf",
+        _ => return None,
+    };
+
+    Some(content_type)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    const LIMITS: ExtractLimits = ExtractLimits {
+        files: 10,
+        file_size: 1024,
+        total_size: 4096,
+    };
+
+    fn zip_of(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut writer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+        for (name, data) in files {
+            writer
+                .start_file(*name, zip::write::SimpleFileOptions::default())
+                .unwrap();
+            writer.write_all(data).unwrap();
+        }
+
+        writer.finish().unwrap().into_inner()
+    }
+
+    /// Written header by header, as the `tar` builder refuses paths with `..` in them.
+    fn tar_of(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, data) in files {
+            let mut header = tar::Header::new_ustar();
+            header.as_old_mut().name[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_size(data.len() as u64);
+            header.set_mode(0o644);
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_cksum();
+            builder.append(&header, *data).unwrap();
+        }
+
+        builder.into_inner().unwrap()
+    }
+
+    fn names(entries: &[ArchiveEntry]) -> Vec<&str> {
+        entries.iter().map(|x| x.name.as_str()).collect()
+    }
+
+    #[test]
+    fn detects_kind() {
+        assert_eq!(ArchiveKind::detect(&zip_of(&[])), Some(ArchiveKind::Zip));
+        assert_eq!(
+            ArchiveKind::detect(&zip_of(&[("a.png", b"a")])),
+            Some(ArchiveKind::Zip)
+        );
+        assert_eq!(
+            ArchiveKind::detect(&tar_of(&[("a.png", b"a")])),
+            Some(ArchiveKind::Tar)
+        );
+        assert_eq!(ArchiveKind::detect(b"\x89PNG\r\n\x1a\n"), None);
+    }
+
+    #[test]
+    fn extracts_files_without_hidden_ones() {
+        let files: &[(&str, &[u8])] = &[
+            ("a.png", b"first"),
+            (".DS_Store", b"hidden"),
+            ("__MACOSX/._a.png", b"metadata"),
+            ("dir/b.jpg", b"second"),
+        ];
+
+        for (kind, data) in [
+            (ArchiveKind::Zip, zip_of(files)),
+            (ArchiveKind::Tar, tar_of(files)),
+        ] {
+            let entries = extract(kind, &data, LIMITS).unwrap();
+
+            assert_eq!(names(&entries), ["a.png", "dir/b.jpg"], "{kind:?}");
+            assert_eq!(entries[0].data, b"first");
+            assert_eq!(entries[1].data, b"second");
+        }
+    }
+
+    #[test]
+    fn limits_file_count() {
+        let limits = ExtractLimits { files: 2, ..LIMITS };
+
+        let data = zip_of(&[("a.png", b"a"), ("b.png", b"b")]);
+        assert_eq!(extract(ArchiveKind::Zip, &data, limits).unwrap().len(), 2);
+
+        let data = zip_of(&[("a.png", b"a"), ("b.png", b"b"), ("c.png", b"c")]);
+        assert!(extract(ArchiveKind::Zip, &data, limits).is_err());
+
+        // Hidden files don't count
+        let data = zip_of(&[("a.png", b"a"), (".hidden", b"h"), ("b.png", b"b")]);
+        assert_eq!(extract(ArchiveKind::Zip, &data, limits).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn limits_file_size() {
+        let limits = ExtractLimits {
+            file_size: 10,
+            ..LIMITS
+        };
+
+        let data = tar_of(&[("a.png", &[0; 10])]);
+        assert!(extract(ArchiveKind::Tar, &data, limits).is_ok());
+
+        let data = tar_of(&[("a.png", &[0; 11])]);
+        assert!(extract(ArchiveKind::Tar, &data, limits).is_err());
+    }
+
+    #[test]
+    fn limits_file_size_of_compressed_files() {
+        // Compresses to a few bytes, so only the extracted size gives it away
+        let data = zip_of(&[("bomb.png", &vec![0; 1024 * 1024])]);
+        assert!(data.len() < 8 * 1024);
+
+        let err = extract(ArchiveKind::Zip, &data, LIMITS).unwrap_err();
+        assert!(err.to_string().contains("larger than 1024 bytes"), "{err}");
+    }
+
+    #[test]
+    fn limits_total_size() {
+        let limits = ExtractLimits {
+            total_size: 15,
+            ..LIMITS
+        };
+
+        let data = zip_of(&[("a.png", &[0; 5]), ("b.png", &[0; 5]), ("c.png", &[0; 5])]);
+        assert_eq!(extract(ArchiveKind::Zip, &data, limits).unwrap().len(), 3);
+
+        let data = zip_of(&[("a.png", &[0; 5]), ("b.png", &[0; 5]), ("c.png", &[0; 6])]);
+        let err = extract(ArchiveKind::Zip, &data, limits).unwrap_err();
+        assert!(err.to_string().starts_with("Archive is larger"), "{err}");
+    }
+
+    #[test]
+    fn refuses_paths_outside_archive() {
+        for name in ["../evil.png", "dir/../../evil.png", "/etc/evil.png"] {
+            let data = zip_of(&[(name, b"evil")]);
+            assert!(
+                extract(ArchiveKind::Zip, &data, LIMITS).is_err(),
+                "zip {name:?}"
+            );
+
+            let data = tar_of(&[(name, b"evil")]);
+            assert!(
+                extract(ArchiveKind::Tar, &data, LIMITS).is_err(),
+                "tar {name:?}"
+            );
+        }
+
+        let data = tar_of(&[("./dir/ok.png", b"ok")]);
+        assert_eq!(
+            names(&extract(ArchiveKind::Tar, &data, LIMITS).unwrap()),
+            ["./dir/ok.png"]
+        );
+    }
+
+    #[test]
+    fn guesses_content_type() {
+        assert_eq!(guess_content_type("dir/a.PNG"), Some("image/png"));
+        assert_eq!(guess_content_type("scan.tiff"), Some("image/tiff"));
+        assert_eq!(guess_content_type("notes.txt"), None);
+        assert_eq!(guess_content_type("no-extension"), None);
+    }
+}
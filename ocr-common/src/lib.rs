@@ -0,0 +1,4 @@
+//! Code shared by the gateway (`ocr-api`) and the Rust OCR server (`ocr-api-rs`).
+
+pub mod archive;
+pub mod timeframe;